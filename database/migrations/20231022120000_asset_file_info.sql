@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE assets ADD COLUMN file_size INTEGER;
+ALTER TABLE assets ADD COLUMN modified_at INTEGER;
+ALTER TABLE assets ADD COLUMN content_hash VARCHAR(255);
+
+CREATE INDEX IF NOT EXISTS assets_content_hash ON assets (content_hash);
@@ -0,0 +1,2 @@
+-- Lets a source folder say which destination its books are moved to
+ALTER TABLE folders ADD COLUMN destination_id INTEGER REFERENCES folders (id) ON DELETE SET NULL;
@@ -0,0 +1,28 @@
+CREATE TABLE IF NOT EXISTS scan_jobs
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    library_id INTEGER NOT NULL,
+    status VARCHAR(255) NOT NULL,
+    discovered INTEGER NOT NULL DEFAULT 0,
+    imported INTEGER NOT NULL DEFAULT 0,
+    skipped INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    removed INTEGER NOT NULL DEFAULT 0,
+    -- Set when the scan as a whole could not be completed
+    error TEXT,
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    finished_at INTEGER,
+    FOREIGN KEY (library_id) REFERENCES libraries(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS scan_jobs_library_status ON scan_jobs (library_id, status);
+
+CREATE TABLE IF NOT EXISTS scan_job_errors
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    message TEXT NOT NULL,
+    FOREIGN KEY (job_id) REFERENCES scan_jobs(id) ON DELETE CASCADE
+);
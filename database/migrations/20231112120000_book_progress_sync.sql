@@ -0,0 +1,24 @@
+-- One progress row per user and book, keeping the newest of any duplicates
+CREATE TABLE IF NOT EXISTS book_progress_new
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    book_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    spine_index INTEGER NOT NULL,
+    -- Fractions between 0 and 1
+    page_progress REAL NOT NULL,
+    total_progress REAL NOT NULL,
+    -- Milliseconds since the unix epoch, the newest write wins
+    updated_at INTEGER NOT NULL,
+    UNIQUE (book_id, user_id),
+    FOREIGN KEY (book_id) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO book_progress_new (book_id, user_id, spine_index, page_progress, total_progress, updated_at)
+SELECT book_id, user_id, page, page_progress, 0, 0 FROM book_progress
+WHERE id IN (SELECT MAX(id) FROM book_progress GROUP BY book_id, user_id);
+
+DROP TABLE book_progress;
+
+ALTER TABLE book_progress_new RENAME TO book_progress;
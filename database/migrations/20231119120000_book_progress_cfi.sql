@@ -0,0 +1,2 @@
+-- Reading location as an EPUB CFI, so it survives changes to the reader window size
+ALTER TABLE book_progress ADD COLUMN cfi TEXT;
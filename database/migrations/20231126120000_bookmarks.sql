@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS bookmarks
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    book_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- Location as an EPUB CFI, spine_index is kept next to it for sorting
+    cfi TEXT NOT NULL,
+    spine_index INTEGER NOT NULL,
+    label TEXT,
+    chapter TEXT,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (book_id) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS bookmarks_book_user ON bookmarks (book_id, user_id);
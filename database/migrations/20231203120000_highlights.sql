@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS highlights
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    book_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    -- The highlighted range as an EPUB CFI, spine_index is kept next to it for sorting
+    cfi TEXT NOT NULL,
+    spine_index INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    color TEXT NOT NULL DEFAULT 'Yellow',
+    note TEXT,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    FOREIGN KEY (book_id) REFERENCES books(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS highlights_book_user ON highlights (book_id, user_id);
@@ -0,0 +1,16 @@
+-- One row per spine document, title and authors are repeated so a query can match them together with the text
+CREATE VIRTUAL TABLE IF NOT EXISTS book_search USING fts5
+(
+    title,
+    authors,
+    content,
+    book_id UNINDEXED,
+    spine_index UNINDEXED,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- Virtual tables can't have foreign keys, this also catches books removed through a cascade
+CREATE TRIGGER IF NOT EXISTS book_search_delete AFTER DELETE ON books
+BEGIN
+    DELETE FROM book_search WHERE book_id = old.id;
+END;
@@ -0,0 +1,9 @@
+-- Seconds since the unix epoch, books that are already there count as added when their file was last changed
+ALTER TABLE books ADD COLUMN added_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE books SET added_at = COALESCE(
+    (SELECT modified_at FROM assets WHERE assets.id = books.asset_id),
+    strftime('%s', 'now')
+);
+
+CREATE INDEX IF NOT EXISTS books_added_at ON books (added_at);
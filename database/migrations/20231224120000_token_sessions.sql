@@ -0,0 +1,21 @@
+-- One token per login, only a hash of the token is stored. The old tokens were stored in
+-- plain text and can't be hashed here, so everyone has to log in again.
+DROP TABLE IF EXISTS bearer_tokens;
+
+CREATE TABLE IF NOT EXISTS bearer_tokens
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    -- Hex encoded SHA-256 of the token
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    -- Seconds since the unix epoch
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    -- Named by the user when logging in, like "Phone"
+    device VARCHAR(255),
+    user_agent VARCHAR(255),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS bearer_tokens_user_id ON bearer_tokens (user_id);
@@ -0,0 +1,20 @@
+-- Admin, Member or ReadOnly, the first user is the admin
+ALTER TABLE users ADD COLUMN role VARCHAR(255) NOT NULL DEFAULT 'Member';
+
+UPDATE users SET role = 'Admin' WHERE id = (SELECT MIN(id) FROM users);
+
+-- Made by admins when registration needs an invite, every code can be used once
+CREATE TABLE IF NOT EXISTS invites
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code VARCHAR(255) NOT NULL UNIQUE,
+    -- The role the new user gets
+    role VARCHAR(255) NOT NULL,
+    created_by INTEGER,
+    -- Seconds since the unix epoch
+    created_at INTEGER NOT NULL,
+    used_by INTEGER,
+    used_at INTEGER,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL,
+    FOREIGN KEY (used_by) REFERENCES users(id) ON DELETE SET NULL
+);
@@ -0,0 +1,15 @@
+-- The libraries a user can see, admins see all of them
+CREATE TABLE IF NOT EXISTS library_access
+(
+    user_id INTEGER NOT NULL,
+    library_id INTEGER NOT NULL,
+    PRIMARY KEY (user_id, library_id),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (library_id) REFERENCES libraries(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS library_access_library_id ON library_access (library_id);
+
+-- Everyone could see everything before, so nobody loses a library
+INSERT INTO library_access (user_id, library_id)
+SELECT users.id, libraries.id FROM users, libraries;
@@ -9,7 +9,10 @@ pub struct InsertableAsset {
     pub file_extension: Option<String>,
 }
 impl InsertableAsset {
-    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<Asset, sqlx::Error> {
+    pub async fn insert<'c, E>(self, executor: E) -> Result<Asset, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = Sqlite>,
+    {
         let Self {
             local_path,
             file_extension,
@@ -28,7 +31,7 @@ impl InsertableAsset {
         .bind(&uuid)
         .bind(&local_path)
         .bind(&file_extension)
-        .fetch_one(pool)
+        .fetch_one(executor)
         .await?;
 
         let id = result.get("id");
@@ -41,6 +44,14 @@ impl InsertableAsset {
     }
 }
 
+// What the scanner knows about a file on disk, used to tell if it has changed between scans
+pub struct FileInfo {
+    pub size: i64,
+    // Seconds since the unix epoch
+    pub modified_at: i64,
+    pub content_hash: String,
+}
+
 #[derive(sqlx::FromRow)]
 pub struct Asset {
     pub id: String,
@@ -49,11 +60,36 @@ pub struct Asset {
 }
 
 impl Asset {
-    pub async fn into_book_cover(
-        &self,
-        book_id: i32,
+    // Book files and covers can be seen by the users that can see the book
+    pub async fn can_access(
+        id: &str,
+        user_id: i32,
         pool: &Pool<Sqlite>,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM books
+                WHERE (
+                    asset_id = $1
+                    OR primary_cover = $1
+                    OR id IN (SELECT book_id FROM book_covers WHERE asset_id = $1)
+                )
+                AND library_id IN {}
+            )
+            "#,
+            crate::library::visible_libraries("$2")
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn into_book_cover<'c, E>(&self, book_id: i32, executor: E) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = Sqlite>,
+    {
         sqlx::query(
             r#"
             INSERT INTO book_covers (book_id, asset_id)
@@ -62,7 +98,7 @@ impl Asset {
         )
         .bind(&book_id)
         .bind(&self.id)
-        .execute(pool)
+        .execute(executor)
         .await?;
 
         Ok(())
@@ -147,6 +183,29 @@ impl Asset {
         Ok(assets)
     }
 
+    pub async fn update_file(
+        id: &str,
+        local_path: &str,
+        file_info: &FileInfo,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE assets SET local_path = $1, file_size = $2, modified_at = $3, content_hash = $4
+            WHERE id = $5
+            "#,
+        )
+        .bind(local_path)
+        .bind(file_info.size)
+        .bind(file_info.modified_at)
+        .bind(&file_info.content_hash)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn delete_asset(id: &str, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
@@ -0,0 +1,131 @@
+use sqlx;
+use sqlx::{Pool, Sqlite};
+
+pub struct InsertableBookmark {
+    pub book_id: i32,
+    pub user_id: i32,
+    pub cfi: String,
+    pub spine_index: i32,
+    pub label: Option<String>,
+    pub chapter: Option<String>,
+}
+
+impl InsertableBookmark {
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<Bookmark, sqlx::Error> {
+        let Self {
+            book_id,
+            user_id,
+            cfi,
+            spine_index,
+            label,
+            chapter,
+        } = self;
+
+        let bookmark: Bookmark = sqlx::query_as::<_, Bookmark>(
+            r#"
+            INSERT INTO bookmarks (book_id, user_id, cfi, spine_index, label, chapter, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, strftime('%s', 'now'))
+            RETURNING *
+            "#,
+        )
+        .bind(book_id)
+        .bind(user_id)
+        .bind(&cfi)
+        .bind(spine_index)
+        .bind(&label)
+        .bind(&chapter)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(bookmark)
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct Bookmark {
+    pub id: i32,
+    pub book_id: i32,
+    pub user_id: i32,
+    pub cfi: String,
+    pub spine_index: i32,
+    pub label: Option<String>,
+    pub chapter: Option<String>,
+    // Seconds since the unix epoch
+    pub created_at: i64,
+}
+
+impl Bookmark {
+    // Bookmarks are private, so they are always looked up together with the user
+    pub async fn get_bookmark(
+        id: i32,
+        book_id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<Bookmark>, sqlx::Error> {
+        let bookmark: Option<Bookmark> = sqlx::query_as::<_, Bookmark>(
+            r#"
+            SELECT * FROM bookmarks WHERE id = $1 AND book_id = $2 AND user_id = $3
+            "#,
+        )
+        .bind(id)
+        .bind(book_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(bookmark)
+    }
+
+    // In reading order as far as the spine goes, and in the order they were made within a document
+    pub async fn get_by_book(
+        book_id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Bookmark>, sqlx::Error> {
+        let bookmarks: Vec<Bookmark> = sqlx::query_as::<_, Bookmark>(
+            r#"
+            SELECT * FROM bookmarks WHERE book_id = $1 AND user_id = $2
+            ORDER BY spine_index, id
+            "#,
+        )
+        .bind(book_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(bookmarks)
+    }
+
+    pub async fn rename(
+        &mut self,
+        label: Option<String>,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE bookmarks SET label = $1 WHERE id = $2
+            "#,
+        )
+        .bind(&label)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.label = label;
+
+        Ok(())
+    }
+
+    pub async fn delete_self(self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM bookmarks WHERE id = $1
+            "#,
+        )
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,19 @@
+use serde::{Deserialize, Serialize};
 use sqlx;
 use sqlx::{Pool, Row, Sqlite};
 use std::cmp::PartialEq;
+use utoipa::ToSchema;
 
+#[derive(Deserialize, ToSchema)]
+#[schema(as = Folder)]
 pub struct InsertableFolder {
     pub nickname: String,
     pub path: String,
     pub watch: bool,
     pub drop_type: DropType,
+    // Only used by source folders, when not set the first destination folder is used
+    #[serde(default)]
+    pub destination_id: Option<i32>,
 }
 
 impl InsertableFolder {
@@ -16,19 +23,21 @@ impl InsertableFolder {
             path: folder_path,
             watch,
             drop_type,
+            destination_id,
         } = self;
 
         let result = sqlx::query(
             r#"
-            INSERT INTO folders (nickname, path, watch, drop_type)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO folders (nickname, path, watch, drop_type, destination_id)
+            VALUES ($1, $2, $3, $4, $5)
             RETURNING id
             "#,
         )
         .bind(&folder_nickname)
         .bind(&folder_path)
         .bind(watch)
-        .bind(&drop_type)
+        .bind(drop_type)
+        .bind(destination_id)
         .fetch_one(pool)
         .await?;
 
@@ -40,22 +49,129 @@ impl InsertableFolder {
             path: folder_path,
             watch,
             drop_type,
+            destination_id,
         })
     }
 }
 
-#[derive(sqlx::Type, PartialEq, Debug)]
+#[derive(sqlx::Type, Serialize, Deserialize, ToSchema, PartialEq, Clone, Copy, Debug)]
 pub enum DropType {
     Source,
     Destination,
     None,
 }
 
-#[derive(Debug)]
+#[derive(sqlx::FromRow, Debug)]
 pub struct Folder {
     pub id: i32,
     pub nickname: String,
     pub path: String,
     pub watch: bool,
     pub drop_type: DropType,
+    pub destination_id: Option<i32>,
+}
+
+impl Folder {
+    pub async fn get_folder(id: i32, pool: &Pool<Sqlite>) -> Result<Folder, sqlx::Error> {
+        let folder: Folder = sqlx::query_as::<_, Folder>(
+            r#"
+            SELECT * FROM folders WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(folder)
+    }
+
+    pub async fn get_folders(pool: &Pool<Sqlite>) -> Result<Vec<Folder>, sqlx::Error> {
+        let folders: Vec<Folder> = sqlx::query_as::<_, Folder>(
+            r#"
+            SELECT * FROM folders
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(folders)
+    }
+
+    pub async fn get_watched(pool: &Pool<Sqlite>) -> Result<Vec<Folder>, sqlx::Error> {
+        let folders: Vec<Folder> = sqlx::query_as::<_, Folder>(
+            r#"
+            SELECT * FROM folders WHERE watch = TRUE
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(folders)
+    }
+
+    pub async fn get_by_drop_type(
+        drop_type: DropType,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Folder>, sqlx::Error> {
+        let folders: Vec<Folder> = sqlx::query_as::<_, Folder>(
+            r#"
+            SELECT * FROM folders WHERE drop_type = $1 ORDER BY id
+            "#,
+        )
+        .bind(drop_type)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(folders)
+    }
+
+    // The folder books dropped into this source folder should be moved to
+    pub async fn get_destination(
+        &self,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<Folder>, sqlx::Error> {
+        if let Some(destination_id) = self.destination_id {
+            return match Folder::get_folder(destination_id, pool).await {
+                Ok(folder) if folder.drop_type == DropType::Destination => Ok(Some(folder)),
+                Ok(_) | Err(sqlx::Error::RowNotFound) => Ok(None),
+                Err(error) => Err(error),
+            };
+        }
+
+        let destinations = Folder::get_by_drop_type(DropType::Destination, pool).await?;
+
+        Ok(destinations.into_iter().next())
+    }
+
+    pub async fn update(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE folders SET nickname = $1, path = $2, watch = $3, drop_type = $4, destination_id = $5
+            WHERE id = $6
+            "#,
+        )
+        .bind(&self.nickname)
+        .bind(&self.path)
+        .bind(self.watch)
+        .bind(self.drop_type)
+        .bind(self.destination_id)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_folder(id: i32, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM folders WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
 }
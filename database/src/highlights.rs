@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use sqlx;
+use sqlx::{Pool, Sqlite};
+use utoipa::ToSchema;
+
+#[derive(sqlx::Type, Serialize, Deserialize, ToSchema, PartialEq, Clone, Copy, Debug, Default)]
+pub enum HighlightColor {
+    #[default]
+    Yellow,
+    Green,
+    Blue,
+    Pink,
+    Purple,
+}
+
+pub struct InsertableHighlight {
+    pub book_id: i32,
+    pub user_id: i32,
+    pub cfi: String,
+    pub spine_index: i32,
+    pub text: String,
+    pub color: HighlightColor,
+    pub note: Option<String>,
+}
+
+impl InsertableHighlight {
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<Highlight, sqlx::Error> {
+        let Self {
+            book_id,
+            user_id,
+            cfi,
+            spine_index,
+            text,
+            color,
+            note,
+        } = self;
+
+        let highlight: Highlight = sqlx::query_as::<_, Highlight>(
+            r#"
+            INSERT INTO highlights (book_id, user_id, cfi, spine_index, text, color, note, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, strftime('%s', 'now'), strftime('%s', 'now'))
+            RETURNING *
+            "#,
+        )
+        .bind(book_id)
+        .bind(user_id)
+        .bind(&cfi)
+        .bind(spine_index)
+        .bind(&text)
+        .bind(color)
+        .bind(&note)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(highlight)
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct Highlight {
+    pub id: i32,
+    pub book_id: i32,
+    pub user_id: i32,
+    pub cfi: String,
+    pub spine_index: i32,
+    // The selected text, kept so highlights can be exported without opening the book
+    pub text: String,
+    pub color: HighlightColor,
+    pub note: Option<String>,
+    // Seconds since the unix epoch
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Highlight {
+    // Highlights are private, so they are always looked up together with the user
+    pub async fn get_highlight(
+        id: i32,
+        book_id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<Highlight>, sqlx::Error> {
+        let highlight: Option<Highlight> = sqlx::query_as::<_, Highlight>(
+            r#"
+            SELECT * FROM highlights WHERE id = $1 AND book_id = $2 AND user_id = $3
+            "#,
+        )
+        .bind(id)
+        .bind(book_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(highlight)
+    }
+
+    // Sorted by spine document, the order inside a document has to come from the CFI
+    pub async fn get_by_book(
+        book_id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Highlight>, sqlx::Error> {
+        let highlights: Vec<Highlight> = sqlx::query_as::<_, Highlight>(
+            r#"
+            SELECT * FROM highlights WHERE book_id = $1 AND user_id = $2
+            ORDER BY spine_index, id
+            "#,
+        )
+        .bind(book_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(highlights)
+    }
+
+    // Stores the color and note, the range and text of a highlight never change
+    pub async fn update(&mut self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        let updated_at: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE highlights SET color = $1, note = $2, updated_at = strftime('%s', 'now')
+            WHERE id = $3
+            RETURNING updated_at
+            "#,
+        )
+        .bind(self.color)
+        .bind(&self.note)
+        .bind(self.id)
+        .fetch_one(pool)
+        .await?;
+
+        self.updated_at = updated_at;
+
+        Ok(())
+    }
+
+    pub async fn delete_self(self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM highlights WHERE id = $1
+            "#,
+        )
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
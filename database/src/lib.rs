@@ -2,17 +2,102 @@ use sqlx::{Pool, Sqlite};
 extern crate argon2;
 
 pub mod assets;
+pub mod bookmarks;
 pub mod folders;
+pub mod highlights;
 pub mod library;
+pub mod maintenance;
+pub mod scan_jobs;
+pub mod search;
 pub mod users;
 
+use sqlx::migrate::{MigrateError, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+// The migrations are built into the binary, so it doesn't need the source tree to set up a database
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// The database file is created if it doesn't exist yet. WAL lets the scanner write while
+// readers keep reading, and writers wait for each other instead of failing right away.
+pub fn connect_options(database_url: &str) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(database_url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .foreign_keys(true)
+        .busy_timeout(Duration::from_secs(10));
+    Ok(options)
+}
+
+// Has to be migrated before use, see migrate
 pub async fn create_pool(database_url: &str) -> Result<Pool<Sqlite>, sqlx::Error> {
     let pool = sqlx::sqlite::SqlitePoolOptions::new()
-        .connect(&database_url)
+        .connect_with(connect_options(database_url)?)
         .await?;
     Ok(pool)
 }
 
+#[derive(Debug)]
+pub enum MigrationError {
+    // The database was migrated by a newer version of the program, running against it could break it
+    NewerSchema { database: i64, known: i64 },
+    DatabaseError(sqlx::Error),
+    Migrate(MigrateError),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::NewerSchema { database, known } => write!(
+                f,
+                "the database schema is at version {}, but this build only knows up to {}",
+                database, known
+            ),
+            MigrationError::DatabaseError(error) => write!(f, "{}", error),
+            MigrationError::Migrate(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(error: sqlx::Error) -> Self {
+        MigrationError::DatabaseError(error)
+    }
+}
+
+impl From<MigrateError> for MigrationError {
+    fn from(error: MigrateError) -> Self {
+        MigrationError::Migrate(error)
+    }
+}
+
+// Runs the migrations that haven't been run yet, returns their versions
+pub async fn migrate(pool: &Pool<Sqlite>) -> Result<Vec<i64>, MigrationError> {
+    let applied = maintenance::applied_migrations(pool).await?;
+    let known = MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default();
+
+    if let Some(&database) = applied.iter().max() {
+        if database > known {
+            return Err(MigrationError::NewerSchema { database, known });
+        }
+    }
+
+    MIGRATOR.run(pool).await?;
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -40,14 +125,43 @@ mod tests {
         let register = users::Register {
             username: "test".into(),
             password: "test".into(),
+            device: None,
+            invite_code: None,
         };
 
-        let token = register.register(&pool).await.unwrap();
+        let token = register.register(None, &pool).await.unwrap();
 
-        assert_eq!(token.user_id, 1);
+        assert_eq!(token.bearer_token.user_id, 1);
         println!("{}", token.token);
     }
 
+    #[tokio::test]
+    async fn test_register_same_name_concurrently() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let register = || users::Register {
+            username: "test".into(),
+            password: "test".into(),
+            device: None,
+            invite_code: None,
+        };
+
+        let (first, second) = tokio::join!(
+            register().register(None, &pool),
+            register().register(None, &pool)
+        );
+
+        // One of them wins, the other is told the name is taken
+        let results = [first, second];
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(users::RegisterError::UsernameTaken))));
+    }
+
     #[tokio::test]
     async fn test_login() {
         let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
@@ -57,19 +171,142 @@ mod tests {
         let register = users::Register {
             username: "test".into(),
             password: "test".into(),
+            device: None,
+            invite_code: None,
         };
 
-        let regist_token = register.register(&pool).await.unwrap();
+        let regist_token = register.register(None, &pool).await.unwrap();
 
         let login = users::Login {
             username: "test".into(),
             password: "test".into(),
+            device: None,
+        };
+
+        let login_token = login.login(None, &pool).await.unwrap();
+
+        // Every login is its own session
+        assert_ne!(regist_token.token, login_token.token);
+        assert_eq!(
+            regist_token.bearer_token.user_id,
+            login_token.bearer_token.user_id
+        );
+        assert_eq!(
+            users::BearerToken::get_by_user(1, &pool)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_expiry() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+
+        let register = users::Register {
+            username: "test".into(),
+            password: "test".into(),
+            device: Some("Phone".into()),
+            invite_code: None,
+        };
+        let token = register
+            .register(Some("test agent".into()), &pool)
+            .await
+            .unwrap();
+        assert_eq!(token.bearer_token.device.as_deref(), Some("Phone"));
+        assert_ne!(token.bearer_token.token_hash, token.token);
+
+        // Using an old token pushes the expiry forward
+        sqlx::query("UPDATE bearer_tokens SET last_used_at = 0, expires_at = $1")
+            .bind(i64::MAX)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let used = users::BearerToken::authenticate(&token.token, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(used.last_used_at > 0);
+        assert!(used.expires_at < i64::MAX);
+
+        sqlx::query("UPDATE bearer_tokens SET expires_at = 0")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(users::BearerToken::authenticate(&token.token, &pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(users::BearerToken::get_by_user(1, &pool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roles_and_invites() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+
+        let register = |username: &str, invite_code: Option<String>| users::Register {
+            username: username.into(),
+            password: "password".into(),
+            device: None,
+            invite_code,
         };
+        let role = |user_id: i32| {
+            let pool = pool.clone();
+            async move {
+                users::User::find_by_id(user_id, &pool)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .role
+            }
+        };
+
+        let admin = register("admin", None).register(None, &pool).await.unwrap();
+        assert_eq!(role(admin.bearer_token.user_id).await, users::Role::Admin);
 
-        let login_token = login.login(&pool).await.unwrap();
+        let member = register("member", None)
+            .register(None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(role(member.bearer_token.user_id).await, users::Role::Member);
+
+        let invite = users::InsertableInvite {
+            role: users::Role::ReadOnly,
+            created_by: Some(admin.bearer_token.user_id),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let reader = register("reader", Some(invite.code.clone()))
+            .register(None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            role(reader.bearer_token.user_id).await,
+            users::Role::ReadOnly
+        );
 
-        assert_eq!(regist_token.token, login_token.token);
-        assert_eq!(regist_token.user_id, login_token.user_id);
+        let used = users::Invite::get_invites(&pool).await.unwrap();
+        assert_eq!(used[0].used_by, Some(reader.bearer_token.user_id));
+
+        // Every invite can only be used once, and a failed claim doesn't create the user
+        for code in [invite.code, "wrong".to_string()] {
+            let result = register("second reader", Some(code))
+                .register(None, &pool)
+                .await;
+            assert!(matches!(result, Err(users::RegisterError::InvalidInvite)));
+        }
+        assert!(users::User::find_by_username("second reader", &pool)
+            .await
+            .unwrap()
+            .is_none());
     }
 
     #[tokio::test]
@@ -81,20 +318,255 @@ mod tests {
         let register = users::Register {
             username: "alice".into(),
             password: "password".into(),
+            device: None,
+            invite_code: None,
         };
 
-        register.register(&pool).await.unwrap();
+        register.register(None, &pool).await.unwrap();
 
         let duplicate = users::Register {
             username: "alice".into(),
             password: "password".into(),
+            device: None,
+            invite_code: None,
         };
 
-        let duplicate_token = duplicate.register(&pool).await;
+        let duplicate_token = duplicate.register(None, &pool).await;
 
         assert!(duplicate_token.is_err());
     }
 
+    #[tokio::test]
+    async fn test_user_maintenance() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+
+        let register = users::Register {
+            username: "alice".into(),
+            password: "password".into(),
+            device: None,
+            invite_code: None,
+        };
+        let token = register.register(None, &pool).await.unwrap();
+
+        let mut user = users::User::find_by_username("alice", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        user.set_password("new password", &pool).await.unwrap();
+        users::BearerToken::delete_by_user(user.id, &pool)
+            .await
+            .unwrap();
+
+        let user = users::User::find_by_id(user.id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(users::verify_password(
+            &user.hashed_password,
+            "new password"
+        ));
+
+        // Basic auth remembers the checked password, but not past a password change
+        let checked = users::authenticate_password("alice", "new password", &pool)
+            .await
+            .unwrap();
+        assert_eq!(checked.map(|user| user.id), Some(user.id));
+        let mut changed = users::User::find_by_id(user.id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        changed.set_password("newer password", &pool).await.unwrap();
+        assert!(users::authenticate_password("alice", "new password", &pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(
+            users::authenticate_password("nobody", "new password", &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        changed.set_password("new password", &pool).await.unwrap();
+        assert!(users::BearerToken::find_by_token(&token.token, &pool)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The user still has a token, which would block the delete without a cascade
+        users::Login {
+            username: "alice".into(),
+            password: "new password".into(),
+            device: None,
+        }
+        .login(None, &pool)
+        .await
+        .unwrap();
+
+        user.delete_self(&pool).await.unwrap();
+        assert!(users::User::find_by_username("alice", &pool)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_account_management() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+
+        let login = |device: &str| users::Login {
+            username: "alice".into(),
+            password: "password".into(),
+            device: Some(device.into()),
+        };
+
+        let first = users::Register {
+            username: "alice".into(),
+            password: "password".into(),
+            device: Some("phone".into()),
+            invite_code: None,
+        }
+        .register(None, &pool)
+        .await
+        .unwrap();
+        let second = login("laptop").login(None, &pool).await.unwrap();
+        let user_id = first.bearer_token.user_id;
+
+        // Changing the password keeps the session it was changed from
+        users::BearerToken::delete_other_sessions(user_id, second.bearer_token.id, &pool)
+            .await
+            .unwrap();
+        let sessions = users::BearerToken::get_by_user(user_id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, second.bearer_token.id);
+
+        let mut user = users::User::find_by_id(user_id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        user.set_username("alicia", &pool).await.unwrap();
+        assert!(users::User::find_by_username("alicia", &pool)
+            .await
+            .unwrap()
+            .is_some());
+
+        let bob = users::Register {
+            username: "bob".into(),
+            password: "password".into(),
+            device: None,
+            invite_code: None,
+        }
+        .register(None, &pool)
+        .await
+        .unwrap();
+        assert!(user.set_username("bob", &pool).await.is_err());
+        assert_eq!(user.username, "alicia");
+        assert_eq!(users::User::count_admins(&pool).await.unwrap(), 1);
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        let book = library::InsertableBook {
+            path: "books/dune.epub".into(),
+            name: "Dune".into(),
+            library_id: library.id,
+            collection_id: None,
+            primary_cover: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        for user_id in [user_id, bob.bearer_token.user_id] {
+            library::InsertableBookProgress {
+                book_id: book.id,
+                user_id,
+                spine_index: 1,
+                page_progress: 0.5,
+                total_progress: 0.1,
+                cfi: None,
+                updated_at: 1000,
+            }
+            .upsert(&pool)
+            .await
+            .unwrap();
+        }
+
+        // The only admin can't be deleted through the checked delete
+        let admin = users::User::find_by_id(user_id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!admin.delete_unless_last_admin(&pool).await.unwrap());
+        assert_eq!(users::User::get_users(&pool).await.unwrap().len(), 2);
+
+        user.delete_self(&pool).await.unwrap();
+
+        let users = users::User::get_users(&pool).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].username, "bob");
+
+        // Only what belonged to the deleted user is gone
+        let progress: Vec<i32> = sqlx::query_scalar("SELECT user_id FROM book_progress")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(progress, vec![bob.bearer_token.user_id]);
+        let tokens: Vec<i32> = sqlx::query_scalar("SELECT user_id FROM bearer_tokens")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(tokens, vec![bob.bearer_token.user_id]);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+
+        let check = maintenance::check(&pool).await.unwrap();
+        assert!(!check.is_ok());
+        assert_eq!(check.pending_migrations.len(), MIGRATOR.iter().count());
+
+        migrate(&pool).await.unwrap();
+
+        let check = maintenance::check(&pool).await.unwrap();
+        assert!(check.is_ok(), "{:?}", check);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_newer_schema() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+
+        let applied = migrate(&pool).await.unwrap();
+        assert_eq!(applied.len(), MIGRATOR.iter().count());
+        assert!(migrate(&pool).await.unwrap().is_empty());
+
+        // Pretend a newer build has migrated the database
+        sqlx::query(
+            r#"
+            INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'from the future', 1, x'00', 0)
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        match migrate(&pool).await {
+            Err(MigrationError::NewerSchema { database, .. }) => {
+                assert_eq!(database, 99990101000000)
+            }
+            other => panic!("expected NewerSchema, got {:?}", other),
+        }
+    }
+
     #[tokio::test]
     async fn test_new_folder() {
         let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
@@ -106,6 +578,7 @@ mod tests {
             path: "hello/hello".into(),
             watch: true,
             drop_type: folders::DropType::Source,
+            destination_id: None,
         };
 
         let insert_result = insertable_folder.insert(&pool).await.unwrap();
@@ -117,4 +590,748 @@ mod tests {
         assert_eq!(insert_result.drop_type, folders::DropType::Source);
         pool.close().await;
     }
+
+    #[tokio::test]
+    async fn test_watched_folders() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        for (nickname, watch) in [("watched", true), ("ignored", false)] {
+            folders::InsertableFolder {
+                nickname: nickname.into(),
+                path: format!("books/{}", nickname),
+                watch,
+                drop_type: folders::DropType::None,
+                destination_id: None,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+        }
+
+        let watched = folders::Folder::get_watched(&pool).await.unwrap();
+
+        assert_eq!(watched.len(), 1);
+        assert_eq!(watched[0].nickname, "watched");
+        assert_eq!(watched[0].drop_type, folders::DropType::None);
+        assert_eq!(folders::Folder::get_folders(&pool).await.unwrap().len(), 2);
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_folder_destination() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let mut folders = Vec::new();
+        for (nickname, drop_type) in [
+            ("first", folders::DropType::Destination),
+            ("second", folders::DropType::Destination),
+            ("drop", folders::DropType::Source),
+        ] {
+            let folder = folders::InsertableFolder {
+                nickname: nickname.into(),
+                path: format!("books/{}", nickname),
+                watch: true,
+                drop_type,
+                destination_id: None,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            folders.push(folder);
+        }
+
+        let mut source = folders.pop().unwrap();
+        let destination = source.get_destination(&pool).await.unwrap().unwrap();
+        assert_eq!(destination.nickname, "first");
+
+        source.destination_id = Some(folders[1].id);
+        source.update(&pool).await.unwrap();
+        let source = folders::Folder::get_folder(source.id, &pool).await.unwrap();
+        let destination = source.get_destination(&pool).await.unwrap().unwrap();
+        assert_eq!(destination.nickname, "second");
+
+        folders::Folder::delete_folder(folders[1].id, &pool)
+            .await
+            .unwrap();
+        let source = folders::Folder::get_folder(source.id, &pool).await.unwrap();
+        assert_eq!(source.destination_id, None);
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_book_metadata() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let book = library::InsertableBook {
+            path: "books/dune.epub".into(),
+            name: "Dune".into(),
+            library_id: library.id,
+            collection_id: None,
+            primary_cover: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let metadata = library::InsertableBookMetadata {
+            book_id: book.id,
+            language: Some("en".into()),
+            publisher: Some("Chilton".into()),
+            published: Some("1965".into()),
+            description: None,
+            authors: vec![library::InsertableAuthor {
+                name: "Frank Herbert".into(),
+                role: Some("aut".into()),
+                file_as: Some("Herbert, Frank".into()),
+            }],
+            subjects: vec!["Science fiction".into()],
+            identifiers: vec![library::InsertableIdentifier {
+                scheme: Some("isbn".into()),
+                value: "9780441013593".into(),
+            }],
+            series: Some(library::InsertableSeries {
+                name: "Dune Chronicles".into(),
+                position: Some(1.0),
+            }),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(metadata.language.as_deref(), Some("en"));
+        assert_eq!(metadata.authors.len(), 1);
+        assert_eq!(
+            metadata.authors[0].file_as.as_deref(),
+            Some("Herbert, Frank")
+        );
+        assert_eq!(metadata.subjects, vec!["Science fiction".to_string()]);
+        assert_eq!(metadata.isbn(), Some("9780441013593"));
+        assert_eq!(metadata.series[0].name, "Dune Chronicles");
+        assert_eq!(metadata.series[0].position, Some(1.0));
+
+        // Storing metadata again replaces the old values instead of adding to them
+        let metadata = library::InsertableBookMetadata {
+            book_id: book.id,
+            language: None,
+            publisher: None,
+            published: None,
+            description: None,
+            authors: vec![library::InsertableAuthor {
+                name: "Frank Herbert".into(),
+                role: None,
+                file_as: None,
+            }],
+            subjects: Vec::new(),
+            identifiers: Vec::new(),
+            series: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(metadata.language, None);
+        assert_eq!(metadata.authors.len(), 1);
+        assert_eq!(
+            metadata.authors[0].file_as.as_deref(),
+            Some("Herbert, Frank")
+        );
+        assert!(metadata.subjects.is_empty());
+        assert!(metadata.series.is_empty());
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_book_progress() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let token = users::Register {
+            username: "reader".into(),
+            password: "reader".into(),
+            device: None,
+            invite_code: None,
+        }
+        .register(None, &pool)
+        .await
+        .unwrap();
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let book = library::InsertableBook {
+            path: "books/dune.epub".into(),
+            name: "Dune".into(),
+            library_id: library.id,
+            collection_id: None,
+            primary_cover: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let progress = |spine_index, updated_at| library::InsertableBookProgress {
+            book_id: book.id,
+            user_id: token.bearer_token.user_id,
+            spine_index,
+            page_progress: 0.5,
+            total_progress: 0.1,
+            cfi: Some(format!("epubcfi(/6/{}!/4/2)", (spine_index + 1) * 2)),
+            updated_at,
+        };
+
+        assert!(
+            library::BookProgress::get_progress(book.id, token.bearer_token.user_id, &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let stored = progress(3, 2000).upsert(&pool).await.unwrap();
+        assert_eq!(stored.spine_index, 3);
+
+        // A newer write replaces the row instead of adding another one
+        let stored = progress(5, 3000).upsert(&pool).await.unwrap();
+        assert_eq!(stored.spine_index, 5);
+
+        // A write from a device that was behind loses
+        let stored = progress(1, 2500).upsert(&pool).await.unwrap();
+        assert_eq!(stored.spine_index, 5);
+        assert_eq!(stored.cfi.as_deref(), Some("epubcfi(/6/12!/4/2)"));
+        assert_eq!(stored.updated_at, 3000);
+
+        let rows: i32 = sqlx::query_scalar("SELECT COUNT(*) FROM book_progress")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 1);
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_bookmarks() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let mut user_ids = Vec::new();
+        for username in ["first", "second"] {
+            let token = users::Register {
+                username: username.into(),
+                password: username.into(),
+                device: None,
+                invite_code: None,
+            }
+            .register(None, &pool)
+            .await
+            .unwrap();
+            user_ids.push(token.bearer_token.user_id);
+        }
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let book = library::InsertableBook {
+            path: "books/dune.epub".into(),
+            name: "Dune".into(),
+            library_id: library.id,
+            collection_id: None,
+            primary_cover: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        for (spine_index, user_id) in [(4, user_ids[0]), (1, user_ids[0]), (2, user_ids[1])] {
+            bookmarks::InsertableBookmark {
+                book_id: book.id,
+                user_id,
+                cfi: format!("epubcfi(/6/{}!/4/2)", (spine_index + 1) * 2),
+                spine_index,
+                label: None,
+                chapter: Some("Chapter".into()),
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+        }
+
+        let first = bookmarks::Bookmark::get_by_book(book.id, user_ids[0], &pool)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].spine_index, 1);
+        assert_eq!(first[1].spine_index, 4);
+
+        // Someone else's bookmark can't be found
+        let other = bookmarks::Bookmark::get_bookmark(first[0].id, book.id, user_ids[1], &pool)
+            .await
+            .unwrap();
+        assert!(other.is_none());
+
+        let mut bookmark =
+            bookmarks::Bookmark::get_bookmark(first[0].id, book.id, user_ids[0], &pool)
+                .await
+                .unwrap()
+                .unwrap();
+        bookmark
+            .rename(Some("Good part".into()), &pool)
+            .await
+            .unwrap();
+        bookmark.delete_self(&pool).await.unwrap();
+
+        let first = bookmarks::Bookmark::get_by_book(book.id, user_ids[0], &pool)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].spine_index, 4);
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_highlights() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        let user_id = users::Register {
+            username: "reader".into(),
+            password: "reader".into(),
+            device: None,
+            invite_code: None,
+        }
+        .register(None, &pool)
+        .await
+        .unwrap()
+        .bearer_token
+        .user_id;
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let book = library::InsertableBook {
+            path: "books/dune.epub".into(),
+            name: "Dune".into(),
+            library_id: library.id,
+            collection_id: None,
+            primary_cover: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let highlight = highlights::InsertableHighlight {
+            book_id: book.id,
+            user_id,
+            cfi: "epubcfi(/6/4!/4/2,/1:0,/1:12)".into(),
+            spine_index: 1,
+            text: "Fear is the mind-killer".into(),
+            color: highlights::HighlightColor::default(),
+            note: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        assert_eq!(highlight.color, highlights::HighlightColor::Yellow);
+
+        let mut highlight =
+            highlights::Highlight::get_highlight(highlight.id, book.id, user_id, &pool)
+                .await
+                .unwrap()
+                .unwrap();
+        highlight.color = highlights::HighlightColor::Blue;
+        highlight.note = Some("Litany against fear".into());
+        highlight.update(&pool).await.unwrap();
+
+        let stored = highlights::Highlight::get_by_book(book.id, user_id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].color, highlights::HighlightColor::Blue);
+        assert_eq!(stored[0].note.as_deref(), Some("Litany against fear"));
+
+        // Highlights go away with the book
+        book.delete_self(&pool).await.unwrap();
+        let stored = highlights::Highlight::get_by_book(book.id, user_id, &pool)
+            .await
+            .unwrap();
+        assert!(stored.is_empty());
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        // Admins see every library
+        let admin = users::InsertableUser {
+            username: "admin".into(),
+            hashed_password: users::hash_password("admin"),
+            role: users::Role::Admin,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let mut books = Vec::new();
+        for (name, author, documents) in [
+            (
+                "Dune",
+                "Frank Herbert",
+                vec!["The spice must flow", "Fear is the mind-killer"],
+            ),
+            (
+                "Emma",
+                "Jane Austen",
+                vec!["Emma Woodhouse, handsome, clever, and rich"],
+            ),
+        ] {
+            let book = library::InsertableBook {
+                path: format!("books/{}.epub", name),
+                name: name.into(),
+                library_id: library.id,
+                collection_id: None,
+                primary_cover: None,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+
+            search::InsertableSearchIndex {
+                book_id: book.id,
+                title: name.into(),
+                authors: author.into(),
+                documents: documents
+                    .into_iter()
+                    .enumerate()
+                    .map(|(spine_index, content)| search::SearchDocument {
+                        spine_index: Some(spine_index as i32),
+                        content: content.into(),
+                    })
+                    .collect(),
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            books.push(book);
+        }
+
+        let results = search::SearchResult::search("mind", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].book_id, books[0].id);
+        assert_eq!(results[0].spine_index, Some(1));
+        assert!(results[0].snippet.contains("\u{2}mind\u{3}"));
+
+        // Title and text can match together, and a book is only returned once
+        let results = search::SearchResult::search("emma clever", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].title, "Emma");
+
+        // Search operators are taken literally
+        let results = search::SearchResult::search("\"spice OR", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        let unindexed = search::SearchResult::get_unindexed(library.id, &pool)
+            .await
+            .unwrap();
+        assert!(unindexed.is_empty());
+
+        // The index goes away with the book
+        books[0].delete_self(&pool).await.unwrap();
+        let results = search::SearchResult::search("spice", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_catalog() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        // Apply migrations
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        // Admins see every library
+        let admin = users::InsertableUser {
+            username: "admin".into(),
+            hashed_password: users::hash_password("admin"),
+            role: users::Role::Admin,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let library = library::InsertableLibrary {
+            path: "books".into(),
+            name: "books".into(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let mut books = Vec::new();
+        for (name, author) in [
+            ("Dune", "Frank Herbert"),
+            ("children of Dune", "Frank Herbert"),
+            ("Emma", "Jane Austen"),
+        ] {
+            let book = library::InsertableBook {
+                path: format!("books/{}.epub", name),
+                name: name.into(),
+                library_id: library.id,
+                collection_id: None,
+                primary_cover: None,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+
+            library::InsertableBookMetadata {
+                book_id: book.id,
+                language: None,
+                publisher: None,
+                published: None,
+                description: None,
+                authors: vec![library::InsertableAuthor {
+                    name: author.into(),
+                    role: None,
+                    file_as: None,
+                }],
+                subjects: Vec::new(),
+                identifiers: Vec::new(),
+                series: None,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            books.push(book);
+        }
+        assert!(books[0].added_at > 0);
+
+        // Added in the same second, so the newest id comes first
+        let recent = library::Book::get_recent(2, admin.id, &pool).await.unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].id, books[2].id);
+
+        let page = library::Book::get_page_by_library(library.id, 2, 0, &pool)
+            .await
+            .unwrap();
+        let names: Vec<&str> = page.iter().map(|book| book.name.as_str()).collect();
+        assert_eq!(names, vec!["children of Dune", "Dune"]);
+        let page = library::Book::get_page_by_library(library.id, 2, 2, &pool)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+
+        let page = library::Book::get_page_by_collection(books[0].collection_id, 10, 0, &pool)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 3);
+
+        let authors = library::Author::get_authors(admin.id, &pool).await.unwrap();
+        assert_eq!(authors.len(), 2);
+        assert_eq!(authors[0].name, "Frank Herbert");
+        assert_eq!(authors[0].book_count, 2);
+
+        let author = library::Author::get_author(authors[1].id, admin.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(author.name, "Jane Austen");
+        let page = library::Book::get_page_by_author(author.id, admin.id, 10, 0, &pool)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].name, "Emma");
+
+        // Listings fetch the books and their metadata for a whole page at once, in the order asked for
+        let ids = [books[2].id, -1, books[0].id];
+        let found = library::Book::get_books_by_ids(&ids, &pool).await.unwrap();
+        let names: Vec<&str> = found.iter().map(|book| book.name.as_str()).collect();
+        assert_eq!(names, vec!["Emma", "Dune"]);
+        let metadata = library::BookMetadata::get_by_books(&ids, &pool)
+            .await
+            .unwrap();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[0].book_id, books[2].id);
+        assert_eq!(metadata[0].authors[0].name, "Jane Austen");
+        assert_eq!(metadata[1].authors[0].name, "Frank Herbert");
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_library_access() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+
+        let mut users = Vec::new();
+        for (username, role) in [
+            ("admin", users::Role::Admin),
+            ("member", users::Role::Member),
+        ] {
+            let user = users::InsertableUser {
+                username: username.into(),
+                hashed_password: users::hash_password(username),
+                role,
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            users.push(user.id);
+        }
+        let (admin, member) = (users[0], users[1]);
+
+        let mut books = Vec::new();
+        for name in ["shared", "private"] {
+            let library = library::InsertableLibrary {
+                path: name.into(),
+                name: name.into(),
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            let cover = assets::InsertableAsset {
+                local_path: format!("{}/cover.jpg", name),
+                file_extension: Some("image/jpeg".into()),
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            let book = library::InsertableBook {
+                path: format!("{}/book.epub", name),
+                name: name.into(),
+                library_id: library.id,
+                collection_id: None,
+                primary_cover: Some(cover.id),
+            }
+            .insert(&pool)
+            .await
+            .unwrap();
+            books.push(book);
+        }
+        let (shared, private) = (&books[0], &books[1]);
+
+        // New libraries are only seen by admins
+        assert!(library::Library::get_libraries_for_user(member, &pool)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            library::Book::get_books_for_user(admin, &pool)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Giving access twice does nothing
+        for _ in 0..2 {
+            library::Library::grant_access(shared.library_id, member, &pool)
+                .await
+                .unwrap();
+        }
+
+        let libraries = library::Library::get_libraries_for_user(member, &pool)
+            .await
+            .unwrap();
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries[0].id, shared.library_id);
+        assert_eq!(
+            library::Book::get_books_for_user(member, &pool)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(library::Book::can_access(shared.id, member, &pool)
+            .await
+            .unwrap());
+        assert!(!library::Book::can_access(private.id, member, &pool)
+            .await
+            .unwrap());
+        assert!(
+            library::Library::can_access(private.library_id, admin, &pool)
+                .await
+                .unwrap()
+        );
+
+        for (book, visible) in [(shared, true), (private, false)] {
+            for asset in [&book.asset_id, book.primary_cover.as_ref().unwrap()] {
+                assert_eq!(
+                    assets::Asset::can_access(asset, member, &pool)
+                        .await
+                        .unwrap(),
+                    visible
+                );
+            }
+        }
+
+        let recent = library::Book::get_recent(10, member, &pool).await.unwrap();
+        assert_eq!(recent.len(), 1);
+
+        let with_access = library::Library::get_users_with_access(shared.library_id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(with_access.len(), 1);
+        assert_eq!(with_access[0].username, "member");
+
+        assert!(
+            library::Library::revoke_access(shared.library_id, member, &pool)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !library::Library::revoke_access(shared.library_id, member, &pool)
+                .await
+                .unwrap()
+        );
+        assert!(!library::Book::can_access(shared.id, member, &pool)
+            .await
+            .unwrap());
+        pool.close().await;
+    }
 }
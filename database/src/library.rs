@@ -1,10 +1,31 @@
+use crate::assets::Asset;
 use serde::{Deserialize, Serialize};
 use sqlx;
-use sqlx::{Pool, Row, Sqlite};
+use sqlx::{Connection, FromRow, Pool, Row, Sqlite, SqliteConnection};
+use std::collections::HashMap;
 use utoipa::ToSchema;
-use crate::assets::Asset;
 
 use crate::assets;
+use crate::users::User;
+
+// A list of ids as a JSON array, so it can be bound to one parameter and read with json_each
+fn id_list(ids: &[i32]) -> String {
+    let ids: Vec<String> = ids.iter().map(i32::to_string).collect();
+    format!("[{}]", ids.join(","))
+}
+
+// The ids of the libraries a user can see, admins see every library. For putting in a query,
+// user_param is the placeholder the user id is bound to, like $1.
+pub(crate) fn visible_libraries(user_param: &str) -> String {
+    format!(
+        r#"(
+            SELECT library_id FROM library_access WHERE user_id = {0}
+            UNION
+            SELECT id FROM libraries WHERE (SELECT role FROM users WHERE id = {0}) = 'Admin'
+        )"#,
+        user_param
+    )
+}
 
 #[derive(Deserialize, ToSchema)]
 #[schema(as = Library)]
@@ -94,6 +115,98 @@ impl Library {
         Ok(libraries)
     }
 
+    pub async fn get_libraries_for_user(
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Library>, sqlx::Error> {
+        let libraries: Vec<Library> = sqlx::query_as::<_, Library>(&format!(
+            r#"
+            SELECT * FROM libraries WHERE id IN {}
+            "#,
+            visible_libraries("$1")
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(libraries)
+    }
+
+    pub async fn can_access(
+        id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            r#"
+            SELECT $1 IN {}
+            "#,
+            visible_libraries("$2")
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+    }
+
+    // Giving access twice does nothing
+    pub async fn grant_access(
+        id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO library_access (user_id, library_id) VALUES ($1, $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // False when the user didn't have access
+    pub async fn revoke_access(
+        id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM library_access WHERE user_id = $1 AND library_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // The users that have been given access, admins see the library without it
+    pub async fn get_users_with_access(
+        id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        let users: Vec<User> = sqlx::query_as::<_, User>(
+            r#"
+            SELECT users.* FROM users
+            INNER JOIN library_access ON library_access.user_id = users.id
+            WHERE library_access.library_id = $1
+            ORDER BY users.username
+            "#,
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(users)
+    }
+
     pub async fn delete_library(id: i32, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
@@ -153,6 +266,8 @@ pub struct Book {
     pub library_id: i32,
     pub collection_id: i32,
     pub primary_cover: Option<String>,
+    // Seconds since the unix epoch
+    pub added_at: i64,
 }
 
 impl Book {
@@ -169,6 +284,25 @@ impl Book {
         Ok(book)
     }
 
+    // Keeps the order of the ids, ids without a book are left out
+    pub async fn get_books_by_ids(
+        ids: &[i32],
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(
+            r#"
+            SELECT books.* FROM json_each($1) AS ids
+            INNER JOIN books ON books.id = ids.value
+            ORDER BY ids.key
+            "#,
+        )
+        .bind(id_list(ids))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
     pub async fn get_books(pool: &Pool<Sqlite>) -> Result<Vec<Book>, sqlx::Error> {
         let books: Vec<Book> = sqlx::query_as::<_, Book>(
             r#"
@@ -181,6 +315,41 @@ impl Book {
         Ok(books)
     }
 
+    pub async fn get_books_for_user(
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM books WHERE library_id IN {}
+            "#,
+            visible_libraries("$1")
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
+    // False for books that don't exist as well
+    pub async fn can_access(
+        id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM books WHERE id = $1 AND library_id IN {})
+            "#,
+            visible_libraries("$2")
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+    }
+
     pub async fn get_books_by_library(
         library_id: i32,
         pool: &Pool<Sqlite>,
@@ -197,6 +366,115 @@ impl Book {
         Ok(books)
     }
 
+    // Newest first, of the books the user can see
+    pub async fn get_recent(
+        limit: i64,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM books WHERE library_id IN {}
+            ORDER BY added_at DESC, id DESC LIMIT $1
+            "#,
+            visible_libraries("$2")
+        ))
+        .bind(limit)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
+    // One page of the books in a library, sorted by title
+    pub async fn get_page_by_library(
+        library_id: i32,
+        limit: i64,
+        offset: i64,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(
+            r#"
+            SELECT * FROM books WHERE library_id = $1
+            ORDER BY name COLLATE NOCASE, id LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(library_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
+    // One page of the books in a collection, sorted by title
+    pub async fn get_page_by_collection(
+        collection_id: i32,
+        limit: i64,
+        offset: i64,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(
+            r#"
+            SELECT * FROM books WHERE collection_id = $1
+            ORDER BY name COLLATE NOCASE, id LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(collection_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
+    // One page of the books by an author that the user can see, sorted by title
+    pub async fn get_page_by_author(
+        author_id: i32,
+        user_id: i32,
+        limit: i64,
+        offset: i64,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Book>, sqlx::Error> {
+        let books: Vec<Book> = sqlx::query_as::<_, Book>(&format!(
+            r#"
+            SELECT books.* FROM books
+            INNER JOIN book_authors ON book_authors.book_id = books.id
+            WHERE book_authors.author_id = $1 AND books.library_id IN {}
+            ORDER BY books.name COLLATE NOCASE, books.id LIMIT $3 OFFSET $4
+            "#,
+            visible_libraries("$2")
+        ))
+        .bind(author_id)
+        .bind(user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(books)
+    }
+
+    pub async fn update(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE books SET name = $1, collection_id = $2, primary_cover = $3
+            WHERE id = $4
+            "#,
+        )
+        .bind(&self.name)
+        .bind(self.collection_id)
+        .bind(&self.primary_cover)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn delete_self(&self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query(
             r#"
@@ -211,6 +489,40 @@ impl Book {
     }
 }
 
+// A book together with the file it was scanned from
+#[derive(sqlx::FromRow)]
+pub struct BookFile {
+    pub book_id: i32,
+    pub asset_id: String,
+    pub collection_id: i32,
+    pub local_path: String,
+    pub file_size: Option<i64>,
+    pub modified_at: Option<i64>,
+    pub content_hash: Option<String>,
+}
+
+impl BookFile {
+    pub async fn get_by_library(
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<BookFile>, sqlx::Error> {
+        let book_files: Vec<BookFile> = sqlx::query_as::<_, BookFile>(
+            r#"
+            SELECT books.id AS book_id, books.asset_id, books.collection_id, assets.local_path,
+                assets.file_size, assets.modified_at, assets.content_hash
+            FROM books
+            INNER JOIN assets ON assets.id = books.asset_id
+            WHERE books.library_id = $1
+            "#,
+        )
+        .bind(library_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(book_files)
+    }
+}
+
 pub struct InsertableBook {
     pub path: String,
     pub name: String,
@@ -221,6 +533,11 @@ pub struct InsertableBook {
 
 impl InsertableBook {
     pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<Book, sqlx::Error> {
+        self.insert_in(&mut *pool.acquire().await?).await
+    }
+
+    // Stores the book and its file on one connection, so a scan can keep them in its transaction
+    pub async fn insert_in(self, connection: &mut SqliteConnection) -> Result<Book, sqlx::Error> {
         let Self {
             path,
             name,
@@ -242,13 +559,13 @@ impl InsertableBook {
             file_extension: Some("application/epub+zip".to_string()),
         };
 
-        let asset_id = insertable_asset.insert(pool).await.unwrap().id;
+        let asset_id = insertable_asset.insert(&mut *connection).await?.id;
 
         let result = sqlx::query(
             r#"
-            INSERT INTO books (asset_id, name, library_id, collection_id, primary_cover)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING id
+            INSERT INTO books (asset_id, name, library_id, collection_id, primary_cover, added_at)
+            VALUES ($1, $2, $3, $4, $5, strftime('%s', 'now'))
+            RETURNING id, added_at
             "#,
         )
         .bind(&asset_id)
@@ -256,11 +573,11 @@ impl InsertableBook {
         .bind(&library_id)
         .bind(&collection_id)
         .bind(&primary_cover)
-        .fetch_one(pool)
-        .await
-        .unwrap();
+        .fetch_one(&mut *connection)
+        .await?;
 
         let id: i32 = result.get("id");
+        let added_at: i64 = result.get("added_at");
 
         Ok(Book {
             id,
@@ -269,6 +586,7 @@ impl InsertableBook {
             library_id,
             collection_id,
             primary_cover: primary_cover,
+            added_at,
         })
     }
 
@@ -286,6 +604,19 @@ pub struct Collection {
 }
 
 impl Collection {
+    pub async fn get_collection(id: i32, pool: &Pool<Sqlite>) -> Result<Collection, sqlx::Error> {
+        let collection: Collection = sqlx::query_as::<_, Collection>(
+            r#"
+            SELECT * FROM collections WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(collection)
+    }
+
     pub async fn get_by_libary(
         library_id: i32,
         pool: &Pool<Sqlite>,
@@ -354,46 +685,57 @@ impl InsertableCollection {
     }
 }
 
-
 pub struct InsertableBookProgress {
     pub book_id: i32,
     pub user_id: i32,
-    pub page: i32,
-    pub page_progress: f32,
+    pub spine_index: i32,
+    pub page_progress: f64,
+    pub total_progress: f64,
+    pub cfi: Option<String>,
+    // Milliseconds since the unix epoch, set by whoever made the change
+    pub updated_at: i64,
 }
 
 impl InsertableBookProgress {
-    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<BookProgress, sqlx::Error> {
+    // Keeps a single row per user and book. An older write than the stored one is ignored,
+    // so the returned progress is always the newest one.
+    pub async fn upsert(self, pool: &Pool<Sqlite>) -> Result<BookProgress, sqlx::Error> {
         let Self {
             book_id,
             user_id,
-            page,
+            spine_index,
             page_progress,
+            total_progress,
+            cfi,
+            updated_at,
         } = self;
 
-        let result = sqlx::query(
+        sqlx::query(
             r#"
-            INSERT INTO book_progress (book_id, user_id, page, page_progress)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id
+            INSERT INTO book_progress (book_id, user_id, spine_index, page_progress, total_progress, cfi, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (book_id, user_id) DO UPDATE SET
+                spine_index = excluded.spine_index,
+                page_progress = excluded.page_progress,
+                total_progress = excluded.total_progress,
+                cfi = excluded.cfi,
+                updated_at = excluded.updated_at
+            WHERE excluded.updated_at >= book_progress.updated_at
             "#,
         )
-        .bind(&book_id)
-        .bind(&user_id)
-        .bind(&page)
-        .bind(&page_progress)
-        .fetch_one(pool)
+        .bind(book_id)
+        .bind(user_id)
+        .bind(spine_index)
+        .bind(page_progress)
+        .bind(total_progress)
+        .bind(&cfi)
+        .bind(updated_at)
+        .execute(pool)
         .await?;
 
-        let id: i32 = result.get("id");
-
-        Ok(BookProgress {
-            id,
-            book_id,
-            user_id,
-            page,
-            page_progress,
-        })
+        BookProgress::get_progress(book_id, user_id, pool)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
     }
 }
 
@@ -402,6 +744,440 @@ pub struct BookProgress {
     pub id: i32,
     pub book_id: i32,
     pub user_id: i32,
-    pub page: i32,
-    pub page_progress: f32,
+    pub spine_index: i32,
+    pub page_progress: f64,
+    pub total_progress: f64,
+    pub cfi: Option<String>,
+    pub updated_at: i64,
+}
+
+impl BookProgress {
+    pub async fn get_progress(
+        book_id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<BookProgress>, sqlx::Error> {
+        let progress: Option<BookProgress> = sqlx::query_as::<_, BookProgress>(
+            r#"
+            SELECT * FROM book_progress WHERE book_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(book_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(progress)
+    }
+}
+
+pub struct InsertableAuthor {
+    pub name: String,
+    pub role: Option<String>,
+    pub file_as: Option<String>,
+}
+
+pub struct InsertableIdentifier {
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+pub struct InsertableSeries {
+    pub name: String,
+    pub position: Option<f64>,
+}
+
+pub struct InsertableBookMetadata {
+    pub book_id: i32,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub authors: Vec<InsertableAuthor>,
+    pub subjects: Vec<String>,
+    pub identifiers: Vec<InsertableIdentifier>,
+    pub series: Option<InsertableSeries>,
+}
+
+impl InsertableBookMetadata {
+    // Replaces any metadata already stored for the book
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<BookMetadata, sqlx::Error> {
+        self.insert_in(&mut *pool.acquire().await?).await
+    }
+
+    // Nested in the transaction of the connection when it has one
+    pub async fn insert_in(
+        self,
+        connection: &mut SqliteConnection,
+    ) -> Result<BookMetadata, sqlx::Error> {
+        let Self {
+            book_id,
+            language,
+            publisher,
+            published,
+            description,
+            authors,
+            subjects,
+            identifiers,
+            series,
+        } = self;
+
+        let mut transaction = connection.begin().await?;
+
+        sqlx::query(
+            r#"
+            UPDATE books SET language = $1, publisher = $2, published = $3, description = $4
+            WHERE id = $5
+            "#,
+        )
+        .bind(&language)
+        .bind(&publisher)
+        .bind(&published)
+        .bind(&description)
+        .bind(book_id)
+        .execute(&mut *transaction)
+        .await?;
+
+        for table in [
+            "book_authors",
+            "book_subjects",
+            "book_identifiers",
+            "book_series",
+        ] {
+            sqlx::query(&format!("DELETE FROM {} WHERE book_id = $1", table))
+                .bind(book_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        for (position, author) in authors.iter().enumerate() {
+            // The same author is shared between books, keep the first known sort key
+            let result = sqlx::query(
+                r#"
+                INSERT INTO authors (name, file_as) VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE SET file_as = COALESCE(authors.file_as, excluded.file_as)
+                RETURNING id
+                "#,
+            )
+            .bind(&author.name)
+            .bind(&author.file_as)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+            let author_id: i32 = result.get("id");
+
+            sqlx::query(
+                r#"
+                INSERT INTO book_authors (book_id, author_id, role, position)
+                VALUES ($1, $2, $3, $4)
+                "#,
+            )
+            .bind(book_id)
+            .bind(author_id)
+            .bind(&author.role)
+            .bind(position as i32)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        for subject in &subjects {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO subjects (name) VALUES ($1)
+                ON CONFLICT (name) DO UPDATE SET name = excluded.name
+                RETURNING id
+                "#,
+            )
+            .bind(subject)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+            let subject_id: i32 = result.get("id");
+
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO book_subjects (book_id, subject_id) VALUES ($1, $2)
+                "#,
+            )
+            .bind(book_id)
+            .bind(subject_id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        for identifier in &identifiers {
+            sqlx::query(
+                r#"
+                INSERT INTO book_identifiers (book_id, scheme, value) VALUES ($1, $2, $3)
+                "#,
+            )
+            .bind(book_id)
+            .bind(&identifier.scheme)
+            .bind(&identifier.value)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        if let Some(series) = &series {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO series (name) VALUES ($1)
+                ON CONFLICT (name) DO UPDATE SET name = excluded.name
+                RETURNING id
+                "#,
+            )
+            .bind(&series.name)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+            let series_id: i32 = result.get("id");
+
+            sqlx::query(
+                r#"
+                INSERT INTO book_series (book_id, series_id, position) VALUES ($1, $2, $3)
+                "#,
+            )
+            .bind(book_id)
+            .bind(series_id)
+            .bind(series.position)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        let metadata = BookMetadata::get_by_books_in(&[book_id], &mut transaction)
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)?;
+        transaction.commit().await?;
+
+        Ok(metadata)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Author {
+    pub id: i32,
+    pub name: String,
+    pub file_as: Option<String>,
+    pub book_count: i64,
+}
+
+// The book counts only include the books the user can see
+impl Author {
+    pub async fn get_author(
+        id: i32,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Author, sqlx::Error> {
+        let author: Author = sqlx::query_as::<_, Author>(&format!(
+            r#"
+            SELECT authors.id, authors.name, authors.file_as, COUNT(books.id) AS book_count
+            FROM authors
+            LEFT JOIN book_authors ON book_authors.author_id = authors.id
+            LEFT JOIN books ON books.id = book_authors.book_id AND books.library_id IN {}
+            WHERE authors.id = $1
+            GROUP BY authors.id
+            "#,
+            visible_libraries("$2")
+        ))
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(author)
+    }
+
+    // Authors of at least one book the user can see, sorted the way they are filed
+    pub async fn get_authors(
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<Author>, sqlx::Error> {
+        let authors: Vec<Author> = sqlx::query_as::<_, Author>(&format!(
+            r#"
+            SELECT authors.id, authors.name, authors.file_as, COUNT(books.id) AS book_count
+            FROM authors
+            INNER JOIN book_authors ON book_authors.author_id = authors.id
+            INNER JOIN books ON books.id = book_authors.book_id
+            WHERE books.library_id IN {}
+            GROUP BY authors.id
+            ORDER BY COALESCE(authors.file_as, authors.name) COLLATE NOCASE
+            "#,
+            visible_libraries("$1")
+        ))
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(authors)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct BookAuthor {
+    pub id: i32,
+    pub name: String,
+    pub file_as: Option<String>,
+    pub role: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct BookIdentifier {
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct BookSeries {
+    pub id: i32,
+    pub name: String,
+    pub position: Option<f64>,
+}
+
+pub struct BookMetadata {
+    pub book_id: i32,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub authors: Vec<BookAuthor>,
+    pub subjects: Vec<String>,
+    pub identifiers: Vec<BookIdentifier>,
+    pub series: Vec<BookSeries>,
+}
+
+impl BookMetadata {
+    pub async fn get_by_book(
+        book_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<BookMetadata, sqlx::Error> {
+        BookMetadata::get_by_books(&[book_id], pool)
+            .await?
+            .pop()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // The same few queries however many books there are, so listings don't need one per book.
+    // Keeps the order of the ids, ids without a book are left out.
+    pub async fn get_by_books(
+        book_ids: &[i32],
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<BookMetadata>, sqlx::Error> {
+        BookMetadata::get_by_books_in(book_ids, &mut *pool.acquire().await?).await
+    }
+
+    pub async fn get_by_books_in(
+        book_ids: &[i32],
+        connection: &mut SqliteConnection,
+    ) -> Result<Vec<BookMetadata>, sqlx::Error> {
+        let ids = id_list(book_ids);
+
+        let mut metadata: Vec<BookMetadata> = sqlx::query(
+            r#"
+            SELECT books.id, books.language, books.publisher, books.published, books.description
+            FROM json_each($1) AS ids
+            INNER JOIN books ON books.id = ids.value
+            ORDER BY ids.key
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *connection)
+        .await?
+        .iter()
+        .map(|row| BookMetadata {
+            book_id: row.get("id"),
+            language: row.get("language"),
+            publisher: row.get("publisher"),
+            published: row.get("published"),
+            description: row.get("description"),
+            authors: Vec::new(),
+            subjects: Vec::new(),
+            identifiers: Vec::new(),
+            series: Vec::new(),
+        })
+        .collect();
+        let positions: HashMap<i32, usize> = metadata
+            .iter()
+            .enumerate()
+            .map(|(position, book)| (book.book_id, position))
+            .collect();
+
+        let authors = sqlx::query(
+            r#"
+            SELECT book_authors.book_id, authors.id, authors.name, authors.file_as, book_authors.role
+            FROM authors
+            INNER JOIN book_authors ON book_authors.author_id = authors.id
+            WHERE book_authors.book_id IN (SELECT value FROM json_each($1))
+            ORDER BY book_authors.position
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *connection)
+        .await?;
+        for row in authors {
+            if let Some(&position) = positions.get(&row.get("book_id")) {
+                metadata[position].authors.push(BookAuthor::from_row(&row)?);
+            }
+        }
+
+        let subjects = sqlx::query(
+            r#"
+            SELECT book_subjects.book_id, subjects.name FROM subjects
+            INNER JOIN book_subjects ON book_subjects.subject_id = subjects.id
+            WHERE book_subjects.book_id IN (SELECT value FROM json_each($1))
+            ORDER BY subjects.name
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *connection)
+        .await?;
+        for row in subjects {
+            if let Some(&position) = positions.get(&row.get("book_id")) {
+                metadata[position].subjects.push(row.get("name"));
+            }
+        }
+
+        let identifiers = sqlx::query(
+            r#"
+            SELECT book_id, scheme, value FROM book_identifiers
+            WHERE book_id IN (SELECT value FROM json_each($1))
+            ORDER BY id
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *connection)
+        .await?;
+        for row in identifiers {
+            if let Some(&position) = positions.get(&row.get("book_id")) {
+                metadata[position]
+                    .identifiers
+                    .push(BookIdentifier::from_row(&row)?);
+            }
+        }
+
+        let series = sqlx::query(
+            r#"
+            SELECT book_series.book_id, series.id, series.name, book_series.position FROM series
+            INNER JOIN book_series ON book_series.series_id = series.id
+            WHERE book_series.book_id IN (SELECT value FROM json_each($1))
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *connection)
+        .await?;
+        for row in series {
+            if let Some(&position) = positions.get(&row.get("book_id")) {
+                metadata[position].series.push(BookSeries::from_row(&row)?);
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn isbn(&self) -> Option<&str> {
+        self.identifiers
+            .iter()
+            .find(|identifier| identifier.scheme.as_deref() == Some("isbn"))
+            .map(|identifier| identifier.value.as_str())
+    }
 }
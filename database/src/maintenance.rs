@@ -0,0 +1,91 @@
+use crate::MIGRATOR;
+use sqlx;
+use sqlx::{Pool, Row, Sqlite};
+
+#[derive(Debug, Default)]
+pub struct DatabaseCheck {
+    // What SQLite found wrong with the file itself, empty when it is fine
+    pub integrity_errors: Vec<String>,
+    // Rows pointing at rows that don't exist, like "bookmarks row 4 -> books"
+    pub foreign_key_errors: Vec<String>,
+    // Migrations built into the binary that haven't been run on the database
+    pub pending_migrations: Vec<String>,
+    // Migrations in the database that this binary doesn't know about, it is older than the database
+    pub unknown_migrations: Vec<i64>,
+}
+
+impl DatabaseCheck {
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty()
+            && self.foreign_key_errors.is_empty()
+            && self.pending_migrations.is_empty()
+            && self.unknown_migrations.is_empty()
+    }
+}
+
+pub async fn check(pool: &Pool<Sqlite>) -> Result<DatabaseCheck, sqlx::Error> {
+    let integrity: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(pool)
+        .await?;
+    let integrity_errors = integrity
+        .into_iter()
+        .filter(|message| message != "ok")
+        .collect();
+
+    let foreign_key_errors = sqlx::query("PRAGMA foreign_key_check")
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            let table: String = row.get("table");
+            let rowid: Option<i64> = row.get("rowid");
+            let parent: String = row.get("parent");
+            format!("{} row {} -> {}", table, rowid.unwrap_or_default(), parent)
+        })
+        .collect();
+
+    let applied = applied_migrations(pool).await?;
+
+    let pending_migrations = MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| format!("{} {}", migration.version, migration.description))
+        .collect();
+    let unknown_migrations = applied
+        .into_iter()
+        .filter(|version| {
+            !MIGRATOR
+                .iter()
+                .any(|migration| migration.version == *version)
+        })
+        .collect();
+
+    Ok(DatabaseCheck {
+        integrity_errors,
+        foreign_key_errors,
+        pending_migrations,
+        unknown_migrations,
+    })
+}
+
+// The versions of the migrations that have been run, a new database has none
+pub async fn applied_migrations(pool: &Pool<Sqlite>) -> Result<Vec<i64>, sqlx::Error> {
+    let has_migrations: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if !has_migrations {
+        return Ok(Vec::new());
+    }
+
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success = 1")
+            .fetch_all(pool)
+            .await?;
+
+    Ok(applied)
+}
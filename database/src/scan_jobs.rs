@@ -0,0 +1,259 @@
+use serde::Serialize;
+use sqlx;
+use sqlx::{Pool, Sqlite};
+
+#[derive(sqlx::Type, Serialize, PartialEq, Clone, Copy, Debug)]
+pub enum ScanStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Default, PartialEq, Clone, Debug)]
+pub struct ScanCounts {
+    pub discovered: i64,
+    pub imported: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub removed: i64,
+}
+
+#[derive(sqlx::FromRow, Clone, Debug)]
+pub struct ScanJob {
+    pub id: i32,
+    pub library_id: i32,
+    pub status: ScanStatus,
+    pub discovered: i64,
+    pub imported: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub removed: i64,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct ScanJobError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ScanJob {
+    pub async fn insert(
+        library_id: i32,
+        status: ScanStatus,
+        pool: &Pool<Sqlite>,
+    ) -> Result<ScanJob, sqlx::Error> {
+        let job: ScanJob = sqlx::query_as::<_, ScanJob>(
+            r#"
+            INSERT INTO scan_jobs (library_id, status, created_at, started_at)
+            VALUES ($1, $2, strftime('%s', 'now'), CASE WHEN $2 = 'Running' THEN strftime('%s', 'now') END)
+            RETURNING *
+            "#,
+        )
+        .bind(library_id)
+        .bind(status)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn get_job(id: i32, pool: &Pool<Sqlite>) -> Result<ScanJob, sqlx::Error> {
+        let job: ScanJob = sqlx::query_as::<_, ScanJob>(
+            r#"
+            SELECT * FROM scan_jobs WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    // The oldest job waiting for the library
+    pub async fn get_queued(
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<ScanJob>, sqlx::Error> {
+        let job: Option<ScanJob> = sqlx::query_as::<_, ScanJob>(
+            r#"
+            SELECT * FROM scan_jobs WHERE library_id = $1 AND status = 'Queued'
+            ORDER BY id LIMIT 1
+            "#,
+        )
+        .bind(library_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    // A job that is queued or running, possibly in another process
+    pub async fn get_active(
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<ScanJob>, sqlx::Error> {
+        let job: Option<ScanJob> = sqlx::query_as::<_, ScanJob>(
+            r#"
+            SELECT * FROM scan_jobs WHERE library_id = $1 AND status IN ('Queued', 'Running')
+            ORDER BY id LIMIT 1
+            "#,
+        )
+        .bind(library_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn get_errors(&self, pool: &Pool<Sqlite>) -> Result<Vec<ScanJobError>, sqlx::Error> {
+        let errors: Vec<ScanJobError> = sqlx::query_as::<_, ScanJobError>(
+            r#"
+            SELECT path, message FROM scan_job_errors WHERE job_id = $1 ORDER BY id
+            "#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(errors)
+    }
+
+    pub async fn start(&mut self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        let started_at: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE scan_jobs SET status = 'Running', started_at = strftime('%s', 'now')
+            WHERE id = $1
+            RETURNING started_at
+            "#,
+        )
+        .bind(self.id)
+        .fetch_one(pool)
+        .await?;
+
+        self.status = ScanStatus::Running;
+        self.started_at = Some(started_at);
+
+        Ok(())
+    }
+
+    pub async fn update_counts(
+        &mut self,
+        counts: &ScanCounts,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE scan_jobs SET discovered = $1, imported = $2, skipped = $3, failed = $4, removed = $5
+            WHERE id = $6
+            "#,
+        )
+        .bind(counts.discovered)
+        .bind(counts.imported)
+        .bind(counts.skipped)
+        .bind(counts.failed)
+        .bind(counts.removed)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.discovered = counts.discovered;
+        self.imported = counts.imported;
+        self.skipped = counts.skipped;
+        self.failed = counts.failed;
+        self.removed = counts.removed;
+
+        Ok(())
+    }
+
+    // Stores the final counts and the path and message of every file that failed
+    pub async fn finish(
+        &mut self,
+        counts: &ScanCounts,
+        errors: &[(String, String)],
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        for (path, message) in errors {
+            sqlx::query(
+                r#"
+                INSERT INTO scan_job_errors (job_id, path, message) VALUES ($1, $2, $3)
+                "#,
+            )
+            .bind(self.id)
+            .bind(path)
+            .bind(message)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        let finished_at: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE scan_jobs SET status = 'Finished', finished_at = strftime('%s', 'now'),
+                discovered = $1, imported = $2, skipped = $3, failed = $4, removed = $5
+            WHERE id = $6
+            RETURNING finished_at
+            "#,
+        )
+        .bind(counts.discovered)
+        .bind(counts.imported)
+        .bind(counts.skipped)
+        .bind(counts.failed)
+        .bind(counts.removed)
+        .bind(self.id)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        self.status = ScanStatus::Finished;
+        self.finished_at = Some(finished_at);
+        self.discovered = counts.discovered;
+        self.imported = counts.imported;
+        self.skipped = counts.skipped;
+        self.failed = counts.failed;
+        self.removed = counts.removed;
+
+        Ok(())
+    }
+
+    pub async fn fail(&mut self, error: String, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        let finished_at: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE scan_jobs SET status = 'Failed', error = $1, finished_at = strftime('%s', 'now')
+            WHERE id = $2
+            RETURNING finished_at
+            "#,
+        )
+        .bind(&error)
+        .bind(self.id)
+        .fetch_one(pool)
+        .await?;
+
+        self.status = ScanStatus::Failed;
+        self.error = Some(error);
+        self.finished_at = Some(finished_at);
+
+        Ok(())
+    }
+
+    // Jobs that were queued or running when the server stopped will never finish
+    pub async fn fail_unfinished(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE scan_jobs SET status = 'Failed', error = 'Interrupted', finished_at = strftime('%s', 'now')
+            WHERE status IN ('Queued', 'Running')
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
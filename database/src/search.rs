@@ -0,0 +1,169 @@
+use sqlx;
+use sqlx::{Pool, Sqlite};
+
+// Wrapped around the matched words in a snippet. The snippet is plain text from the book,
+// so it has to be escaped before the markers are turned into something like <mark>.
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+pub struct SearchDocument {
+    // None when the book has no readable text, so it can still be found by title and author
+    pub spine_index: Option<i32>,
+    pub content: String,
+}
+
+pub struct InsertableSearchIndex {
+    pub book_id: i32,
+    pub title: String,
+    pub authors: String,
+    pub documents: Vec<SearchDocument>,
+}
+
+impl InsertableSearchIndex {
+    // Replaces whatever was indexed for the book before
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        let Self {
+            book_id,
+            title,
+            authors,
+            mut documents,
+        } = self;
+
+        if documents.is_empty() {
+            documents.push(SearchDocument {
+                spine_index: None,
+                content: String::new(),
+            });
+        }
+
+        let mut transaction = pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM book_search WHERE book_id = $1
+            "#,
+        )
+        .bind(book_id)
+        .execute(&mut *transaction)
+        .await?;
+
+        for document in documents {
+            sqlx::query(
+                r#"
+                INSERT INTO book_search (title, authors, content, book_id, spine_index)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+            )
+            .bind(&title)
+            .bind(&authors)
+            .bind(&document.content)
+            .bind(book_id)
+            .bind(document.spine_index)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct SearchResult {
+    pub book_id: i32,
+    pub title: String,
+    pub spine_index: Option<i32>,
+    pub snippet: String,
+    // Lower is better
+    pub rank: f64,
+}
+
+impl SearchResult {
+    // The best matching spine document of every book the user can see, best books first.
+    // Matches in the title count the most, then the authors, then the text.
+    pub async fn search(
+        query: &str,
+        limit: i64,
+        user_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<SearchResult>, sqlx::Error> {
+        let expression = match_expression(query);
+        if expression.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let results: Vec<SearchResult> = sqlx::query_as::<_, SearchResult>(&format!(
+            r#"
+            WITH hits AS (
+                SELECT rowid AS id, book_id, bm25(book_search, 10.0, 5.0, 1.0) AS rank
+                FROM book_search
+                WHERE book_search MATCH $1
+                AND CAST(book_id AS INTEGER) IN (SELECT id FROM books WHERE library_id IN {})
+            ),
+            best AS (
+                SELECT id, rank FROM (
+                    SELECT id, rank, row_number() OVER (PARTITION BY book_id ORDER BY rank) AS position
+                    FROM hits
+                )
+                WHERE position = 1
+                ORDER BY rank
+                LIMIT $4
+            )
+            SELECT
+                CAST(book_search.book_id AS INTEGER) AS book_id,
+                book_search.title,
+                book_search.spine_index,
+                snippet(book_search, -1, $2, $3, '…', 24) AS snippet,
+                best.rank
+            FROM book_search
+            JOIN best ON best.id = book_search.rowid
+            WHERE book_search MATCH $1
+            ORDER BY best.rank
+            "#,
+            crate::library::visible_libraries("$5")
+        ))
+        .bind(expression)
+        .bind(MATCH_START.to_string())
+        .bind(MATCH_END.to_string())
+        .bind(limit)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(results)
+    }
+
+    // Books that have never been indexed, like the ones scanned before there was a search index
+    pub async fn get_unindexed(
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        let book_ids: Vec<i32> = sqlx::query_scalar(
+            r#"
+            SELECT id FROM books
+            WHERE library_id = $1 AND id NOT IN (SELECT book_id FROM book_search)
+            "#,
+        )
+        .bind(library_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(book_ids)
+    }
+}
+
+// Treats every word as a literal so quotes and operators typed by users can't break the query.
+// The last word is matched as a prefix, since it is often still being typed.
+fn match_expression(query: &str) -> String {
+    let words: Vec<String> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+
+    if words.is_empty() {
+        return String::new();
+    }
+
+    format!("{}*", words.join(" "))
+}
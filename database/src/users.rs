@@ -1,19 +1,42 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use utoipa::ToSchema;
 
 extern crate rand_core;
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
     Argon2,
 };
 
+#[derive(sqlx::Type, Serialize, Deserialize, ToSchema, PartialEq, Clone, Copy, Debug)]
+pub enum Role {
+    // Manages libraries, folders and invites
+    Admin,
+    Member,
+    // Can read and download, but can't save progress, bookmarks or highlights
+    ReadOnly,
+}
+
+impl Role {
+    pub fn can_write(self) -> bool {
+        self != Role::ReadOnly
+    }
+}
+
 #[derive(sqlx::FromRow)]
 pub struct User {
     pub id: i32,
     pub username: String,
     #[sqlx(rename = "password")]
     pub hashed_password: String,
+    pub role: Role,
 }
 
 impl User {
@@ -45,41 +68,251 @@ impl User {
 
         Ok(user)
     }
+
+    pub async fn get_users(pool: &Pool<Sqlite>) -> Result<Vec<Self>, sqlx::Error> {
+        let users = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users ORDER BY username
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    // Fails with a unique constraint error when the username is taken
+    pub async fn set_username(
+        &mut self,
+        username: &str,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET username = $1 WHERE id = $2
+            "#,
+        )
+        .bind(username)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.username = username.to_string();
+
+        Ok(())
+    }
+
+    pub async fn set_password(
+        &mut self,
+        password: &str,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        let hashed_password = hash_password(password);
+
+        sqlx::query(
+            r#"
+            UPDATE users SET password = $1 WHERE id = $2
+            "#,
+        )
+        .bind(&hashed_password)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.hashed_password = hashed_password;
+
+        Ok(())
+    }
+
+    pub async fn set_role(&mut self, role: Role, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE users SET role = $1 WHERE id = $2
+            "#,
+        )
+        .bind(role)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.role = role;
+
+        Ok(())
+    }
+
+    pub async fn any_exist(pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users)")
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn count_admins(pool: &Pool<Sqlite>) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE role = 'Admin'")
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete_self(self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        self.delete(false, pool).await.map(|_| ())
+    }
+
+    // Returns false when the user is the last admin and was kept. The check is part of the delete
+    // statement, so two admins deleting each other at the same time can't both succeed.
+    pub async fn delete_unless_last_admin(self, pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
+        self.delete(true, pool).await
+    }
+
+    // Everything the user owns cascades, but not when the connection has foreign keys turned off
+    async fn delete(self, keep_last_admin: bool, pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        let deleted = sqlx::query(
+            r#"
+            DELETE FROM users WHERE id = $1
+            AND NOT ($2 AND role = 'Admin' AND (SELECT COUNT(*) FROM users WHERE role = 'Admin') <= 1)
+            "#,
+        )
+        .bind(self.id)
+        .bind(keep_last_admin)
+        .execute(&mut *transaction)
+        .await?;
+        if deleted.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        for table in [
+            "bearer_tokens",
+            "book_progress",
+            "bookmarks",
+            "highlights",
+            "library_access",
+        ] {
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id = $1", table))
+                .bind(self.id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
 }
 
 pub struct InsertableUser {
     pub username: String,
     pub hashed_password: String,
+    pub role: Role,
 }
 
 impl InsertableUser {
-    async fn insert(self, pool: &Pool<Sqlite>) -> Result<User, sqlx::Error> {
+    // The first user becomes the admin whatever the role, someone has to be able to set things up.
+    // Takes any executor so registering with an invite can run in a transaction.
+    pub async fn insert<'c, E>(self, executor: E) -> Result<User, sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = Sqlite>,
+    {
         let Self {
             username,
             hashed_password,
+            role,
         } = self;
 
         let result = sqlx::query(
             r#"
-            INSERT INTO users ( username, password ) VALUES ( $1, $2 )
-            RETURNING id
+            INSERT INTO users ( username, password, role )
+            VALUES ( $1, $2, CASE WHEN EXISTS (SELECT 1 FROM users) THEN $3 ELSE 'Admin' END )
+            RETURNING id, role
             "#,
         )
         .bind(&username)
         .bind(&hashed_password)
-        .fetch_one(pool)
+        .bind(role)
+        .fetch_one(executor)
         .await?;
 
         let id: i32 = result.get("id");
+        let role: Role = result.get("role");
 
         Ok(User {
             id,
             username,
             hashed_password,
+            role,
         })
     }
 }
 
+#[derive(sqlx::FromRow)]
+pub struct Invite {
+    pub id: i32,
+    pub code: String,
+    pub role: Role,
+    pub created_by: Option<i32>,
+    // Seconds since the unix epoch
+    pub created_at: i64,
+    pub used_by: Option<i32>,
+    pub used_at: Option<i64>,
+}
+
+impl Invite {
+    pub async fn get_invites(pool: &Pool<Sqlite>) -> Result<Vec<Self>, sqlx::Error> {
+        let invites = sqlx::query_as::<_, Invite>(
+            r#"
+            SELECT * FROM invites ORDER BY created_at DESC
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(invites)
+    }
+
+    pub async fn delete_invite(id: i32, pool: &Pool<Sqlite>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM invites WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct InsertableInvite {
+    pub role: Role,
+    pub created_by: Option<i32>,
+}
+
+impl InsertableInvite {
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<Invite, sqlx::Error> {
+        let Self { role, created_by } = self;
+
+        // Short enough to read out loud or type on a phone
+        let mut bytes = [0u8; 8];
+        OsRng.fill_bytes(&mut bytes);
+        let code: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let invite = sqlx::query_as::<_, Invite>(
+            r#"
+            INSERT INTO invites ( code, role, created_by, created_at )
+            VALUES ( $1, $2, $3, strftime('%s', 'now') )
+            RETURNING *
+            "#,
+        )
+        .bind(code)
+        .bind(role)
+        .bind(created_by)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(invite)
+    }
+}
+
 pub fn hash_password(password: &str) -> String {
     let salt_string = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -99,62 +332,291 @@ pub fn verify_password<'a>(hashed_password: &'a str, password: &str) -> bool {
         .is_ok()
 }
 
-struct InsertableBearerToken {
-    user_id: i32,
-    token: String,
+// How long a username and password that checked out are trusted without running argon2 again
+const CREDENTIAL_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+// Basic auth sends the password with every request, and a page of covers in a reader is a lot of
+// requests. Maps a salted hash of the credentials to the password hash they were checked against,
+// so a changed password or a deleted user doesn't match any more.
+type CheckedCredentials = HashMap<[u8; 32], (String, Instant)>;
+static CHECKED_CREDENTIALS: OnceLock<Mutex<CheckedCredentials>> = OnceLock::new();
+static CREDENTIAL_SALT: OnceLock<[u8; 32]> = OnceLock::new();
+// Unknown usernames are checked against this, so they take as long as a wrong password
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+fn checked_credentials() -> &'static Mutex<CheckedCredentials> {
+    CHECKED_CREDENTIALS.get_or_init(Default::default)
 }
 
-impl InsertableBearerToken {
-    pub fn new(user_id: i32) -> Self {
-        let token = uuid::Uuid::new_v4().to_string();
-        Self { user_id, token }
+fn credential_key(username: &str, password: &str) -> [u8; 32] {
+    let salt = CREDENTIAL_SALT.get_or_init(|| {
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    });
+
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(username.as_bytes());
+    hasher.update([0]);
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+// For Basic auth, where the password comes with every request. Argon2 runs on the blocking
+// threads, it is too slow for the ones driving the requests.
+pub async fn authenticate_password(
+    username: &str,
+    password: &str,
+    pool: &Pool<Sqlite>,
+) -> Result<Option<User>, sqlx::Error> {
+    let user = User::find_by_username(username, pool).await?;
+    let key = credential_key(username, password);
+
+    let checked = user.as_ref().is_some_and(|user| {
+        checked_credentials().lock().unwrap().get(&key).is_some_and(
+            |(hashed_password, checked_at)| {
+                *hashed_password == user.hashed_password
+                    && checked_at.elapsed() < CREDENTIAL_LIFETIME
+            },
+        )
+    });
+    if checked {
+        return Ok(user);
     }
 
-    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<BearerToken, sqlx::Error> {
-        let Self { user_id, token } = self;
+    let hashed_password = user.as_ref().map(|user| user.hashed_password.clone());
+    let password = password.to_string();
+    let valid = tokio::task::spawn_blocking(move || match hashed_password {
+        Some(hashed_password) => verify_password(&hashed_password, &password),
+        None => {
+            let dummy_hash = DUMMY_HASH.get_or_init(|| hash_password("dummy password"));
+            verify_password(dummy_hash, &password);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false);
+
+    let user = user.filter(|_| valid);
+    if let Some(user) = &user {
+        let mut checked = checked_credentials().lock().unwrap();
+        checked.retain(|_, (_, checked_at)| checked_at.elapsed() < CREDENTIAL_LIFETIME);
+        checked.insert(key, (user.hashed_password.clone(), Instant::now()));
+    }
 
-        let result = sqlx::query(
+    Ok(user)
+}
+
+// How long a token lasts without being used, using it pushes the expiry forward
+pub const TOKEN_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+// Writing on every request would be a lot for the reader, which loads every image on its own
+const LAST_USED_RESOLUTION: i64 = 60;
+
+// Only a hash of the token is stored, so a copy of the database can't be used to log in.
+// The tokens are random, a slow password hash isn't needed.
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub struct InsertableBearerToken {
+    pub user_id: i32,
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl InsertableBearerToken {
+    pub async fn insert(self, pool: &Pool<Sqlite>) -> Result<NewBearerToken, sqlx::Error> {
+        let Self {
+            user_id,
+            device,
+            user_agent,
+        } = self;
+
+        let token = generate_token();
+
+        let bearer_token = sqlx::query_as::<_, BearerToken>(
             r#"
-            INSERT INTO bearer_tokens ( user_id, token ) VALUES ( $1, $2 )
-            RETURNING id
+            INSERT INTO bearer_tokens
+            ( user_id, token_hash, created_at, last_used_at, expires_at, device, user_agent )
+            VALUES ( $1, $2, strftime('%s', 'now'), strftime('%s', 'now'), strftime('%s', 'now') + $3, $4, $5 )
+            RETURNING *
             "#,
         )
-        .bind(&user_id)
-        .bind(&token)
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(TOKEN_LIFETIME)
+        .bind(device)
+        .bind(user_agent)
         .fetch_one(pool)
         .await?;
 
-        let id: i32 = result.get("id");
-
-        let bearer_token = BearerToken { id, user_id, token };
-
-        Ok(bearer_token)
+        Ok(NewBearerToken {
+            token,
+            bearer_token,
+        })
     }
 }
 
+// The token itself is only known when it is created
+pub struct NewBearerToken {
+    pub token: String,
+    pub bearer_token: BearerToken,
+}
+
 #[derive(sqlx::FromRow)]
 pub struct BearerToken {
     pub id: i32,
     pub user_id: i32,
-    pub token: String,
+    pub token_hash: String,
+    // Seconds since the unix epoch
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+    pub device: Option<String>,
+    pub user_agent: Option<String>,
 }
 
 impl BearerToken {
+    // Expired tokens are not found
     pub async fn find_by_token(
         token: &str,
         pool: &Pool<Sqlite>,
     ) -> Result<Option<Self>, sqlx::Error> {
         let bearer_token: Option<BearerToken> = sqlx::query_as::<_, BearerToken>(
             r#"
-            SELECT * FROM bearer_tokens WHERE token = $1
+            SELECT * FROM bearer_tokens WHERE token_hash = $1 AND expires_at > strftime('%s', 'now')
+            "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(bearer_token)
+    }
+
+    // Finds the token and marks it as used, for checking requests
+    pub async fn authenticate(
+        token: &str,
+        pool: &Pool<Sqlite>,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let bearer_token = match Self::find_by_token(token, pool).await? {
+            Some(bearer_token) => bearer_token,
+            None => return Ok(None),
+        };
+
+        let used = sqlx::query_as::<_, BearerToken>(
+            r#"
+            UPDATE bearer_tokens
+            SET last_used_at = strftime('%s', 'now'), expires_at = strftime('%s', 'now') + $1
+            WHERE id = $2 AND last_used_at <= strftime('%s', 'now') - $3
+            RETURNING *
+            "#,
+        )
+        .bind(TOKEN_LIFETIME)
+        .bind(bearer_token.id)
+        .bind(LAST_USED_RESOLUTION)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(Some(used.unwrap_or(bearer_token)))
+    }
+
+    pub async fn get_by_user(user_id: i32, pool: &Pool<Sqlite>) -> Result<Vec<Self>, sqlx::Error> {
+        let bearer_tokens = sqlx::query_as::<_, BearerToken>(
+            r#"
+            SELECT * FROM bearer_tokens
+            WHERE user_id = $1 AND expires_at > strftime('%s', 'now')
+            ORDER BY last_used_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(bearer_tokens)
+    }
+
+    pub async fn get_token(id: i32, pool: &Pool<Sqlite>) -> Result<Option<Self>, sqlx::Error> {
+        let bearer_token = sqlx::query_as::<_, BearerToken>(
+            r#"
+            SELECT * FROM bearer_tokens WHERE id = $1
             "#,
         )
-        .bind(token)
+        .bind(id)
         .fetch_optional(pool)
         .await?;
 
         Ok(bearer_token)
     }
+
+    pub async fn delete_self(self, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM bearer_tokens WHERE id = $1
+            "#,
+        )
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Logs the user out everywhere
+    pub async fn delete_by_user(user_id: i32, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM bearer_tokens WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Logs the user out everywhere else, after changing the password
+    pub async fn delete_other_sessions(
+        user_id: i32,
+        keep_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM bearer_tokens WHERE user_id = $1 AND id != $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(keep_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_expired(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM bearer_tokens WHERE expires_at <= strftime('%s', 'now')
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, ToSchema)]
@@ -162,11 +624,23 @@ impl BearerToken {
 pub struct Login {
     pub username: String,
     pub password: String,
+    // A name for the session, so it can be told apart from the others
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
 impl Login {
-    pub async fn login(self, pool: &Pool<Sqlite>) -> Result<BearerToken, LoginError> {
-        let Self { username, password } = self;
+    // Every login gets its own token
+    pub async fn login(
+        self,
+        user_agent: Option<String>,
+        pool: &Pool<Sqlite>,
+    ) -> Result<NewBearerToken, LoginError> {
+        let Self {
+            username,
+            password,
+            device,
+        } = self;
 
         let user_result = User::find_by_username(&username, &pool).await;
 
@@ -180,19 +654,18 @@ impl Login {
             return Err(LoginError::PasswordIncorrect);
         }
 
-        let token: BearerToken = sqlx::query_as::<_, BearerToken>(
-            r#"
-            SELECT * FROM bearer_tokens WHERE user_id = $1
-            "#,
-        )
-        .bind(user.id)
-        .fetch_one(pool)
-        .await
-        .map_err(|error| match error {
-            error => LoginError::DatabaseError(error),
-        })?;
+        BearerToken::delete_expired(pool)
+            .await
+            .map_err(LoginError::DatabaseError)?;
 
-        Ok(token)
+        InsertableBearerToken {
+            user_id: user.id,
+            device,
+            user_agent,
+        }
+        .insert(pool)
+        .await
+        .map_err(LoginError::DatabaseError)
     }
 }
 
@@ -208,11 +681,25 @@ pub enum LoginError {
 pub struct Register {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub device: Option<String>,
+    // Gives the role of the invite instead of Member
+    #[serde(default)]
+    pub invite_code: Option<String>,
 }
 
 impl Register {
-    pub async fn register(self, pool: &Pool<Sqlite>) -> Result<BearerToken, RegisterError> {
-        let Self { username, password } = self;
+    pub async fn register(
+        self,
+        user_agent: Option<String>,
+        pool: &Pool<Sqlite>,
+    ) -> Result<NewBearerToken, RegisterError> {
+        let Self {
+            username,
+            password,
+            device,
+            invite_code,
+        } = self;
 
         let user_result = User::find_by_username(&username, &pool).await;
 
@@ -224,22 +711,66 @@ impl Register {
 
         let hashed_password = hash_password(&password);
 
+        // The invite is claimed together with the user, so it can't be used twice
+        let mut transaction = pool.begin().await.map_err(RegisterError::DatabaseError)?;
+
+        let role = match &invite_code {
+            Some(code) => sqlx::query_scalar::<_, Role>(
+                r#"
+                UPDATE invites SET used_at = strftime('%s', 'now')
+                WHERE code = $1 AND used_at IS NULL
+                RETURNING role
+                "#,
+            )
+            .bind(code)
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(RegisterError::DatabaseError)?
+            .ok_or(RegisterError::InvalidInvite)?,
+            None => Role::Member,
+        };
+
         let user = InsertableUser {
             username: username.clone(),
             hashed_password,
+            role,
         }
-        .insert(pool)
+        .insert(&mut *transaction)
         .await
         .map_err(|error| match error {
-            error => panic!("Unexpected error: {:?}", error),
+            // Someone else took the name after the check above
+            sqlx::Error::Database(error) if error.is_unique_violation() => {
+                RegisterError::UsernameTaken
+            }
+            error => RegisterError::DatabaseError(error),
         })?;
 
-        let token = InsertableBearerToken::new(user.id)
-            .insert(pool)
+        if let Some(code) = &invite_code {
+            sqlx::query(
+                r#"
+                UPDATE invites SET used_by = $1 WHERE code = $2
+                "#,
+            )
+            .bind(user.id)
+            .bind(code)
+            .execute(&mut *transaction)
             .await
-            .map_err(|error| match error {
-                error => panic!("Unexpected error: {:?}", error),
-            })?;
+            .map_err(RegisterError::DatabaseError)?;
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(RegisterError::DatabaseError)?;
+
+        let token = InsertableBearerToken {
+            user_id: user.id,
+            device,
+            user_agent,
+        }
+        .insert(pool)
+        .await
+        .map_err(RegisterError::DatabaseError)?;
 
         Ok(token)
     }
@@ -249,4 +780,5 @@ impl Register {
 pub enum RegisterError {
     DatabaseError(sqlx::Error),
     UsernameTaken,
+    InvalidInvite,
 }
@@ -0,0 +1,604 @@
+// EPUB Canonical Fragment Identifiers, see https://idpf.org/epub/linking/cfi/
+//
+// Only the parts readers need are supported: a path to an element or a text position in a
+// spine document, with id assertions, character offsets and ranges. Temporal and spatial
+// offsets and text assertions are skipped when parsing.
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::fmt::{Display, Write};
+use std::str::FromStr;
+
+// The spine is the third child of the package element, after metadata and manifest
+const SPINE_STEP: usize = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CfiStep {
+    // Even numbers are elements, odd numbers the text between them
+    pub index: usize,
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CfiPath {
+    pub steps: Vec<CfiStep>,
+    // Character offset into the text the last step points at
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfi {
+    pub spine_index: usize,
+    // The idref of the spine item, used to find the document again if the spine changes
+    pub spine_id: Option<String>,
+    // Path inside the spine document, for a range it is the common parent of start and end
+    pub path: CfiPath,
+    // Start and end of a range, relative to path
+    pub range: Option<(CfiPath, CfiPath)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedLocation {
+    // Zero based position among the element children, starting below the root element
+    pub element_path: Vec<usize>,
+    // The id of the element the path ends at
+    pub element_id: Option<String>,
+    // Zero based text chunk of the element when the location is inside text
+    pub text_node: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedCfi {
+    pub spine_index: usize,
+    pub start: ResolvedLocation,
+    pub end: Option<ResolvedLocation>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CfiError {
+    Syntax(String),
+    SpineIndexOutOfRange,
+    NotFound(String),
+    InvalidDocument(String),
+}
+
+impl Display for CfiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CfiError::Syntax(error) => write!(f, "Invalid cfi: {}", error),
+            CfiError::SpineIndexOutOfRange => write!(f, "The cfi points outside the spine"),
+            CfiError::NotFound(error) => write!(f, "Could not resolve cfi: {}", error),
+            CfiError::InvalidDocument(error) => write!(f, "Could not read document: {}", error),
+        }
+    }
+}
+
+impl Cfi {
+    // Points at the start of a spine document
+    pub fn for_spine_item(spine_index: usize, spine_id: Option<String>) -> Cfi {
+        Cfi {
+            spine_index,
+            spine_id,
+            path: CfiPath::default(),
+            range: None,
+        }
+    }
+
+    pub fn is_range(&self) -> bool {
+        self.range.is_some()
+    }
+
+    // The path to the start of a range, or the whole path when it isn't a range
+    pub fn start(&self) -> CfiPath {
+        match &self.range {
+            Some((start, _)) => join(&self.path, start),
+            None => self.path.clone(),
+        }
+    }
+
+    pub fn end(&self) -> Option<CfiPath> {
+        self.range.as_ref().map(|(_, end)| join(&self.path, end))
+    }
+}
+
+fn join(parent: &CfiPath, local: &CfiPath) -> CfiPath {
+    CfiPath {
+        steps: parent.steps.iter().chain(&local.steps).cloned().collect(),
+        offset: local.offset,
+    }
+}
+
+// Characters with a meaning in a cfi have to be escaped inside assertions
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .fold(String::with_capacity(value.len()), |mut escaped, c| {
+            if matches!(c, '^' | '[' | ']' | '(' | ')' | ',' | ';' | '=') {
+                escaped.push('^');
+            }
+            escaped.push(c);
+            escaped
+        })
+}
+
+fn write_path(f: &mut impl Write, path: &CfiPath) -> std::fmt::Result {
+    for step in &path.steps {
+        write!(f, "/{}", step.index)?;
+        if let Some(id) = &step.id {
+            write!(f, "[{}]", escape(id))?;
+        }
+    }
+    if let Some(offset) = path.offset {
+        write!(f, ":{}", offset)?;
+    }
+    Ok(())
+}
+
+impl Display for Cfi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "epubcfi(/{}/{}", SPINE_STEP, (self.spine_index + 1) * 2)?;
+        if let Some(spine_id) = &self.spine_id {
+            write!(f, "[{}]", escape(spine_id))?;
+        }
+        f.write_char('!')?;
+        write_path(f, &self.path)?;
+        if let Some((start, end)) = &self.range {
+            f.write_char(',')?;
+            write_path(f, start)?;
+            f.write_char(',')?;
+            write_path(f, end)?;
+        }
+        f.write_char(')')
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> CfiError {
+        CfiError::Syntax(message.to_string())
+    }
+
+    fn integer(&mut self) -> Result<usize, CfiError> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*c);
+            self.chars.next();
+        }
+        digits.parse().map_err(|_| self.error("Expected a number"))
+    }
+
+    // Reads what is inside [], and returns the part before any parameters
+    fn assertion(&mut self) -> Result<Option<String>, CfiError> {
+        if self.chars.peek() != Some(&'[') {
+            return Ok(None);
+        }
+        self.chars.next();
+
+        let mut value = String::new();
+        let mut in_parameters = false;
+        loop {
+            match self.chars.next() {
+                Some('^') => {
+                    let c = self.chars.next().ok_or(self.error("Unfinished escape"))?;
+                    if !in_parameters {
+                        value.push(c);
+                    }
+                }
+                Some(';') => in_parameters = true,
+                Some(']') => break,
+                Some(c) if !in_parameters => value.push(c),
+                Some(_) => (),
+                None => return Err(self.error("Unclosed assertion")),
+            }
+        }
+
+        Ok(Some(value).filter(|value| !value.is_empty()))
+    }
+
+    fn steps(&mut self) -> Result<Vec<CfiStep>, CfiError> {
+        let mut steps = Vec::new();
+        while self.chars.peek() == Some(&'/') {
+            self.chars.next();
+            let index = self.integer()?;
+            let id = self.assertion()?;
+            steps.push(CfiStep { index, id });
+        }
+        Ok(steps)
+    }
+
+    fn path(&mut self) -> Result<CfiPath, CfiError> {
+        let steps = self.steps()?;
+        let mut offset = None;
+
+        loop {
+            match self.chars.peek() {
+                Some(':') => {
+                    self.chars.next();
+                    offset = Some(self.integer()?);
+                    // Text assertions only help when the text has changed
+                    self.assertion()?;
+                }
+                // Temporal and spatial offsets only matter for media
+                Some('~') | Some('@') => {
+                    self.chars.next();
+                    while let Some(c) = self.chars.peek() {
+                        if c.is_ascii_digit() || *c == '.' || *c == ':' {
+                            self.chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    self.assertion()?;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(CfiPath { steps, offset })
+    }
+}
+
+impl FromStr for Cfi {
+    type Err = CfiError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let inner = value
+            .trim()
+            .strip_prefix("epubcfi(")
+            .and_then(|inner| inner.strip_suffix(')'))
+            .ok_or(CfiError::Syntax("Missing epubcfi()".to_string()))?;
+
+        let mut parser = Parser {
+            chars: inner.chars().peekable(),
+        };
+
+        let package_steps = parser.steps()?;
+        if parser.chars.next() != Some('!') {
+            return Err(parser.error("Expected ! after the spine step"));
+        }
+        let spine_step = match package_steps.as_slice() {
+            [_, spine_step] if spine_step.index >= 2 && spine_step.index % 2 == 0 => spine_step,
+            _ => return Err(parser.error("Expected a path to a spine item")),
+        };
+
+        let path = parser.path()?;
+        let range = match parser.chars.next() {
+            None => None,
+            Some(',') => {
+                let start = parser.path()?;
+                if parser.chars.next() != Some(',') {
+                    return Err(parser.error("Expected the end of the range"));
+                }
+                let end = parser.path()?;
+                Some((start, end))
+            }
+            Some(c) => return Err(CfiError::Syntax(format!("Unexpected character {}", c))),
+        };
+
+        if parser.chars.next().is_some() {
+            return Err(parser.error("Unexpected characters at the end"));
+        }
+
+        Ok(Cfi {
+            spine_index: spine_step.index / 2 - 1,
+            spine_id: spine_step.id.clone(),
+            path,
+            range,
+        })
+    }
+}
+
+struct Node {
+    id: Option<String>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+// The element structure of a document, text is not needed since it's addressed by position
+struct Document {
+    nodes: Vec<Node>,
+}
+
+fn id_attribute(e: &BytesStart) -> Option<String> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .find(|a| a.key.local_name().as_ref() == b"id")
+        .and_then(|a| a.unescape_value().ok().map(|value| value.to_string()))
+}
+
+impl Document {
+    fn parse(xml: &str) -> Result<Document, CfiError> {
+        let mut reader = Reader::from_str(xml);
+        // Lots of books have slightly broken xhtml
+        reader.check_end_names(false);
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+
+        loop {
+            let (e, empty) = match reader.read_event() {
+                Ok(Event::Start(e)) => (e, false),
+                Ok(Event::Empty(e)) => (e, true),
+                Ok(Event::End(_)) => {
+                    stack.pop();
+                    continue;
+                }
+                Ok(Event::Eof) => break,
+                Ok(_) => continue,
+                Err(error) => return Err(CfiError::InvalidDocument(error.to_string())),
+            };
+
+            let parent = stack.last().copied();
+            // Anything after the root element is ignored
+            if parent.is_none() && !nodes.is_empty() {
+                continue;
+            }
+
+            let index = nodes.len();
+            nodes.push(Node {
+                id: id_attribute(&e),
+                parent,
+                children: Vec::new(),
+            });
+            if let Some(parent) = parent {
+                nodes[parent].children.push(index);
+            }
+            if !empty {
+                stack.push(index);
+            }
+        }
+
+        if nodes.is_empty() {
+            return Err(CfiError::InvalidDocument("No root element".to_string()));
+        }
+
+        Ok(Document { nodes })
+    }
+
+    fn find_id(&self, id: &str) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.id.as_deref() == Some(id))
+    }
+
+    fn element_path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = Vec::new();
+        while let Some(parent) = self.nodes[node].parent {
+            let position = self.nodes[parent]
+                .children
+                .iter()
+                .position(|child| *child == node)
+                .unwrap_or_default();
+            path.push(position);
+            node = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    fn node_at(&self, element_path: &[usize]) -> Option<usize> {
+        element_path.iter().try_fold(0, |node, position| {
+            self.nodes[node].children.get(*position).copied()
+        })
+    }
+
+    fn resolve(&self, path: &CfiPath) -> Result<ResolvedLocation, CfiError> {
+        let mut node = 0;
+        let mut text_node = None;
+
+        for (position, step) in path.steps.iter().enumerate() {
+            if step.index % 2 == 1 {
+                if position + 1 != path.steps.len() {
+                    return Err(CfiError::NotFound(
+                        "Only the last step can point at text".to_string(),
+                    ));
+                }
+                text_node = Some(step.index / 2);
+                break;
+            }
+
+            let child = step
+                .index
+                .checked_sub(2)
+                .and_then(|index| self.nodes[node].children.get(index / 2).copied());
+
+            // The id wins when the document has changed since the cfi was made
+            node = match (child, &step.id) {
+                (Some(child), Some(id)) if self.nodes[child].id.as_ref() != Some(id) => {
+                    self.find_id(id).unwrap_or(child)
+                }
+                (Some(child), _) => child,
+                (None, Some(id)) => self
+                    .find_id(id)
+                    .ok_or(CfiError::NotFound(format!("No element with id {}", id)))?,
+                (None, None) => {
+                    return Err(CfiError::NotFound(format!(
+                        "No element at step {}",
+                        step.index
+                    )))
+                }
+            };
+        }
+
+        Ok(ResolvedLocation {
+            element_path: self.element_path(node),
+            element_id: self.nodes[node].id.clone(),
+            text_node,
+            offset: path.offset,
+        })
+    }
+
+    fn steps(&self, node: usize) -> Vec<CfiStep> {
+        self.element_path(node)
+            .into_iter()
+            .scan(0, |parent, position| {
+                let child = self.nodes[*parent].children[position];
+                *parent = child;
+                Some(CfiStep {
+                    index: (position + 1) * 2,
+                    id: self.nodes[child].id.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Finds the element and text position a cfi points at in the given spine document
+pub fn resolve(cfi: &Cfi, document: &str) -> Result<ResolvedCfi, CfiError> {
+    let document = Document::parse(document)?;
+
+    let start = document.resolve(&cfi.start())?;
+    let end = match cfi.end() {
+        Some(end) => Some(document.resolve(&end)?),
+        None => None,
+    };
+
+    Ok(ResolvedCfi {
+        spine_index: cfi.spine_index,
+        start,
+        end,
+    })
+}
+
+// Makes a cfi for an element, given by its position among the element children below the root.
+// With text, the cfi points at a character in the given text chunk of the element.
+pub fn generate(
+    spine_index: usize,
+    spine_id: Option<String>,
+    document: &str,
+    element_path: &[usize],
+    text: Option<(usize, usize)>,
+) -> Result<Cfi, CfiError> {
+    let document = Document::parse(document)?;
+    let node = document.node_at(element_path).ok_or(CfiError::NotFound(
+        "No element at the given path".to_string(),
+    ))?;
+
+    let mut path = CfiPath {
+        steps: document.steps(node),
+        offset: None,
+    };
+    if let Some((text_node, offset)) = text {
+        path.steps.push(CfiStep {
+            index: text_node * 2 + 1,
+            id: None,
+        });
+        path.offset = Some(offset);
+    }
+
+    Ok(Cfi {
+        spine_index,
+        spine_id,
+        path,
+        range: None,
+    })
+}
+
+// Makes a cfi for the element with the given id, e.g. the fragment of a toc entry
+pub fn generate_for_id(
+    spine_index: usize,
+    spine_id: Option<String>,
+    document: &str,
+    id: &str,
+) -> Result<Cfi, CfiError> {
+    let parsed = Document::parse(document)?;
+    let node = parsed
+        .find_id(id)
+        .ok_or(CfiError::NotFound(format!("No element with id {}", id)))?;
+
+    generate(
+        spine_index,
+        spine_id,
+        document,
+        &parsed.element_path(node),
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+    <head><title>Chapter</title></head>
+    <body id="body01">
+        <p>First</p>
+        <p>Second<br/>line</p>
+        <p id="para05">Some <em>text</em> here</p>
+    </body>
+</html>"#;
+
+    #[test]
+    fn test_parse_and_display() {
+        let cfi: Cfi = "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)"
+            .parse()
+            .unwrap();
+
+        assert_eq!(cfi.spine_index, 1);
+        assert_eq!(cfi.spine_id.as_deref(), Some("chap01ref"));
+        assert_eq!(cfi.path.steps.len(), 3);
+        assert_eq!(cfi.path.steps[1].id.as_deref(), Some("para05"));
+        assert_eq!(cfi.path.offset, Some(10));
+        assert_eq!(
+            cfi.to_string(),
+            "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)"
+        );
+
+        let range: Cfi = "epubcfi(/6/4!/4/10,/1:1,/3:4)".parse().unwrap();
+        assert!(range.is_range());
+        assert_eq!(range.start().steps.len(), 3);
+        assert_eq!(range.end().unwrap().offset, Some(4));
+        assert_eq!(range.to_string(), "epubcfi(/6/4!/4/10,/1:1,/3:4)");
+
+        let escaped: Cfi = "epubcfi(/6/2[a^]b;s=x]!/2~1.5@10:20)".parse().unwrap();
+        assert_eq!(escaped.spine_id.as_deref(), Some("a]b"));
+        assert_eq!(escaped.to_string(), "epubcfi(/6/2[a^]b]!/2)");
+
+        assert!("epubcfi(/6/3!/4)".parse::<Cfi>().is_err());
+        assert!("epubcfi(/6/4!/4[unclosed)".parse::<Cfi>().is_err());
+        assert!("/6/4!/4".parse::<Cfi>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let cfi: Cfi = "epubcfi(/6/2!/4/6[para05]/1:3)".parse().unwrap();
+        let resolved = resolve(&cfi, CHAPTER).unwrap();
+
+        assert_eq!(resolved.start.element_path, vec![1, 2]);
+        assert_eq!(resolved.start.element_id.as_deref(), Some("para05"));
+        assert_eq!(resolved.start.text_node, Some(0));
+        assert_eq!(resolved.start.offset, Some(3));
+
+        // A wrong step is corrected by the id assertion
+        let moved: Cfi = "epubcfi(/6/2!/4/2[para05])".parse().unwrap();
+        assert_eq!(
+            resolve(&moved, CHAPTER).unwrap().start.element_path,
+            vec![1, 2]
+        );
+
+        let range: Cfi = "epubcfi(/6/2!/4/6,/1:0,/2/1:4)".parse().unwrap();
+        let resolved = resolve(&range, CHAPTER).unwrap();
+        assert_eq!(resolved.start.element_path, vec![1, 2]);
+        assert_eq!(resolved.end.unwrap().element_path, vec![1, 2, 0]);
+
+        let missing: Cfi = "epubcfi(/6/2!/4/20)".parse().unwrap();
+        assert!(resolve(&missing, CHAPTER).is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let cfi = generate(2, Some("chapter3".into()), CHAPTER, &[1, 1], Some((1, 2))).unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/6[chapter3]!/4[body01]/4/3:2)");
+
+        let cfi = generate_for_id(0, None, CHAPTER, "para05").unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/2!/4[body01]/6[para05])");
+        assert_eq!(
+            resolve(&cfi, CHAPTER).unwrap().start.element_path,
+            vec![1, 2]
+        );
+    }
+}
@@ -31,10 +31,12 @@ fn extract_styles(doc: &mut EpubDoc<BufReader<File>>, style_path: String) -> Vec
     styles_with_newline.into_bytes()
 }
 
-pub fn process_page(file_path: PathBuf, page_num: usize, book_id: i32) -> String {
+// The resource url is where the resources of the book are served, like
+// https://example.com/api/v1/book/{book_id}/resource
+pub fn process_page(file_path: PathBuf, page_num: usize, resource_url: &str) -> String {
     let mut doc = EpubDoc::new(&file_path).unwrap();
     doc.set_current_page(page_num);
-    let mut converter = EpubXmlHtmlConverter::new(doc, book_id);
+    let mut converter = EpubXmlHtmlConverter::new(doc, resource_url.to_string());
     converter.convert();
     converter.generate_html()
 }
@@ -632,11 +634,11 @@ struct EpubXmlHtmlConverter {
     xml_tree: Tree<XmlNode>,
     epub_doc: EpubDoc<BufReader<File>>,
     html_doc: String,
-    book_id: i32,
+    resource_url: String,
 }
 
 impl EpubXmlHtmlConverter {
-    pub fn new(mut epub_doc: EpubDoc<BufReader<File>>, book_id: i32) -> Self {
+    pub fn new(mut epub_doc: EpubDoc<BufReader<File>>, resource_url: String) -> Self {
         let xml_parser = XmlPraser::new(epub_doc.get_current_str().unwrap().0);
         let xml_tree = xml_parser.parse();
         let html_doc = String::new();
@@ -645,7 +647,7 @@ impl EpubXmlHtmlConverter {
             xml_tree,
             epub_doc,
             html_doc,
-            book_id,
+            resource_url,
         }
     }
 
@@ -660,8 +662,8 @@ impl EpubXmlHtmlConverter {
                 let attributes = node.attributes.as_mut().unwrap();
                 let image_path = attributes.get("xlink:href").unwrap();
                 let new_image_path = format!(
-                    "http://127.0.0.1:8273/api/v1/book/{}/resource?path={}",
-                    self.book_id,
+                    "{}?path={}",
+                    self.resource_url,
                     image_path.strip_prefix("../").unwrap_or(image_path)
                 );
 
@@ -696,8 +698,8 @@ impl EpubXmlHtmlConverter {
                 let attributes = node.attributes.as_mut().unwrap();
                 let image_path = attributes.get("src").unwrap();
                 let new_image_path = format!(
-                    "http://127.0.0.1:8273/api/v1/book/{}/resource?path={}",
-                    self.book_id,
+                    "{}?path={}",
+                    self.resource_url,
                     image_path.strip_prefix("../").unwrap_or(image_path)
                 );
                 attributes.insert("src".to_string(), new_image_path);
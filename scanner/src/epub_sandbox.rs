@@ -3,12 +3,17 @@ use quick_xml::events::Event;
 use quick_xml::reader::Reader;
 use quick_xml::Writer;
 
+use crate::cfi::{self, Cfi, CfiError, ResolvedCfi};
+use crate::metadata::{read_metadata, Metadata};
+use crate::toc::{parse_nav, parse_ncx, TocEntry};
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::BufReader;
 use std::io::Cursor;
 use std::io::Read;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
 use zip::read::ZipFile;
 use zip::ZipArchive;
@@ -22,13 +27,23 @@ type SpineItem = String;
 type MimeType = String;
 type EpubArchive = ZipArchive<BufReader<std::fs::File>>;
 type Resource = HashMap<String, (PathBuf, MimeType)>;
+
+// Used when an epub is missing META-INF/container.xml
+const DEFAULT_PACKAGE_PATH: &str = "content.opf";
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+
 pub struct Epub {
     file: EpubArchive,
     spine: Vec<SpineItem>,
     resources: Vec<Resource>,
-    metadata: HashMap<String, String>,
-    cover_id: Option<String>,
+    metadata: Metadata,
     path: PathBuf,
+    // The directory containing the package document, all manifest hrefs are relative to it
+    root_dir: PathBuf,
+    // Manifest id of the EPUB3 navigation document
+    nav_id: Option<String>,
+    // Manifest id of the EPUB2 toc.ncx, taken from the toc attribute of the spine
+    ncx_id: Option<String>,
 }
 
 impl Epub {
@@ -41,9 +56,11 @@ impl Epub {
             spine: Vec::new(),
             file: zip,
             resources: Vec::new(),
-            metadata: HashMap::new(),
-            cover_id: None,
+            metadata: Metadata::default(),
             path: path.clone(),
+            root_dir: PathBuf::new(),
+            nav_id: None,
+            ncx_id: None,
         };
 
         epub.populate_epub()?;
@@ -54,8 +71,26 @@ impl Epub {
         &self.path
     }
 
+    fn find_package_path(&mut self) -> Result<String, EpubError> {
+        let container = match self.file.by_name(CONTAINER_PATH) {
+            Ok(container) => container,
+            // Some older epubs skip the container, assume the package is at the root
+            Err(zip::result::ZipError::FileNotFound) => return Ok(DEFAULT_PACKAGE_PATH.to_string()),
+            Err(e) => return Err(EpubError::from(e)),
+        };
+        let mut reader = Reader::from_reader(BufReader::new(container));
+
+        read_container(&mut reader)?.ok_or(EpubError::MissingPackage)
+    }
+
     fn populate_epub(&mut self) -> Result<(), EpubError> {
-        let content = self.file.by_name("content.opf")?;
+        let package_path = self.find_package_path()?;
+        self.root_dir = PathBuf::from(&package_path)
+            .parent()
+            .map(|parent| parent.to_path_buf())
+            .unwrap_or_default();
+
+        let content = self.file.by_name(&package_path)?;
         let content_reader = BufReader::new(content);
         let mut reader = Reader::from_reader(content_reader);
 
@@ -65,15 +100,25 @@ impl Epub {
             match reader.read_event_into(&mut buff) {
                 Ok(Event::Start(ref e)) => {
                     if let b"spine" = e.name().as_ref() {
+                        self.ncx_id = e
+                            .attributes()
+                            .filter_map(|a| a.ok())
+                            .find(|a| a.key.as_ref() == b"toc")
+                            .and_then(|a| a.unescape_value().ok().map(|id| id.to_string()));
                         read_spine(reader.borrow_mut(), &mut self.spine)?;
                     } else if let b"manifest" = e.name().as_ref() {
-                        read_manifest(reader.borrow_mut(), &mut self.resources)?;
-                    } else if let b"metadata" = e.name().as_ref() {
-                        read_metadata(reader.borrow_mut(), &mut self.metadata, &mut self.cover_id)?;
+                        self.nav_id = read_manifest(
+                            reader.borrow_mut(),
+                            &mut self.resources,
+                            &self.root_dir,
+                        )?;
+                    } else if let b"metadata" = e.local_name().as_ref() {
+                        self.metadata = read_metadata(reader.borrow_mut())?;
                     }
                 }
                 Ok(Event::Eof) => break,
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+                // A broken package makes the book unreadable, it must not take the scan down with it
+                Err(e) => return Err(EpubError::from(e)),
                 _ => (),
             }
         }
@@ -96,8 +141,7 @@ impl Epub {
         zip_file.read_to_end(&mut buff);
 
         if path.ends_with(".css") {
-            let styles = std::str::from_utf8(&buff)
-                .unwrap()
+            let styles = String::from_utf8_lossy(&buff)
                 .replace("-webkit-", "")
                 .replace("-epub-", "");
             return Some(styles.into_bytes());
@@ -113,33 +157,129 @@ impl Epub {
             .map(|resource| resource.clone())
     }
 
-    pub fn get_page(&mut self, index: usize, asset_id: &String) -> Option<(Vec<u8>, String)> {
+    // The resource url is where get_book_resource serves this book, like
+    // https://example.com/api/v1/book/{asset_id}/resource
+    pub fn get_page(&mut self, index: usize, resource_url: &str) -> Option<(Vec<u8>, String)> {
         let id = self.spine.get(index)?;
         let resource = self.get_res(id.clone())?;
         let (path, mime_type) = resource.get(id)?;
         // Need to do some modification to the html file
         // Need to add a base tag to the head
         let file = self.get_res_by_path(&path)?;
-        let base = format!(
-            "http://localhost:8273/api/v1/book/{}/resource/{}",
-            asset_id,
-            path.to_str()?
-        );
-        let file = add_base(file, &base).unwrap();
+        let base = format!("{}/{}", resource_url, path.to_str()?);
+        let file = add_base(file, &base).ok()?;
 
         Some((file, mime_type.clone()))
     }
 
     pub fn get_cover(&mut self) -> Option<(Vec<u8>, String)> {
-        let id = self.cover_id.as_ref()?;
+        let id = self.metadata.cover_id.as_ref()?;
         let resource = self.get_res(id.clone())?;
         let (path, mime_type) = resource.get(id)?;
         let file = self.get_res_by_path(&path)?;
         Some((file, mime_type.clone()))
     }
 
-    pub fn get_metadata(&self, identifier: &str) -> Option<&String> {
-        self.metadata.get(identifier)
+    // Itemrefs missing from the manifest stay as None so the positions match the spine indexes
+    fn spine_paths(&self) -> Vec<Option<PathBuf>> {
+        self.spine
+            .iter()
+            .map(|id| {
+                let resource = self.get_res(id.clone())?;
+                resource.get(id).map(|(path, _)| path.clone())
+            })
+            .collect()
+    }
+
+    fn read_toc_document(&mut self, id: &str) -> Option<(String, PathBuf)> {
+        let resource = self.get_res(id.to_string())?;
+        let (path, _) = resource.get(id)?;
+        let file = self.get_res_by_path(path)?;
+        let doc_dir = path
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default();
+
+        Some((String::from_utf8_lossy(&file).into_owned(), doc_dir))
+    }
+
+    // Prefers the EPUB3 navigation document, and falls back to the EPUB2 ncx
+    pub fn get_toc(&mut self) -> Result<Vec<TocEntry>, EpubError> {
+        let spine = self.spine_paths();
+
+        if let Some(nav_id) = self.nav_id.clone() {
+            if let Some((nav, doc_dir)) = self.read_toc_document(&nav_id) {
+                let toc = parse_nav(&nav, &doc_dir, &spine)?;
+                if !toc.is_empty() {
+                    return Ok(toc);
+                }
+            }
+        }
+
+        let ncx_id = self.ncx_id.clone().or_else(|| {
+            self.resources.iter().find_map(|resource| {
+                resource
+                    .iter()
+                    .find(|(_, (_, mime_type))| mime_type == "application/x-dtbncx+xml")
+                    .map(|(id, _)| id.clone())
+            })
+        });
+
+        match ncx_id.and_then(|id| self.read_toc_document(&id)) {
+            Some((ncx, doc_dir)) => parse_ncx(&ncx, &doc_dir, &spine),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn get_metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn spine_len(&self) -> usize {
+        self.spine.len()
+    }
+
+    // The unmodified xhtml of a spine document
+    pub fn get_spine_document(&mut self, index: usize) -> Option<String> {
+        let id = self.spine.get(index)?;
+        let resource = self.get_res(id.clone())?;
+        let (path, _) = resource.get(id)?;
+        let file = self.get_res_by_path(path)?;
+
+        Some(String::from_utf8_lossy(&file).into_owned())
+    }
+
+    // The spine id in the cfi is trusted over the index, since the spine can change between versions of a book
+    pub fn resolve_cfi(&mut self, cfi: &Cfi) -> Result<ResolvedCfi, CfiError> {
+        let spine_index = cfi
+            .spine_id
+            .as_ref()
+            .and_then(|spine_id| self.spine.iter().position(|id| id == spine_id))
+            .unwrap_or(cfi.spine_index);
+
+        let document = self
+            .get_spine_document(spine_index)
+            .ok_or(CfiError::SpineIndexOutOfRange)?;
+
+        let mut resolved = cfi::resolve(cfi, &document)?;
+        resolved.spine_index = spine_index;
+
+        Ok(resolved)
+    }
+
+    // See cfi::generate for what element_path and text mean
+    pub fn generate_cfi(
+        &mut self,
+        spine_index: usize,
+        element_path: &[usize],
+        text: Option<(usize, usize)>,
+    ) -> Result<Cfi, CfiError> {
+        let document = self
+            .get_spine_document(spine_index)
+            .ok_or(CfiError::SpineIndexOutOfRange)?;
+        let spine_id = self.spine.get(spine_index).cloned();
+
+        cfi::generate(spine_index, spine_id, &document, element_path, text)
     }
 }
 
@@ -155,18 +295,16 @@ fn add_base(buff: Vec<u8>, base_url: &str) -> Result<Vec<u8>, EpubError> {
     loop {
         match reader.read_event() {
             Ok(Event::Start(e)) if e.name().as_ref() == b"head" => {
-                assert!(writer.write_event(Event::Start(e)).is_ok());
+                writer.write_event(Event::Start(e))?;
 
                 let mut base_el = BytesStart::new("base");
                 base_el.push_attribute(("href", base_url));
 
-                assert!(writer.write_event(Event::Start(base_el)).is_ok());
+                writer.write_event(Event::Start(base_el))?;
             }
             Ok(Event::Eof) => break,
-            Ok(e) => {
-                assert!(writer.write_event(e).is_ok())
-            }
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+            Ok(e) => writer.write_event(e)?,
+            Err(e) => return Err(EpubError::from(e)),
         }
     }
     Ok(writer.into_inner().into_inner())
@@ -208,41 +346,26 @@ fn read_spine(
     Ok(())
 }
 
-fn read_manifest(
+fn read_container(
     reader: &mut Reader<BufReader<ZipFile<'_>>>,
-    resources: &mut Vec<Resource>,
-) -> Result<(), EpubError> {
+) -> Result<Option<String>, EpubError> {
     let mut buff = Vec::new();
 
     loop {
         match reader.read_event_into(&mut buff) {
-            Ok(Event::Empty(ref e)) => {
-                if b"item" != e.name().as_ref() {
+            Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e)) => {
+                if b"rootfile" != e.name().as_ref() {
                     continue;
                 }
 
-                let mut id = String::new();
-                let mut href = PathBuf::new();
-                let mut media_type = String::new();
+                let full_path = e
+                    .attributes()
+                    .filter_map(|a| a.ok())
+                    .find(|a| a.key.as_ref() == b"full-path");
 
-                for attr in e.attributes().filter_map(|a| a.ok()) {
-                    match attr.key.as_ref() {
-                        b"href" => href.push(attr.decode_and_unescape_value(&reader)?.as_ref()),
-                        b"media-type" => {
-                            media_type.push_str(attr.decode_and_unescape_value(&reader)?.as_ref())
-                        }
-                        b"id" => id.push_str(attr.decode_and_unescape_value(&reader)?.as_ref()),
-                        _ => {}
-                    }
-                }
-
-                let mut resource = Resource::new();
-                resource.insert(id, (href, media_type));
-                resources.push(resource);
-            }
-            Ok(Event::End(ref e)) => {
-                if let b"manifest" = e.name().as_ref() {
-                    break;
+                if let Some(full_path) = full_path {
+                    let full_path = full_path.decode_and_unescape_value(reader)?.to_string();
+                    return Ok(Some(full_path));
                 }
             }
             Ok(Event::Eof) => break,
@@ -253,74 +376,104 @@ fn read_manifest(
         }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+// Hrefs are URLs, so characters like spaces arrive percent encoded
+fn percent_decode(href: &str) -> String {
+    let bytes = href.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Joins a href onto the package directory and resolves any "." and ".." components,
+// zip archives have no notion of relative paths so this has to be done by hand
+pub(crate) fn resolve_href(root_dir: &Path, href: &str) -> PathBuf {
+    let mut resolved = PathBuf::new();
+
+    for component in root_dir.join(percent_decode(href)).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(part) => resolved.push(part),
+            _ => (),
+        }
+    }
+
+    resolved
 }
 
-fn read_metadata(
+fn read_manifest(
     reader: &mut Reader<BufReader<ZipFile<'_>>>,
-    metadata: &mut HashMap<String, String>,
-    cover_id: &mut Option<String>,
-) -> Result<(), EpubError> {
+    resources: &mut Vec<Resource>,
+    root_dir: &Path,
+) -> Result<Option<String>, EpubError> {
     let mut buff = Vec::new();
+    let mut nav_id = None;
 
     loop {
         match reader.read_event_into(&mut buff) {
-            // Get the cover_id
-            Ok(Event::Empty(ref e)) => match e.name().as_ref() {
-                b"meta" => {
-                    let mut name = String::new();
-                    let mut content = String::new();
-
-                    for attr in e.attributes().filter_map(|a| a.ok()) {
-                        match attr.key.as_ref() {
-                            b"name" => {
-                                name.push_str(attr.decode_and_unescape_value(&reader)?.as_ref())
-                            }
-                            b"content" => {
-                                content.push_str(attr.decode_and_unescape_value(&reader)?.as_ref())
-                            }
-                            _ => {}
+            Ok(Event::Empty(ref e)) => {
+                if b"item" != e.name().as_ref() {
+                    continue;
+                }
+
+                let mut id = String::new();
+                let mut href = PathBuf::new();
+                let mut media_type = String::new();
+                let mut is_nav = false;
+
+                for attr in e.attributes().filter_map(|a| a.ok()) {
+                    match attr.key.as_ref() {
+                        b"href" => {
+                            href = resolve_href(root_dir, &attr.decode_and_unescape_value(&reader)?)
                         }
-                    }
-                    if name == "cover" {
-                        *cover_id = Some(content);
+                        b"media-type" => {
+                            media_type.push_str(attr.decode_and_unescape_value(&reader)?.as_ref())
+                        }
+                        b"id" => id.push_str(attr.decode_and_unescape_value(&reader)?.as_ref()),
+                        b"properties" => {
+                            is_nav = attr
+                                .decode_and_unescape_value(reader)?
+                                .split_whitespace()
+                                .any(|property| property == "nav")
+                        }
+                        _ => {}
                     }
                 }
-                // e if e.starts_with("dc:".as_bytes()) => {
-                //     let name = std::str::from_utf8(&e[2..])
-                //         .map_err(|_| EpubError::Convertion)?
-                //         .to_string();
-                //     let mut data = String::new();
-
-                //     match reader.read_event_into(&mut buff) {
-                //         Ok(Event::Text(e)) => data.push_str(e.unescape()?.into_owned().as_ref()),
-                //         _ => continue,
-                //     }
-
-                //     metadata.insert(name, data);
-                // }
-                _ => continue,
-            },
-            Ok(Event::Start(ref e)) => match e.name().as_ref() {
-                e if e.starts_with("dc:".as_bytes()) => {
-                    let name = std::str::from_utf8(&e[3..])
-                        .map_err(|_| EpubError::Convertion)?
-                        .to_string();
-                    let mut data = String::new();
-
-                    match reader.read_event_into(&mut buff) {
-                        Ok(Event::Text(e)) => data.push_str(e.unescape()?.into_owned().as_ref()),
-                        _ => continue,
-                    }
 
-                    metadata.insert(name, data);
+                if is_nav {
+                    nav_id = Some(id.clone());
                 }
 
-                _ => continue,
-            },
-
+                let mut resource = Resource::new();
+                resource.insert(id, (href, media_type));
+                resources.push(resource);
+            }
             Ok(Event::End(ref e)) => {
-                if let b"metadata" = e.name().as_ref() {
+                if let b"manifest" = e.name().as_ref() {
                     break;
                 }
             }
@@ -332,7 +485,7 @@ fn read_metadata(
         }
     }
 
-    Ok(())
+    Ok(nav_id)
 }
 
 #[derive(Debug)]
@@ -341,6 +494,7 @@ pub enum EpubError {
     Zip(zip::result::ZipError),
     Xml(quick_xml::Error),
     Convertion,
+    MissingPackage,
 }
 
 impl From<std::io::Error> for EpubError {
@@ -357,7 +511,7 @@ impl From<zip::result::ZipError> for EpubError {
 
 impl From<quick_xml::Error> for EpubError {
     fn from(error: quick_xml::Error) -> Self {
-        Self::Zip(zip::result::ZipError::FileNotFound)
+        Self::Xml(error)
     }
 }
 
@@ -368,6 +522,7 @@ impl Display for EpubError {
             EpubError::Zip(e) => write!(f, "{}", e),
             EpubError::Xml(e) => write!(f, "{}", e),
             EpubError::Convertion => write!(f, "Could not convert to utf8"),
+            EpubError::MissingPackage => write!(f, "Could not find the package document"),
         }
     }
 }
@@ -375,6 +530,182 @@ impl Display for EpubError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_epub::{temp_path, write_test_epub};
     use std::path::Path;
 
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+    <rootfiles>
+        <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+    </rootfiles>
+</container>"#;
+
+    const PACKAGE: &str = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title>Nested</dc:title>
+        <meta name="cover" content="cover-image"/>
+    </metadata>
+    <manifest>
+        <item id="chapter1" href="Text/chapter1.xhtml" media-type="application/xhtml+xml"/>
+        <item id="style" href="Styles/../Styles/style.css" media-type="text/css"/>
+        <item id="cover-image" href="Images/cover.jpg" media-type="image/jpeg"/>
+        <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    </manifest>
+    <spine toc="ncx">
+        <itemref idref="chapter1"/>
+    </spine>
+</package>"#;
+
+    const NCX: &str = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <navMap>
+        <navPoint id="p1" playOrder="1">
+            <navLabel><text>Chapter 1</text></navLabel>
+            <content src="Text/chapter1.xhtml#start"/>
+        </navPoint>
+    </navMap>
+</ncx>"#;
+
+    const CHAPTER: &str =
+        r#"<html><head><title>One</title></head><body><p>Hello</p></body></html>"#;
+
+    fn nested_epub(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        write_test_epub(
+            &path,
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", PACKAGE),
+                ("OEBPS/Text/chapter1.xhtml", CHAPTER),
+                ("OEBPS/Styles/style.css", "p { -webkit-hyphens: auto; }"),
+                ("OEBPS/Images/cover.jpg", "jpeg"),
+                ("OEBPS/toc.ncx", NCX),
+            ],
+        );
+        path
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        let root = Path::new("OEBPS");
+        assert_eq!(
+            resolve_href(root, "Text/chapter1.xhtml"),
+            PathBuf::from("OEBPS/Text/chapter1.xhtml")
+        );
+        assert_eq!(
+            resolve_href(root, "../Images/cover.jpg"),
+            PathBuf::from("Images/cover.jpg")
+        );
+        assert_eq!(
+            resolve_href(Path::new(""), "./chapter1.xhtml"),
+            PathBuf::from("chapter1.xhtml")
+        );
+        assert_eq!(
+            resolve_href(root, "Text/chapter%201.xhtml"),
+            PathBuf::from("OEBPS/Text/chapter 1.xhtml")
+        );
+    }
+
+    #[test]
+    fn test_nested_package() {
+        let path = nested_epub("nested-package");
+        let mut epub = Epub::new(&path).unwrap();
+
+        assert_eq!(epub.get_metadata().title.as_deref(), Some("Nested"));
+
+        let (cover, mime_type) = epub.get_cover().unwrap();
+        assert_eq!(cover, b"jpeg");
+        assert_eq!(mime_type, "image/jpeg");
+
+        let (page, _) = epub
+            .get_page(0, "https://example.com/api/v1/book/asset/resource")
+            .unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page
+            .contains("https://example.com/api/v1/book/asset/resource/OEBPS/Text/chapter1.xhtml"));
+
+        let style = epub
+            .get_res_by_path(&PathBuf::from("OEBPS/Styles/style.css"))
+            .unwrap();
+        assert_eq!(style, b"p { hyphens: auto; }");
+
+        let toc = epub.get_toc().unwrap();
+        assert_eq!(toc.len(), 1);
+        assert_eq!(toc[0].label, "Chapter 1");
+        assert_eq!(toc[0].spine_index, Some(0));
+        assert_eq!(toc[0].fragment, Some("start".to_string()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_toc_with_missing_itemref() {
+        let path = temp_path("missing-itemref");
+        let package = PACKAGE.replace(
+            r#"<itemref idref="chapter1"/>"#,
+            r#"<itemref idref="missing"/><itemref idref="chapter1"/>"#,
+        );
+        write_test_epub(
+            &path,
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", &package),
+                ("OEBPS/Text/chapter1.xhtml", CHAPTER),
+                ("OEBPS/toc.ncx", NCX),
+            ],
+        );
+        let mut epub = Epub::new(&path).unwrap();
+
+        let toc = epub.get_toc().unwrap();
+        assert_eq!(toc[0].spine_index, Some(1));
+        assert!(epub.get_spine_document(1).unwrap().contains("Hello"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cfi() {
+        let path = nested_epub("cfi");
+        let mut epub = Epub::new(&path).unwrap();
+
+        let cfi = epub.generate_cfi(0, &[1, 0], Some((0, 2))).unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/2[chapter1]!/4/2/1:2)");
+
+        let resolved = epub.resolve_cfi(&cfi).unwrap();
+        assert_eq!(resolved.spine_index, 0);
+        assert_eq!(resolved.start.element_path, vec![1, 0]);
+
+        // The spine id points at the right document even when the index is wrong
+        let moved: Cfi = "epubcfi(/6/8[chapter1]!/4/2)".parse().unwrap();
+        assert_eq!(epub.resolve_cfi(&moved).unwrap().spine_index, 0);
+
+        let outside: Cfi = "epubcfi(/6/8!/4/2)".parse().unwrap();
+        assert_eq!(
+            epub.resolve_cfi(&outside),
+            Err(CfiError::SpineIndexOutOfRange)
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_package_without_container() {
+        let path = temp_path("no-container");
+        write_test_epub(
+            &path,
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("content.opf", &PACKAGE.replace("Text/", "")),
+                ("chapter1.xhtml", CHAPTER),
+            ],
+        );
+        let mut epub = Epub::new(&path).unwrap();
+
+        assert!(epub.get_page(0, "/resource").is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
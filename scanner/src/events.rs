@@ -0,0 +1,115 @@
+use serde::Serialize;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+// Subscribers that fall this far behind miss events, and have to fetch what they need again
+const BUS_CAPACITY: usize = 256;
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    BookAdded {
+        library_id: i32,
+        book_id: i32,
+    },
+    BookUpdated {
+        library_id: i32,
+        book_id: i32,
+    },
+    BookRemoved {
+        library_id: i32,
+        book_id: i32,
+    },
+    ScanStarted {
+        library_id: i32,
+        job_id: i32,
+    },
+    ScanProgress {
+        library_id: i32,
+        job_id: i32,
+        discovered: i64,
+        imported: i64,
+        skipped: i64,
+        failed: i64,
+        removed: i64,
+    },
+    ScanFinished {
+        library_id: i32,
+        job_id: i32,
+        // Either Finished or Failed
+        status: String,
+    },
+    ProgressSynced {
+        user_id: i32,
+        book_id: i32,
+    },
+}
+
+impl Event {
+    // Reading progress is private, everything else is shared by the users that can see the library
+    pub fn visible_to(&self, user_id: i32) -> bool {
+        match self {
+            Event::ProgressSynced {
+                user_id: owner_id, ..
+            } => *owner_id == user_id,
+            _ => true,
+        }
+    }
+
+    pub fn library_id(&self) -> Option<i32> {
+        match self {
+            Event::BookAdded { library_id, .. }
+            | Event::BookUpdated { library_id, .. }
+            | Event::BookRemoved { library_id, .. }
+            | Event::ScanStarted { library_id, .. }
+            | Event::ScanProgress { library_id, .. }
+            | Event::ScanFinished { library_id, .. } => Some(*library_id),
+            Event::ProgressSynced { .. } => None,
+        }
+    }
+}
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+pub fn publish(event: Event) {
+    // Sending only fails when nobody is listening, which is fine
+    let _ = bus().send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let mut receiver = subscribe();
+        let event = Event::ProgressSynced {
+            user_id: 4,
+            book_id: 2,
+        };
+        publish(event.clone());
+
+        // Other tests publish on the same bus
+        loop {
+            if receiver.recv().await.unwrap() == event {
+                break;
+            }
+        }
+
+        assert!(event.visible_to(4));
+        assert!(!event.visible_to(5));
+        assert_eq!(event.library_id(), None);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"progress_synced","user_id":4,"book_id":2}"#
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use crate::epub_sandbox::{Epub, EpubError};
+use crate::metadata::Metadata;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PATTERN: &str = "{author}/{series}/{title}.epub";
+
+const UNKNOWN_AUTHOR: &str = "Unknown Author";
+// Keeps the generated names well below the file name limit of most file systems
+const MAX_SEGMENT_LENGTH: usize = 120;
+
+#[derive(Debug)]
+pub enum IngestError {
+    Io(std::io::Error),
+    InvalidEpub(String),
+    // The source is the destination, or one is inside the other
+    OverlappingFolders,
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Io(error) => write!(f, "Io error: {}", error),
+            IngestError::InvalidEpub(error) => write!(f, "Not a valid epub: {}", error),
+            IngestError::OverlappingFolders => {
+                write!(f, "The source and destination folders overlap")
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for IngestError {
+    fn from(error: std::io::Error) -> Self {
+        IngestError::Io(error)
+    }
+}
+
+impl From<EpubError> for IngestError {
+    fn from(error: EpubError) -> Self {
+        IngestError::InvalidEpub(error.to_string())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    // Where each imported book ended up
+    pub imported: Vec<PathBuf>,
+    // The files that were moved to quarantine, with their quarantine path and the reason
+    pub quarantined: Vec<(PathBuf, IngestError)>,
+    // The files that couldn't be moved anywhere and are still in the source folder
+    pub failed: Vec<(PathBuf, IngestError)>,
+}
+
+// Removes characters that are not allowed in file names on the common file systems
+fn sanitize(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_SEGMENT_LENGTH)
+        .collect();
+
+    // A leading dot would hide the file, and Windows doesn't allow trailing dots
+    sanitized.trim().trim_matches('.').trim().to_string()
+}
+
+fn author(metadata: &Metadata) -> Option<&str> {
+    metadata
+        .authors
+        .iter()
+        .find(|author| author.role.is_none() || author.role.as_deref() == Some("aut"))
+        .or(metadata.authors.first())
+        .map(|author| author.name.as_str())
+}
+
+// Builds the relative path of a book from the pattern, segments that end up empty are left out,
+// so a book without a series is stored directly in the author folder.
+pub fn render_pattern(pattern: &str, metadata: &Metadata, fallback_title: &str) -> PathBuf {
+    let title = metadata
+        .title
+        .as_deref()
+        .map(sanitize)
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| sanitize(fallback_title));
+    let author = author(metadata)
+        .map(sanitize)
+        .filter(|author| !author.is_empty())
+        .unwrap_or_else(|| UNKNOWN_AUTHOR.to_string());
+    let series = metadata
+        .series
+        .as_ref()
+        .map(|series| sanitize(&series.name))
+        .unwrap_or_default();
+
+    let mut path = PathBuf::new();
+    let segments: Vec<&str> = pattern.split('/').collect();
+    let (file_name, folders) = segments.split_last().unwrap_or((&"", &[]));
+
+    let render = |segment: &str| {
+        segment
+            .replace("{author}", &author)
+            .replace("{series}", &series)
+            .replace("{title}", &title)
+            .trim()
+            .to_string()
+    };
+
+    for folder in folders {
+        let folder = render(folder);
+        if !folder.is_empty() && folder != "." && folder != ".." {
+            path.push(folder);
+        }
+    }
+
+    let mut file_name = render(file_name);
+    let file_name_stem = file_name.trim_end_matches(".epub").trim();
+    if file_name_stem.is_empty() || file_name_stem == "." {
+        file_name = title.clone();
+    }
+    if !file_name.ends_with(".epub") {
+        file_name.push_str(".epub");
+    }
+    path.push(file_name);
+
+    path
+}
+
+// Finds a path that isn't taken yet by adding a number to the file name
+fn free_path(path: PathBuf) -> PathBuf {
+    if !path.exists() {
+        return path;
+    }
+
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (2..)
+        .map(|number| path.with_file_name(format!("{} ({}){}", stem, number, extension)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+// Renaming doesn't work across file systems, so fall back to copying the file
+fn move_file(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+fn validate(file: &Path) -> Result<Metadata, IngestError> {
+    let is_epub = file
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("epub"))
+        .unwrap_or(false);
+    if !is_epub {
+        return Err(IngestError::InvalidEpub(
+            "File does not have the epub extension".to_string(),
+        ));
+    }
+
+    let epub = Epub::new(&file.to_path_buf())?;
+    Ok(epub.get_metadata().clone())
+}
+
+// Moves a single file into the destination if it is a valid epub, and returns the new path
+pub fn ingest_file(file: &Path, destination: &Path, pattern: &str) -> Result<PathBuf, IngestError> {
+    let metadata = validate(file)?;
+    let fallback_title = file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let target = free_path(destination.join(render_pattern(pattern, &metadata, &fallback_title)));
+    move_file(file, &target)?;
+
+    Ok(target)
+}
+
+// True when the folders are the same or one is inside the other. Books moved from one into the
+// other would be picked up again, so a source folder can't overlap a destination or a library.
+pub fn paths_overlap(first: &Path, second: &Path) -> bool {
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (first, second) = (canonical(first), canonical(second));
+
+    first.starts_with(&second) || second.starts_with(&first)
+}
+
+fn dropped_files(folder: &Path, files: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+    for entry in fs::read_dir(folder)?.filter_map(|entry_res| entry_res.ok()) {
+        // Hidden files are usually downloads or copies that are still in progress
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if path.is_dir() {
+            dropped_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Moves every file in the source folder into the destination, or into quarantine if it can't be imported
+pub fn ingest_folder(
+    source: &Path,
+    destination: &Path,
+    quarantine: &Path,
+    pattern: &str,
+) -> Result<IngestReport, IngestError> {
+    if paths_overlap(source, destination) {
+        return Err(IngestError::OverlappingFolders);
+    }
+
+    let mut files = Vec::new();
+    dropped_files(source, &mut files)?;
+
+    let mut report = IngestReport::default();
+
+    for file in files {
+        match ingest_file(&file, destination, pattern) {
+            Ok(target) => report.imported.push(target),
+            Err(error) => {
+                // One file that can't be moved shouldn't keep the rest of the folder from being imported
+                let file_name = file.file_name().unwrap_or_default();
+                let target = free_path(quarantine.join(file_name));
+                match move_file(&file, &target) {
+                    Ok(()) => report.quarantined.push((target, error)),
+                    Err(move_error) => report.failed.push((file, IngestError::Io(move_error))),
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{Contributor, Series};
+    use crate::test_epub::{temp_path, write_simple_epub, write_test_epub};
+
+    fn metadata(title: Option<&str>, author: Option<&str>, series: Option<&str>) -> Metadata {
+        Metadata {
+            title: title.map(String::from),
+            authors: author
+                .map(|name| Contributor {
+                    name: name.to_string(),
+                    role: None,
+                    file_as: None,
+                })
+                .into_iter()
+                .collect(),
+            series: series.map(|name| Series {
+                name: name.to_string(),
+                position: None,
+            }),
+            ..Metadata::default()
+        }
+    }
+
+    #[test]
+    fn test_render_pattern() {
+        let full = metadata(Some("The Title"), Some("Some Author"), Some("A Series"));
+        assert_eq!(
+            render_pattern(DEFAULT_PATTERN, &full, "file"),
+            PathBuf::from("Some Author/A Series/The Title.epub")
+        );
+
+        let no_series = metadata(Some("What? A/B"), None, None);
+        assert_eq!(
+            render_pattern(DEFAULT_PATTERN, &no_series, "file"),
+            PathBuf::from("Unknown Author/What_ A_B.epub")
+        );
+
+        let no_title = metadata(None, Some("Author"), None);
+        assert_eq!(
+            render_pattern("{author} - {title}", &no_title, "file"),
+            PathBuf::from("Author - file.epub")
+        );
+
+        assert_eq!(
+            render_pattern("../{series}/{series}.epub", &no_title, "file"),
+            PathBuf::from("file.epub")
+        );
+    }
+
+    #[test]
+    fn test_ingest_folder() {
+        let root = temp_path("ingest-folder");
+        let _ = fs::remove_dir_all(&root);
+        let source = root.join("source");
+        let destination = root.join("destination");
+        let quarantine = root.join("quarantine");
+        fs::create_dir_all(source.join("nested")).unwrap();
+
+        write_simple_epub(&source.join("first.epub"), "First", "<p>First</p>");
+        write_simple_epub(&source.join("nested/second.epub"), "First", "<p>Again</p>");
+        fs::write(source.join("broken.epub"), "not a zip").unwrap();
+        fs::write(source.join(".partial.epub"), "still downloading").unwrap();
+
+        let report = ingest_folder(&source, &destination, &quarantine, DEFAULT_PATTERN).unwrap();
+
+        let mut imported = report.imported.clone();
+        imported.sort();
+        assert_eq!(
+            imported,
+            vec![
+                destination.join("Test Author/First (2).epub"),
+                destination.join("Test Author/First.epub"),
+            ]
+        );
+        assert_eq!(report.quarantined.len(), 1);
+        assert_eq!(report.quarantined[0].0, quarantine.join("broken.epub"));
+        assert!(quarantine.join("broken.epub").exists());
+        assert!(!source.join("first.epub").exists());
+        assert!(source.join(".partial.epub").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ingest_folder_failed_quarantine() {
+        let root = temp_path("ingest-failed-quarantine");
+        let _ = fs::remove_dir_all(&root);
+        let source = root.join("source");
+        let destination = root.join("destination");
+        // A file where the quarantine folder should be, so nothing can be moved into it
+        let quarantine = root.join("quarantine");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(&quarantine, "").unwrap();
+
+        write_simple_epub(&source.join("good.epub"), "Good", "<p>Good</p>");
+        fs::write(source.join("broken.epub"), "not a zip").unwrap();
+        write_test_epub(
+            &source.join("malformed.epub"),
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("content.opf", "<package></manifest></package>"),
+            ],
+        );
+
+        let report = ingest_folder(&source, &destination, &quarantine, DEFAULT_PATTERN).unwrap();
+
+        assert_eq!(
+            report.imported,
+            vec![destination.join("Test Author/Good.epub")]
+        );
+        assert!(report.quarantined.is_empty());
+        let mut failed: Vec<&PathBuf> = report.failed.iter().map(|(path, _)| path).collect();
+        failed.sort();
+        assert_eq!(
+            failed,
+            vec![&source.join("broken.epub"), &source.join("malformed.epub")]
+        );
+        assert!(source.join("malformed.epub").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_overlapping_folders() {
+        let root = temp_path("ingest-overlap");
+        let _ = fs::remove_dir_all(&root);
+        let library = root.join("library");
+        let inbox = library.join("inbox");
+        let other = root.join("other");
+        fs::create_dir_all(&inbox).unwrap();
+        fs::create_dir_all(&other).unwrap();
+
+        assert!(paths_overlap(&library, &library));
+        assert!(paths_overlap(&inbox, &library));
+        assert!(paths_overlap(&library, &inbox));
+        assert!(paths_overlap(&library.join("inbox/../"), &inbox));
+        assert!(!paths_overlap(&other, &library));
+        // A shared prefix in the name doesn't make it the same folder
+        assert!(!paths_overlap(&root.join("lib"), &library));
+
+        write_simple_epub(&library.join("book.epub"), "Book", "<p>Book</p>");
+        let result = ingest_folder(&library, &library, &other, DEFAULT_PATTERN);
+        assert!(matches!(result, Err(IngestError::OverlappingFolders)));
+        assert!(library.join("book.epub").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
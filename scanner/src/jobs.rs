@@ -0,0 +1,443 @@
+use crate::events::{publish, Event};
+use crate::scanner::{incremental_scan_with_progress, ScanError, ScanReport};
+use database::library::Library;
+use database::scan_jobs::{ScanCounts, ScanJob, ScanStatus};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex, Semaphore};
+use tracing::{error, info, warn};
+
+// Runs the scans for the libraries of one server, or one command
+#[derive(Clone)]
+pub struct ScanJobs {
+    // Libraries that have a job running right now
+    running: Arc<Mutex<HashSet<i32>>>,
+    // Limits how many libraries are scanned at the same time, scans reading from the same disk mostly slow each other down
+    permits: Arc<Semaphore>,
+}
+
+fn scan_counts(report: &ScanReport) -> ScanCounts {
+    ScanCounts {
+        discovered: report.discovered as i64,
+        imported: (report.added + report.updated + report.moved) as i64,
+        skipped: report.unchanged as i64,
+        failed: report.failed.len() as i64,
+        removed: report.removed as i64,
+    }
+}
+
+impl ScanJobs {
+    pub fn new(concurrency: usize) -> Self {
+        ScanJobs {
+            running: Default::default(),
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+        }
+    }
+
+    // Starts scanning the library in the background, or queues a scan if one is already running.
+    // A single scan picks up every change, so a library never has more than one job waiting.
+    pub async fn start_scan(
+        &self,
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<ScanJob, ScanError> {
+        let mut running = self.running.lock().await;
+
+        if running.contains(&library_id) {
+            if let Some(job) = ScanJob::get_queued(library_id, pool).await? {
+                return Ok(job);
+            }
+            return Ok(ScanJob::insert(library_id, ScanStatus::Queued, pool).await?);
+        }
+
+        let job = ScanJob::insert(library_id, ScanStatus::Running, pool).await?;
+        running.insert(library_id);
+
+        let jobs = self.clone();
+        tokio::spawn(
+            self.clone()
+                .run_jobs(job.clone(), pool.clone(), move |job, pool| {
+                    jobs.clone().run_job(job, pool)
+                }),
+        );
+
+        Ok(job)
+    }
+
+    // Scans the library and waits for it to finish, for use outside the server.
+    // The job is recorded like any other, but the library can't be busy, not even in another process.
+    pub async fn run_scan(
+        &self,
+        library_id: i32,
+        pool: &Pool<Sqlite>,
+    ) -> Result<ScanJob, ScanError> {
+        let job = {
+            let mut running = self.running.lock().await;
+
+            if let Some(job) = ScanJob::get_active(library_id, pool).await? {
+                return Err(ScanError::AlreadyRunning(job.id));
+            }
+
+            let job = ScanJob::insert(library_id, ScanStatus::Running, pool).await?;
+            running.insert(library_id);
+            job
+        };
+
+        let jobs = self.clone();
+        self.clone()
+            .run_jobs(job.clone(), pool.clone(), move |job, pool| {
+                jobs.clone().run_job(job, pool)
+            })
+            .await;
+
+        Ok(ScanJob::get_job(job.id, pool).await?)
+    }
+
+    // Runs the job and then the jobs queued for the same library, each one with run
+    async fn run_jobs<F, Fut>(self, mut job: ScanJob, pool: Pool<Sqlite>, run: F)
+    where
+        F: Fn(ScanJob, Pool<Sqlite>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let library_id = job.library_id;
+
+        loop {
+            // A scan that panics only takes down its own task, the library has to be released either way
+            if let Err(panic) = tokio::spawn(run(job.clone(), pool.clone())).await {
+                error!("Scan job {} stopped unexpectedly: {}", job.id, panic);
+                let _ = job
+                    .fail(format!("The scan stopped unexpectedly: {}", panic), &pool)
+                    .await;
+                publish(Event::ScanFinished {
+                    library_id,
+                    job_id: job.id,
+                    status: format!("{:?}", job.status),
+                });
+            }
+
+            // Holding the lock makes sure a job queued meanwhile is either picked up here or started by start_scan
+            let mut running = self.running.lock().await;
+            match ScanJob::get_queued(library_id, &pool).await {
+                Ok(Some(next)) => {
+                    job = next;
+                    if let Err(error) = job.start(&pool).await {
+                        error!("Could not start scan job {}: {}", job.id, error);
+                        running.remove(&library_id);
+                        return;
+                    }
+                }
+                _ => {
+                    running.remove(&library_id);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn run_job(self, mut job: ScanJob, pool: Pool<Sqlite>) {
+        // The job shows as running while it waits for its turn, the semaphore is never closed
+        let _permit = self.permits.acquire().await;
+
+        publish(Event::ScanStarted {
+            library_id: job.library_id,
+            job_id: job.id,
+        });
+
+        scan(&mut job, &pool).await;
+
+        publish(Event::ScanFinished {
+            library_id: job.library_id,
+            job_id: job.id,
+            status: format!("{:?}", job.status),
+        });
+    }
+}
+
+async fn scan(job: &mut ScanJob, pool: &Pool<Sqlite>) {
+    let library = match Library::get_library(job.library_id, pool).await {
+        Ok(library) => library,
+        Err(error) => {
+            error!("Could not find library {}: {}", job.library_id, error);
+            let _ = job.fail(error.to_string(), pool).await;
+            return;
+        }
+    };
+
+    info!("Scanning library {}", &library.path);
+
+    // The counts are written in the background, updates that come faster than the database keeps up are skipped
+    let (sender, mut receiver) = watch::channel(ScanCounts::default());
+    let mut progress_job = job.clone();
+    let progress_pool = pool.clone();
+    let writer = tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let counts = receiver.borrow_and_update().clone();
+            let _ = progress_job.update_counts(&counts, &progress_pool).await;
+            publish(Event::ScanProgress {
+                library_id: progress_job.library_id,
+                job_id: progress_job.id,
+                discovered: counts.discovered,
+                imported: counts.imported,
+                skipped: counts.skipped,
+                failed: counts.failed,
+                removed: counts.removed,
+            });
+        }
+    });
+
+    let result = incremental_scan_with_progress(&library.path, library.id, pool, &mut |report| {
+        let counts = scan_counts(report);
+        sender.send_if_modified(|current| {
+            if *current == counts {
+                return false;
+            }
+            *current = counts;
+            true
+        });
+    })
+    .await;
+
+    // Waits for the last progress update, so it can't overwrite the final counts
+    drop(sender);
+    let _ = writer.await;
+
+    let stored = match result {
+        Ok(report) => {
+            info!(
+                "Done scanning library {}: {} added, {} updated, {} moved, {} removed, {} unchanged",
+                &library.path,
+                report.added,
+                report.updated,
+                report.moved,
+                report.removed,
+                report.unchanged
+            );
+            for (path, error) in &report.failed {
+                warn!("Could not import {}: {}", path.display(), error);
+            }
+
+            let errors: Vec<(String, String)> = report
+                .failed
+                .iter()
+                .map(|(path, error)| (path.to_string_lossy().to_string(), error.to_string()))
+                .collect();
+            job.finish(&scan_counts(&report), &errors, pool).await
+        }
+        Err(error) => {
+            error!("Error scanning library {}: {}", &library.path, error);
+            job.fail(error.to_string(), pool).await
+        }
+    };
+
+    if let Err(error) = stored {
+        error!("Could not store scan job {}: {}", job.id, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_epub::{temp_path, write_simple_epub, write_test_epub};
+    use database::library::InsertableLibrary;
+    use sqlx::sqlite::SqlitePool;
+    use std::fs;
+    use std::time::Duration;
+
+    async fn wait_for(job_id: i32, pool: &Pool<Sqlite>) -> ScanJob {
+        for _ in 0..200 {
+            let job = ScanJob::get_job(job_id, pool).await.unwrap();
+            if job.status == ScanStatus::Finished || job.status == ScanStatus::Failed {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Scan job {} did not finish", job_id);
+    }
+
+    #[tokio::test]
+    async fn test_scan_job() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("scan-job");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+        write_simple_epub(&library_path.join("first.epub"), "First", "<p>First</p>");
+        write_simple_epub(&library_path.join("second.epub"), "Second", "<p>Second</p>");
+        fs::write(library_path.join("broken.epub"), "not a zip").unwrap();
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "Scan job".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let jobs = ScanJobs::new(2);
+
+        let job = jobs.start_scan(library.id, &pool).await.unwrap();
+        let job = wait_for(job.id, &pool).await;
+
+        assert_eq!(job.status, ScanStatus::Finished);
+        assert_eq!(job.discovered, 3);
+        assert_eq!(job.imported, 2);
+        assert_eq!(job.failed, 1);
+
+        let errors = job.get_errors(&pool).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].path.ends_with("broken.epub"));
+
+        let job = jobs.start_scan(library.id, &pool).await.unwrap();
+        let job = wait_for(job.id, &pool).await;
+        assert_eq!(job.skipped, 2);
+        assert_eq!(job.imported, 0);
+
+        fs::remove_dir_all(&library_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_job_malformed_package() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("scan-job-malformed");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+        write_test_epub(
+            &library_path.join("malformed.epub"),
+            &[
+                ("mimetype", "application/epub+zip"),
+                ("content.opf", "<package></manifest></package>"),
+            ],
+        );
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "Malformed".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let jobs = ScanJobs::new(2);
+
+        // The broken book fails on its own and the scan goes on
+        let job = jobs.start_scan(library.id, &pool).await.unwrap();
+        let job = wait_for(job.id, &pool).await;
+        assert_eq!(job.status, ScanStatus::Finished);
+        assert_eq!(job.failed, 1);
+
+        // A scan that panics fails its job and releases the library
+        let job = ScanJob::insert(library.id, ScanStatus::Running, &pool)
+            .await
+            .unwrap();
+        jobs.clone()
+            .run_jobs(job.clone(), pool.clone(), |_, _| async {
+                panic!("Malformed package")
+            })
+            .await;
+
+        let job = ScanJob::get_job(job.id, &pool).await.unwrap();
+        assert_eq!(job.status, ScanStatus::Failed);
+        assert!(job.error.unwrap().contains("stopped unexpectedly"));
+
+        // The library isn't left locked, so the next scan starts right away
+        let job = jobs.start_scan(library.id, &pool).await.unwrap();
+        assert_eq!(job.status, ScanStatus::Running);
+        assert_eq!(wait_for(job.id, &pool).await.status, ScanStatus::Finished);
+
+        fs::remove_dir_all(&library_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_job_queue() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("scan-job-queue");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "Queue".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let jobs = ScanJobs::new(1);
+
+        // Keeps the first scan waiting for its turn
+        let permit = jobs.permits.acquire().await.unwrap();
+
+        let running = jobs.start_scan(library.id, &pool).await.unwrap();
+        let first = jobs.start_scan(library.id, &pool).await.unwrap();
+        let second = jobs.start_scan(library.id, &pool).await.unwrap();
+
+        assert_eq!(running.status, ScanStatus::Running);
+        assert_eq!(first.status, ScanStatus::Queued);
+        assert_eq!(first.id, second.id);
+
+        // The queued scan runs once the first one is done
+        drop(permit);
+        assert_eq!(
+            wait_for(running.id, &pool).await.status,
+            ScanStatus::Finished
+        );
+        assert_eq!(wait_for(first.id, &pool).await.status, ScanStatus::Finished);
+
+        fs::remove_dir_all(&library_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_scan() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("run-scan");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+        write_simple_epub(&library_path.join("book.epub"), "Book", "<p>Book</p>");
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "Run".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let jobs = ScanJobs::new(1);
+
+        // A job left by the server has to finish first
+        let mut queued = ScanJob::insert(library.id, ScanStatus::Queued, &pool)
+            .await
+            .unwrap();
+        let result = jobs.run_scan(library.id, &pool).await;
+        assert!(matches!(result, Err(ScanError::AlreadyRunning(id)) if id == queued.id));
+        queued.fail("Interrupted".to_string(), &pool).await.unwrap();
+
+        let job = jobs.run_scan(library.id, &pool).await.unwrap();
+        assert_eq!(job.status, ScanStatus::Finished);
+        assert_eq!(job.imported, 1);
+        assert!(!jobs.running.lock().await.contains(&library.id));
+
+        fs::remove_dir_all(&library_path).unwrap();
+    }
+}
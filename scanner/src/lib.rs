@@ -8,8 +8,18 @@ use std::sync::OnceLock;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio::task::JoinHandle;
+pub mod cfi;
 pub mod epub_sandbox;
+pub mod events;
+pub mod ingest;
+pub mod jobs;
+pub mod metadata;
 pub mod scanner;
+pub mod search;
+pub mod toc;
+
+#[cfg(test)]
+mod test_epub;
 
 const FILE_EXTENSIONS: [&str; 1] = ["epub"];
 
@@ -22,8 +32,7 @@ const TEMPORARY_COVER_DIR: &str = "./covers";
 static COVER_PATH: OnceLock<String> = OnceLock::new();
 
 pub fn set_metadata_path(path: String) {
-    let path = COVER_PATH.get_or_init(|| path);
-    println!("Metadata path set to {}", path);
+    COVER_PATH.get_or_init(|| path);
 }
 
 struct BookCover {
@@ -0,0 +1,395 @@
+use crate::epub_sandbox::EpubError;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contributor {
+    pub name: String,
+    // MARC relator code, e.g. "aut" or "edt"
+    pub role: Option<String>,
+    // Sort key, e.g. "Tolkien, J. R. R."
+    pub file_as: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    pub position: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub authors: Vec<Contributor>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    pub identifiers: Vec<Identifier>,
+    pub series: Option<Series>,
+    // Manifest id of the cover image, from <meta name="cover">
+    pub cover_id: Option<String>,
+}
+
+impl Metadata {
+    pub fn isbn(&self) -> Option<&str> {
+        self.identifiers
+            .iter()
+            .find(|identifier| identifier.scheme.as_deref() == Some("isbn"))
+            .map(|identifier| identifier.value.as_str())
+    }
+}
+
+// A dc: element or a meta element, before EPUB3 refinements are applied
+#[derive(Debug, Default)]
+struct RawElement {
+    name: String,
+    id: Option<String>,
+    attributes: HashMap<String, String>,
+    text: String,
+}
+
+fn read_attributes(e: &BytesStart) -> HashMap<String, String> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .filter_map(|a| {
+            // opf:role and role mean the same thing, so the prefix is dropped
+            let key = std::str::from_utf8(a.key.local_name().as_ref())
+                .ok()?
+                .to_string();
+            let value = a.unescape_value().ok()?.to_string();
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn raw_element(e: &BytesStart) -> Result<RawElement, EpubError> {
+    let name = std::str::from_utf8(e.local_name().as_ref())
+        .map_err(|_| EpubError::Convertion)?
+        .to_string();
+    let attributes = read_attributes(e);
+    let id = attributes.get("id").cloned();
+
+    Ok(RawElement {
+        name,
+        id,
+        attributes,
+        text: String::new(),
+    })
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!value.is_empty()).then_some(value)
+}
+
+// Reads everything up to the closing metadata tag
+pub(crate) fn read_metadata<R: BufRead>(reader: &mut Reader<R>) -> Result<Metadata, EpubError> {
+    let mut buff = Vec::new();
+    let mut elements = Vec::new();
+    let mut current: Option<RawElement> = None;
+
+    loop {
+        match reader.read_event_into(&mut buff) {
+            Ok(Event::Empty(ref e)) => elements.push(raw_element(e)?),
+            Ok(Event::Start(ref e)) => current = Some(raw_element(e)?),
+            Ok(Event::Text(ref e)) => {
+                if let Some(element) = current.as_mut() {
+                    match e.unescape() {
+                        Ok(text) => element.text.push_str(&text),
+                        Err(_) => element.text.push_str(&String::from_utf8_lossy(e)),
+                    }
+                }
+            }
+            Ok(Event::CData(ref e)) => {
+                if let Some(element) = current.as_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(e));
+                }
+            }
+            Ok(Event::End(ref e)) => {
+                if e.local_name().as_ref() == b"metadata" {
+                    break;
+                }
+                if let Some(element) = current.take() {
+                    elements.push(element);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => Err(EpubError::from(e))?,
+            _ => (),
+        }
+        buff.clear();
+    }
+
+    Ok(build_metadata(elements))
+}
+
+fn build_metadata(elements: Vec<RawElement>) -> Metadata {
+    // EPUB3 attaches roles, sort keys and series positions through <meta refines="#id">
+    let mut refinements: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for element in elements.iter().filter(|element| element.name == "meta") {
+        let (Some(refines), Some(property)) = (
+            element.attributes.get("refines"),
+            element.attributes.get("property"),
+        ) else {
+            continue;
+        };
+        let Some(text) = non_empty(&element.text) else {
+            continue;
+        };
+
+        refinements
+            .entry(refines.trim_start_matches('#').to_string())
+            .or_default()
+            .entry(property.clone())
+            .or_insert(text);
+    }
+
+    let refinement = |element: &RawElement, property: &str| {
+        element
+            .id
+            .as_ref()
+            .and_then(|id| refinements.get(id))
+            .and_then(|properties| properties.get(property))
+            .cloned()
+    };
+
+    let mut metadata = Metadata::default();
+    let mut calibre_series = None;
+    let mut calibre_series_index = None;
+
+    for element in &elements {
+        match element.name.as_str() {
+            "title" if metadata.title.is_none() => {
+                metadata.title = non_empty(&element.text);
+            }
+            "creator" | "contributor" => {
+                let Some(name) = non_empty(&element.text) else {
+                    continue;
+                };
+                let role = element
+                    .attributes
+                    .get("role")
+                    .cloned()
+                    .or_else(|| refinement(element, "role"))
+                    // Creators without a role are assumed to be the authors
+                    .or_else(|| (element.name == "creator").then(|| "aut".to_string()));
+                let file_as = element
+                    .attributes
+                    .get("file-as")
+                    .cloned()
+                    .or_else(|| refinement(element, "file-as"));
+
+                metadata.authors.push(Contributor {
+                    name,
+                    role,
+                    file_as,
+                });
+            }
+            "language" if metadata.language.is_none() => {
+                metadata.language = non_empty(&element.text);
+            }
+            "publisher" if metadata.publisher.is_none() => {
+                metadata.publisher = non_empty(&element.text);
+            }
+            "date" => {
+                // EPUB2 allows several dates, only the publication date is of interest
+                let event = element.attributes.get("event").map(|e| e.as_str());
+                if metadata.published.is_none() && matches!(event, None | Some("publication")) {
+                    metadata.published = non_empty(&element.text);
+                }
+            }
+            "description" if metadata.description.is_none() => {
+                metadata.description = non_empty(&element.text);
+            }
+            "subject" => {
+                if let Some(subject) = non_empty(&element.text) {
+                    metadata.subjects.push(subject);
+                }
+            }
+            "identifier" => {
+                let Some(value) = non_empty(&element.text) else {
+                    continue;
+                };
+                let scheme = element
+                    .attributes
+                    .get("scheme")
+                    .cloned()
+                    .or_else(|| refinement(element, "identifier-type"));
+                metadata.identifiers.push(parse_identifier(scheme, value));
+            }
+            "meta" => {
+                let name = element.attributes.get("name").map(|n| n.as_str());
+                let content = element.attributes.get("content").cloned();
+                match name {
+                    Some("cover") => metadata.cover_id = content,
+                    Some("calibre:series") => calibre_series = content.and_then(|c| non_empty(&c)),
+                    Some("calibre:series_index") => {
+                        calibre_series_index = content.and_then(|c| c.trim().parse().ok())
+                    }
+                    _ => (),
+                }
+
+                let property = element.attributes.get("property").map(|p| p.as_str());
+                if property == Some("belongs-to-collection") && metadata.series.is_none() {
+                    let collection_type = refinement(element, "collection-type");
+                    // Collections without a type are most often series in practice
+                    if matches!(collection_type.as_deref(), None | Some("series")) {
+                        if let Some(name) = non_empty(&element.text) {
+                            metadata.series = Some(Series {
+                                name,
+                                position: refinement(element, "group-position")
+                                    .and_then(|position| position.trim().parse().ok()),
+                            });
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if metadata.series.is_none() {
+        metadata.series = calibre_series.map(|name| Series {
+            name,
+            position: calibre_series_index,
+        });
+    }
+
+    metadata
+}
+
+// Normalizes the scheme to lowercase and pulls it out of urn:scheme:value identifiers
+fn parse_identifier(scheme: Option<String>, value: String) -> Identifier {
+    let scheme = scheme.map(|scheme| scheme.to_lowercase());
+
+    if let Some(rest) = value.strip_prefix("urn:") {
+        if let Some((urn_scheme, urn_value)) = rest.split_once(':') {
+            let urn_scheme = urn_scheme.to_lowercase();
+            if scheme.is_none() || scheme.as_deref() == Some(urn_scheme.as_str()) {
+                return Identifier {
+                    scheme: Some(urn_scheme),
+                    value: urn_value.to_string(),
+                };
+            }
+        }
+    }
+
+    // Some books only mark their isbn by the shape of the value
+    if scheme.is_none() {
+        let digits: String = value.chars().filter(|c| *c != '-' && *c != ' ').collect();
+        let looks_like_isbn = (digits.len() == 13 && digits.starts_with("97"))
+            && digits.chars().all(|c| c.is_ascii_digit());
+        if looks_like_isbn {
+            return Identifier {
+                scheme: Some("isbn".to_string()),
+                value,
+            };
+        }
+    }
+
+    Identifier { scheme, value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(xml: &str) -> Metadata {
+        let mut reader = Reader::from_str(xml);
+        read_metadata(&mut reader).unwrap()
+    }
+
+    #[test]
+    fn test_epub2_metadata() {
+        let metadata = parse(
+            r#"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>The Hobbit</dc:title>
+    <dc:creator opf:role="aut" opf:file-as="Tolkien, J. R. R.">J. R. R. Tolkien</dc:creator>
+    <dc:contributor opf:role="ill">Alan Lee</dc:contributor>
+    <dc:language>en</dc:language>
+    <dc:publisher>Allen &amp; Unwin</dc:publisher>
+    <dc:date opf:event="modification">2020-01-01</dc:date>
+    <dc:date opf:event="publication">1937-09-21</dc:date>
+    <dc:description><![CDATA[<p>There and back again</p>]]></dc:description>
+    <dc:subject>Fantasy</dc:subject>
+    <dc:subject>Adventure</dc:subject>
+    <dc:identifier opf:scheme="ISBN">978-0-261-10221-7</dc:identifier>
+    <dc:identifier>urn:uuid:0a1b2c</dc:identifier>
+    <meta name="calibre:series" content="Middle-earth"/>
+    <meta name="calibre:series_index" content="1.0"/>
+    <meta name="cover" content="cover-image"/>
+</metadata>"#,
+        );
+
+        assert_eq!(metadata.title.as_deref(), Some("The Hobbit"));
+        assert_eq!(
+            metadata.authors[0],
+            Contributor {
+                name: "J. R. R. Tolkien".to_string(),
+                role: Some("aut".to_string()),
+                file_as: Some("Tolkien, J. R. R.".to_string()),
+            }
+        );
+        assert_eq!(metadata.authors[1].role.as_deref(), Some("ill"));
+        assert_eq!(metadata.language.as_deref(), Some("en"));
+        assert_eq!(metadata.publisher.as_deref(), Some("Allen & Unwin"));
+        assert_eq!(metadata.published.as_deref(), Some("1937-09-21"));
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("<p>There and back again</p>")
+        );
+        assert_eq!(metadata.subjects, vec!["Fantasy", "Adventure"]);
+        assert_eq!(metadata.isbn(), Some("978-0-261-10221-7"));
+        assert_eq!(metadata.identifiers[1].scheme.as_deref(), Some("uuid"));
+        assert_eq!(
+            metadata.series,
+            Some(Series {
+                name: "Middle-earth".to_string(),
+                position: Some(1.0),
+            })
+        );
+        assert_eq!(metadata.cover_id.as_deref(), Some("cover-image"));
+    }
+
+    #[test]
+    fn test_epub3_refinements() {
+        let metadata = parse(
+            r##"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title id="t1">Dune</dc:title>
+    <dc:creator id="c1">Frank Herbert</dc:creator>
+    <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#c1" property="file-as">Herbert, Frank</meta>
+    <dc:identifier id="i1">9780441013593</dc:identifier>
+    <meta property="belongs-to-collection" id="s1">Dune Chronicles</meta>
+    <meta refines="#s1" property="collection-type">series</meta>
+    <meta refines="#s1" property="group-position">1</meta>
+    <meta property="dcterms:modified">2020-01-01T00:00:00Z</meta>
+</metadata>"##,
+        );
+
+        assert_eq!(metadata.title.as_deref(), Some("Dune"));
+        assert_eq!(metadata.authors[0].role.as_deref(), Some("aut"));
+        assert_eq!(
+            metadata.authors[0].file_as.as_deref(),
+            Some("Herbert, Frank")
+        );
+        assert_eq!(metadata.isbn(), Some("9780441013593"));
+        assert_eq!(
+            metadata.series,
+            Some(Series {
+                name: "Dune Chronicles".to_string(),
+                position: Some(1.0),
+            })
+        );
+    }
+}
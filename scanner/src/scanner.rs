@@ -1,18 +1,30 @@
 use crate::epub_sandbox::Epub;
 use crate::epub_sandbox::EpubError;
+use crate::events::{publish, Event};
+use crate::metadata::Metadata;
+use crate::search::search_index;
 use database::assets::Asset;
+use database::assets::FileInfo;
 use database::assets::InsertableAsset;
 use database::library::Collection;
-use database::library::{Book, InsertableBook, InsertableCollection, Library};
+use database::library::{
+    Book, BookFile, InsertableAuthor, InsertableBook, InsertableBookMetadata, InsertableCollection,
+    InsertableIdentifier, InsertableSeries, Library,
+};
+use database::search::SearchResult;
 use futures::stream;
 use futures::StreamExt;
+use sha2::{Digest, Sha256};
 use sqlx::pool;
 use sqlx::sqlite::Sqlite;
 use sqlx::Pool;
+use std::collections::{HashMap, HashSet};
 use std::f32::consts::E;
 use std::fs;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
 use tokio::io::AsyncWriteExt;
 
 const MIME_TYPE_EXTENSIONS_MAP: [(&str, &str); 2] = [("image/jpeg", "jpg"), ("image/png", "png")];
@@ -21,10 +33,12 @@ const ALLOWED_COVER_MIME_TYPES: [&str; 2] = ["image/jpeg", "image/png"];
 static COVER_PATH: OnceLock<String> = OnceLock::new();
 
 pub fn set_metadata_path(path: String) {
-    let path = COVER_PATH.get_or_init(|| path);
-    println!("Metadata path set to {}", path);
+    COVER_PATH.get_or_init(|| path);
 }
 
+// The book itself, its cover if it has one and the metadata read from the package
+type ScannedBook = (InsertableBook, Option<InsertableAsset>, Metadata);
+
 #[derive(Debug)]
 struct CollectionDicovery {
     // The name of the containing folder
@@ -55,6 +69,19 @@ fn discover_books(folder_path: impl Into<PathBuf>) -> Result<(Vec<PathBuf>), Sca
 
     return Ok(book_paths);
 }
+// Books filed further down in a collection, e.g. author/series/title.epub, belong to the collection
+fn discover_nested_books(folder_path: &Path) -> Result<Vec<PathBuf>, ScanError> {
+    let mut book_paths = discover_books(folder_path)?;
+
+    for entry in fs::read_dir(folder_path)?.filter_map(|entry_res| entry_res.ok()) {
+        if entry.path().is_dir() {
+            book_paths.extend(discover_nested_books(&entry.path())?);
+        }
+    }
+
+    Ok(book_paths)
+}
+
 fn discover_collections(
     folder_path: impl Into<PathBuf>,
 ) -> Result<Vec<CollectionDicovery>, ScanError> {
@@ -129,9 +156,11 @@ async fn scan_book(
     epub: &mut Epub,
     library_id: i32,
     collection_id: Option<i32>,
-) -> Result<(InsertableBook, Option<InsertableAsset>), ScanError> {
-    let title = epub
-        .get_metadata("title")
+) -> Result<ScannedBook, ScanError> {
+    let metadata = epub.get_metadata().clone();
+    let title = metadata
+        .title
+        .clone()
         .ok_or(ScanError::EpubError("No title".to_string()))?;
     let path = epub.get_path();
     let path_str = path.to_str().ok_or(ScanError::InvalidPath(
@@ -139,7 +168,7 @@ async fn scan_book(
     ))?;
     let insertable_book = InsertableBook {
         path: path_str.to_string(),
-        name: title.to_string(),
+        name: title,
         library_id: library_id,
         collection_id,
         primary_cover: None,
@@ -147,7 +176,66 @@ async fn scan_book(
 
     let cover = extract_cover(epub).await?;
 
-    return Ok((insertable_book, cover));
+    return Ok((insertable_book, cover, metadata));
+}
+
+fn insertable_metadata(book_id: i32, metadata: Metadata) -> InsertableBookMetadata {
+    InsertableBookMetadata {
+        book_id,
+        language: metadata.language,
+        publisher: metadata.publisher,
+        published: metadata.published,
+        description: metadata.description,
+        authors: metadata
+            .authors
+            .into_iter()
+            .map(|author| InsertableAuthor {
+                name: author.name,
+                role: author.role,
+                file_as: author.file_as,
+            })
+            .collect(),
+        subjects: metadata.subjects,
+        identifiers: metadata
+            .identifiers
+            .into_iter()
+            .map(|identifier| InsertableIdentifier {
+                scheme: identifier.scheme,
+                value: identifier.value,
+            })
+            .collect(),
+        series: metadata.series.map(|series| InsertableSeries {
+            name: series.name,
+            position: series.position,
+        }),
+    }
+}
+
+async fn store_book(scanned_book: ScannedBook, pool: &Pool<Sqlite>) -> Result<Book, ScanError> {
+    let (mut book, cover, metadata) = scanned_book;
+
+    // A book is never left behind without its metadata
+    let mut transaction = pool.begin().await?;
+
+    let book = if let Some(cover) = cover {
+        let cover_asset = cover.insert(&mut *transaction).await?;
+        book.add_cover(cover_asset.id.clone());
+        let book = book.insert_in(&mut transaction).await?;
+        cover_asset
+            .into_book_cover(book.id, &mut *transaction)
+            .await?;
+        book
+    } else {
+        book.insert_in(&mut transaction).await?
+    };
+
+    insertable_metadata(book.id, metadata)
+        .insert_in(&mut transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    Ok(book)
 }
 
 pub async fn clean_up(path: impl Into<PathBuf>, pool: &Pool<Sqlite>) -> Result<(), ScanError> {
@@ -229,7 +317,7 @@ pub async fn force_scan(
         .await;
 
     // 5. Scan books
-    let insertable_books: Vec<(InsertableBook, Option<InsertableAsset>)> = stream::iter(root_books)
+    let insertable_books: Vec<ScannedBook> = stream::iter(root_books)
         .filter_map(|book_path| async move {
             let mut epub = Epub::new(&book_path).unwrap();
             scan_book(&mut epub, library_id, None).await.ok()
@@ -238,45 +326,433 @@ pub async fn force_scan(
         .await;
     // 6. Insert books into database
 
-    for (mut book, cover) in insertable_books {
-        if let Some(cover) = cover {
-            let cover_asset = cover.insert(&pool).await?;
-            book.add_cover(cover_asset.id.clone());
-            let book = book.insert(pool).await?;
-            cover_asset.into_book_cover(book.id, pool).await?;
-        } else {
-            book.insert(pool).await?;
-        }
-
+    for scanned_book in insertable_books {
+        store_book(scanned_book, pool).await?;
     }
 
     // 7. Scan books in collections
     for collection in collections {
         let collection_path = PathBuf::from(&collection.path);
         let collection_books = discover_books(&collection_path)?;
-        let insertable_books: Vec<(InsertableBook, Option<InsertableAsset>)> =
-            stream::iter(collection_books)
-                .filter_map(|book_path| async move {
-                    let mut epub = Epub::new(&book_path).unwrap();
-                    scan_book(&mut epub, library_id, Some(collection.id))
+        let insertable_books: Vec<ScannedBook> = stream::iter(collection_books)
+            .filter_map(|book_path| async move {
+                let mut epub = Epub::new(&book_path).unwrap();
+                scan_book(&mut epub, library_id, Some(collection.id))
+                    .await
+                    .ok()
+            })
+            .collect()
+            .await;
+
+        for scanned_book in insertable_books {
+            store_book(scanned_book, pool).await?;
+        }
+    }
+    Ok(())
+}
+
+// Summary of an incremental scan
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub discovered: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub moved: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    pub failed: Vec<(PathBuf, ScanError)>,
+}
+
+fn file_stat(path: &Path) -> Result<(i64, i64), ScanError> {
+    let metadata = fs::metadata(path)?;
+    let modified_at = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    Ok((metadata.len() as i64, modified_at))
+}
+
+fn hash_file(path: &Path) -> Result<String, ScanError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn file_info(path: &Path) -> Result<FileInfo, ScanError> {
+    let (size, modified_at) = file_stat(path)?;
+    let content_hash = hash_file(path)?;
+
+    Ok(FileInfo {
+        size,
+        modified_at,
+        content_hash,
+    })
+}
+
+fn path_to_string(path: &Path) -> Result<String, ScanError> {
+    path.to_str()
+        .map(|path| path.to_string())
+        .ok_or(ScanError::InvalidPath(
+            "Path is not valid unicode".to_string(),
+        ))
+}
+
+async fn delete_covers(book_id: i32, pool: &Pool<Sqlite>) -> Result<(), ScanError> {
+    for cover in Asset::get_cover_assets_book(book_id, pool).await? {
+        match tokio::fs::remove_file(&cover.local_path).await {
+            Ok(_) => (),
+            // Someone already cleaned up the cover, the row still has to go
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error.into()),
+        }
+        cover.delete_self(pool).await?;
+    }
+
+    Ok(())
+}
+
+// Extracts the cover of the book again, like after the cover folder was lost.
+// Returns false when the book has no cover.
+pub async fn regenerate_cover(book_id: i32, pool: &Pool<Sqlite>) -> Result<bool, ScanError> {
+    let mut book = Book::get_book(book_id, pool).await?;
+    let asset = Asset::get_asset(&book.asset_id, pool)
+        .await?
+        .ok_or(ScanError::AssetNotFound)?;
+    let mut epub = Epub::new(&PathBuf::from(&asset.local_path))?;
+
+    delete_covers(book.id, pool).await?;
+    book.primary_cover = None;
+    if let Some(cover) = extract_cover(&mut epub).await? {
+        let cover_asset = cover.insert(pool).await?;
+        cover_asset.into_book_cover(book.id, pool).await?;
+        book.primary_cover = Some(cover_asset.id);
+    }
+    book.update(pool).await?;
+
+    Ok(book.primary_cover.is_some())
+}
+
+async fn remove_book(book_file: BookFile, pool: &Pool<Sqlite>) -> Result<(), ScanError> {
+    delete_covers(book_file.book_id, pool).await?;
+    // Deleting the asset cascades to the book
+    Asset::delete_asset(&book_file.asset_id, pool).await?;
+
+    Ok(())
+}
+
+async fn add_book(
+    path: &Path,
+    library_id: i32,
+    collection_id: i32,
+    pool: &Pool<Sqlite>,
+) -> Result<Book, ScanError> {
+    let file_info = file_info(path)?;
+    let mut epub = Epub::new(&path.to_path_buf())?;
+    let scanned_book = scan_book(&mut epub, library_id, Some(collection_id)).await?;
+    let book = store_book(scanned_book, pool).await?;
+    search_index(book.id, &mut epub).insert(pool).await?;
+    Asset::update_file(&book.asset_id, &path_to_string(path)?, &file_info, pool).await?;
+
+    Ok(book)
+}
+
+// Re-reads a book whose file has changed, keeping the id of the book
+async fn refresh_book(
+    path: &Path,
+    book_file: &BookFile,
+    file_info: FileInfo,
+    pool: &Pool<Sqlite>,
+) -> Result<(), ScanError> {
+    let mut book = Book::get_book(book_file.book_id, pool).await?;
+    let mut epub = Epub::new(&path.to_path_buf())?;
+    let (scanned_book, cover, metadata) =
+        scan_book(&mut epub, book.library_id, Some(book_file.collection_id)).await?;
+
+    delete_covers(book.id, pool).await?;
+    book.name = scanned_book.name;
+    book.primary_cover = None;
+    if let Some(cover) = cover {
+        let cover_asset = cover.insert(pool).await?;
+        cover_asset.into_book_cover(book.id, pool).await?;
+        book.primary_cover = Some(cover_asset.id);
+    }
+    book.update(pool).await?;
+
+    insertable_metadata(book.id, metadata).insert(pool).await?;
+    search_index(book.id, &mut epub).insert(pool).await?;
+    Asset::update_file(
+        &book_file.asset_id,
+        &path_to_string(path)?,
+        &file_info,
+        pool,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn move_book(
+    path: &Path,
+    book_file: &BookFile,
+    collection_id: i32,
+    file_info: &FileInfo,
+    pool: &Pool<Sqlite>,
+) -> Result<(), ScanError> {
+    let mut book = Book::get_book(book_file.book_id, pool).await?;
+    book.collection_id = collection_id;
+    book.update(pool).await?;
+    Asset::update_file(&book_file.asset_id, &path_to_string(path)?, file_info, pool).await?;
+
+    Ok(())
+}
+
+// Books scanned before there was a search index are indexed by the next scan
+// Returns the books that could not be read, so they end up with the other failures in the report
+async fn index_unindexed(
+    library_id: i32,
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<(PathBuf, ScanError)>, ScanError> {
+    let mut failed = Vec::new();
+    for book_id in SearchResult::get_unindexed(library_id, pool).await? {
+        let book = Book::get_book(book_id, pool).await?;
+        let Some(asset) = Asset::get_asset(&book.asset_id, pool).await? else {
+            continue;
+        };
+
+        match Epub::new(&PathBuf::from(&asset.local_path)) {
+            Ok(mut epub) => search_index(book.id, &mut epub).insert(pool).await?,
+            Err(error) => failed.push((PathBuf::from(asset.local_path), error.into())),
+        }
+    }
+
+    Ok(failed)
+}
+
+// Makes sure every folder in the library has a collection, returns the collection id for each folder
+async fn sync_collections(
+    path: &Path,
+    library_id: i32,
+    pool: &Pool<Sqlite>,
+) -> Result<(HashMap<PathBuf, i32>, Vec<Collection>), ScanError> {
+    let path_str = path_to_string(path)?;
+    let mut existing = Collection::get_by_libary(library_id, pool).await?;
+    let root_position = existing
+        .iter()
+        .position(|collection| collection.name == "root" && collection.path == path_str)
+        .ok_or(ScanError::InvalidPath(
+            "Library has no root collection".to_string(),
+        ))?;
+    let root = existing.remove(root_position);
+
+    let mut collection_ids = HashMap::new();
+    collection_ids.insert(path.to_path_buf(), root.id);
+
+    for discovery in discover_collections(path)? {
+        let position = existing
+            .iter()
+            .position(|collection| Path::new(&collection.path) == discovery.path);
+        let collection = match position {
+            Some(position) => existing.remove(position),
+            None => {
+                scan_collection(&discovery, library_id)?
+                    .insert(pool)
+                    .await?
+            }
+        };
+        collection_ids.insert(discovery.path, collection.id);
+    }
+
+    // Whatever is left no longer exists on disk
+    Ok((collection_ids, existing))
+}
+
+// Compares the library folder with what is stored, and only touches the books that changed.
+// Books keep their ids as long as the file is still there, even if it has been moved or edited.
+pub async fn incremental_scan(
+    path: impl Into<PathBuf>,
+    library_id: i32,
+    pool: &Pool<Sqlite>,
+) -> Result<ScanReport, ScanError> {
+    incremental_scan_with_progress(path, library_id, pool, &mut |_| ()).await
+}
+
+// Same as incremental_scan, but hands the report so far to progress as the scan goes along
+pub async fn incremental_scan_with_progress(
+    path: impl Into<PathBuf>,
+    library_id: i32,
+    pool: &Pool<Sqlite>,
+    progress: &mut (dyn FnMut(&ScanReport) + Send),
+) -> Result<ScanReport, ScanError> {
+    let path: PathBuf = path.into();
+    let mut report = ScanReport::default();
+
+    let (collection_ids, stale_collections) = sync_collections(&path, library_id, pool).await?;
+
+    let mut known_files: HashMap<String, BookFile> = BookFile::get_by_library(library_id, pool)
+        .await?
+        .into_iter()
+        .map(|book_file| (book_file.local_path.clone(), book_file))
+        .collect();
+
+    let mut new_files = Vec::new();
+
+    for (folder, collection_id) in &collection_ids {
+        let book_paths = if *folder == path {
+            discover_books(folder)?
+        } else {
+            discover_nested_books(folder)?
+        };
+
+        for book_path in book_paths {
+            report.discovered += 1;
+            progress(&report);
+
+            let path_str = match path_to_string(&book_path) {
+                Ok(path_str) => path_str,
+                Err(error) => {
+                    report.failed.push((book_path, error));
+                    continue;
+                }
+            };
+
+            let Some(book_file) = known_files.remove(&path_str) else {
+                new_files.push((book_path, *collection_id));
+                continue;
+            };
+
+            let result = match file_stat(&book_path) {
+                // Size and modification time are cheap to check, so the file is only hashed when they differ
+                Ok((size, modified_at))
+                    if book_file.file_size == Some(size)
+                        && book_file.modified_at == Some(modified_at)
+                        && book_file.content_hash.is_some() =>
+                {
+                    report.unchanged += 1;
+                    Ok(())
+                }
+                Ok(_) => match file_info(&book_path) {
+                    Ok(file_info)
+                        if book_file.content_hash.as_ref() == Some(&file_info.content_hash) =>
+                    {
+                        Asset::update_file(&book_file.asset_id, &path_str, &file_info, pool)
+                            .await
+                            .map_err(ScanError::from)
+                            .map(|_| report.unchanged += 1)
+                    }
+                    Ok(file_info) => refresh_book(&book_path, &book_file, file_info, pool)
                         .await
-                        .ok()
-                })
-                .collect()
-                .await;
-
-        for (mut book, cover) in insertable_books {
-            if let Some(cover) = cover {
-                let cover_asset = cover.insert(&pool).await?;
-                book.add_cover(cover_asset.id.clone());
-                let book = book.insert(pool).await?;
-                cover_asset.into_book_cover(book.id, pool).await?;
-            } else {
-                book.insert(pool).await?;
+                        .map(|_| {
+                            report.updated += 1;
+                            publish(Event::BookUpdated {
+                                library_id,
+                                book_id: book_file.book_id,
+                            });
+                        }),
+                    Err(error) => Err(error),
+                },
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = result {
+                report.failed.push((book_path, error));
             }
         }
     }
-    Ok(())
+
+    // Files that disappeared from their old path might just have been moved or renamed
+    let mut missing_by_hash: HashMap<String, BookFile> = HashMap::new();
+    let mut missing = Vec::new();
+    for (_, book_file) in known_files {
+        match book_file.content_hash.clone() {
+            Some(content_hash) => {
+                missing_by_hash.insert(content_hash, book_file);
+            }
+            None => missing.push(book_file),
+        }
+    }
+
+    for (book_path, collection_id) in new_files {
+        progress(&report);
+
+        let file_info = match file_info(&book_path) {
+            Ok(file_info) => file_info,
+            Err(error) => {
+                report.failed.push((book_path, error));
+                continue;
+            }
+        };
+
+        let result = match missing_by_hash.remove(&file_info.content_hash) {
+            Some(book_file) => move_book(&book_path, &book_file, collection_id, &file_info, pool)
+                .await
+                .map(|_| {
+                    report.moved += 1;
+                    publish(Event::BookUpdated {
+                        library_id,
+                        book_id: book_file.book_id,
+                    });
+                }),
+            None => add_book(&book_path, library_id, collection_id, pool)
+                .await
+                .map(|book| {
+                    report.added += 1;
+                    publish(Event::BookAdded {
+                        library_id,
+                        book_id: book.id,
+                    });
+                }),
+        };
+
+        if let Err(error) = result {
+            report.failed.push((book_path, error));
+        }
+    }
+
+    // Collections that still hold a book that could not be removed
+    let mut kept_collections = HashSet::new();
+
+    for book_file in missing.into_iter().chain(missing_by_hash.into_values()) {
+        let book_id = book_file.book_id;
+        let collection_id = book_file.collection_id;
+        let book_path = PathBuf::from(&book_file.local_path);
+        match remove_book(book_file, pool).await {
+            Ok(_) => {
+                report.removed += 1;
+                publish(Event::BookRemoved {
+                    library_id,
+                    book_id,
+                });
+            }
+            Err(error) => {
+                kept_collections.insert(collection_id);
+                report.failed.push((book_path, error));
+            }
+        }
+    }
+
+    // The kept collections are removed by a later scan, together with their books
+    for collection in stale_collections {
+        if kept_collections.contains(&collection.id) {
+            continue;
+        }
+        if let Err(error) = collection.delete_self(pool).await {
+            report
+                .failed
+                .push((PathBuf::from(&collection.path), error.into()));
+        }
+    }
+
+    report
+        .failed
+        .extend(index_unindexed(library_id, pool).await?);
+
+    progress(&report);
+
+    Ok(report)
 }
 
 #[derive(Debug)]
@@ -287,6 +763,25 @@ pub enum ScanError {
     MetadataNotSet(String),
     InvalidCoverMimeType(String),
     AssetNotFound,
+    AlreadyRunning(i32),
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::InvalidPath(error) => write!(f, "Invalid path: {}", error),
+            ScanError::DatabaseError => write!(f, "Database error"),
+            ScanError::EpubError(error) => write!(f, "Could not read epub: {}", error),
+            ScanError::MetadataNotSet(error) => write!(f, "Missing metadata: {}", error),
+            ScanError::InvalidCoverMimeType(mime_type) => {
+                write!(f, "Unsupported cover type: {}", mime_type)
+            }
+            ScanError::AssetNotFound => write!(f, "Asset not found"),
+            ScanError::AlreadyRunning(job_id) => {
+                write!(f, "Scan job {} is already scanning the library", job_id)
+            }
+        }
+    }
 }
 
 impl From<std::io::Error> for ScanError {
@@ -310,6 +805,266 @@ impl From<sqlx::Error> for ScanError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
+    use crate::test_epub::{temp_path, write_simple_epub};
+    use database::library::InsertableLibrary;
+    use database::users::{InsertableUser, Role};
+    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+
+    async fn book_id(path: &Path, library_id: i32, pool: &Pool<Sqlite>) -> Option<i32> {
+        let path = path.to_str().unwrap();
+        BookFile::get_by_library(library_id, pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|book_file| book_file.local_path == path)
+            .map(|book_file| book_file.book_id)
+    }
+
+    #[tokio::test]
+    async fn test_incremental_scan() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("incremental-scan");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(library_path.join("series")).unwrap();
+
+        let first = library_path.join("first.epub");
+        let second = library_path.join("second.epub");
+        let third = library_path.join("series").join("third.epub");
+        write_simple_epub(&first, "First", "<html><head></head><body>1</body></html>");
+        write_simple_epub(
+            &second,
+            "Second",
+            "<html><head></head><body>2</body></html>",
+        );
+        write_simple_epub(&third, "Third", "<html><head></head><body>3</body></html>");
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "library".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.discovered, 3);
+        assert_eq!(report.added, 3);
+        assert!(report.failed.is_empty());
+
+        let first_id = book_id(&first, library.id, &pool).await.unwrap();
+        let second_id = book_id(&second, library.id, &pool).await.unwrap();
+
+        // Nothing changed on disk, so nothing should change in the database
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.unchanged, 3);
+        assert_eq!(report.added, 0);
+
+        // Edit one book, move another and delete the last one
+        write_simple_epub(
+            &second,
+            "Second edition",
+            "<html><head></head><body>2</body></html>",
+        );
+        let moved = library_path.join("series").join("first.epub");
+        fs::rename(&first, &moved).unwrap();
+        fs::remove_file(&third).unwrap();
+
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(report.moved, 1);
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.added, 0);
+
+        assert_eq!(book_id(&second, library.id, &pool).await, Some(second_id));
+        assert_eq!(book_id(&moved, library.id, &pool).await, Some(first_id));
+        assert_eq!(book_id(&third, library.id, &pool).await, None);
+
+        let second_book = Book::get_book(second_id, &pool).await.unwrap();
+        assert_eq!(second_book.name, "Second edition");
+
+        let books = Book::get_books_by_library(library.id, &pool).await.unwrap();
+        assert_eq!(books.len(), 2);
 
+        // The search index follows the changes, an admin can search every library
+        let admin = InsertableUser {
+            username: "admin".to_string(),
+            hashed_password: String::new(),
+            role: Role::Admin,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        let results = SearchResult::search("edition", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].book_id, second_id);
+        let results = SearchResult::search("third", 10, admin.id, &pool)
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        fs::remove_dir_all(library_path).unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_incremental_scan_failed_removal() {
+        // sqlx retries a failed statement until its result is dropped, waiting out locks held by other
+        // connections, so with more than one connection the refused removal can go through after all
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("failed-removal");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+
+        // The first book lies in a collection of its own
+        let collection_path = library_path.join("collection");
+        fs::create_dir_all(&collection_path).unwrap();
+        let first = collection_path.join("first.epub");
+        let second = library_path.join("second.epub");
+        write_simple_epub(&first, "First", "<html><head></head><body>1</body></html>");
+        write_simple_epub(
+            &second,
+            "Second",
+            "<html><head></head><body>2</body></html>",
+        );
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "library".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+
+        // Make the database refuse to remove the first book
+        let first_file = BookFile::get_by_library(library.id, &pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|book_file| book_file.local_path == first.to_str().unwrap())
+            .unwrap();
+        sqlx::query(&format!(
+            "CREATE TRIGGER keep_first BEFORE DELETE ON assets WHEN OLD.id = '{}' \
+             BEGIN SELECT RAISE(ABORT, 'locked'); END",
+            first_file.asset_id
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        fs::remove_dir_all(&collection_path).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        // The failed removal is reported, the other book is still removed and the collection is kept for its book
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, first);
+        assert_eq!(book_id(&second, library.id, &pool).await, None);
+        assert!(Collection::get_collection(first_file.collection_id, &pool)
+            .await
+            .is_ok());
+
+        // Once the book can be removed the collection goes with it
+        sqlx::query("DROP TRIGGER keep_first")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(report.failed.is_empty());
+        assert!(Collection::get_collection(first_file.collection_id, &pool)
+            .await
+            .is_err());
+
+        fs::remove_dir_all(library_path).unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_add_book_failed_metadata() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../database/migrations")
+            .run(&pool)
+            .await
+            .unwrap();
+
+        let library_path = temp_path("failed-metadata");
+        let _ = fs::remove_dir_all(&library_path);
+        fs::create_dir_all(&library_path).unwrap();
+        let book = library_path.join("book.epub");
+        write_simple_epub(&book, "Book", "<html><head></head><body>1</body></html>");
+
+        let library = InsertableLibrary {
+            path: library_path.to_str().unwrap().to_string(),
+            name: "library".to_string(),
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+
+        // Make the database refuse the authors of the book
+        sqlx::query(
+            "CREATE TRIGGER refuse_authors BEFORE INSERT ON book_authors \
+             BEGIN SELECT RAISE(ABORT, 'locked'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The book is left out completely instead of being stored without its metadata
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.added, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(book_id(&book, library.id, &pool).await, None);
+        let (assets,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM assets")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(assets, 0);
+
+        // The next scan adds it
+        sqlx::query("DROP TRIGGER refuse_authors")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let report = incremental_scan(&library_path, library.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(report.added, 1);
+        assert!(book_id(&book, library.id, &pool).await.is_some());
+
+        fs::remove_dir_all(library_path).unwrap();
+        pool.close().await;
+    }
 }
@@ -0,0 +1,236 @@
+use crate::epub_sandbox::Epub;
+use database::search::{InsertableSearchIndex, SearchDocument};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+
+// Characters of text shown before and after a match
+const CONTEXT_LENGTH: usize = 40;
+
+// Elements whose text is never shown to the reader
+const HIDDEN_ELEMENTS: [&[u8]; 4] = [b"head", b"script", b"style", b"title"];
+
+fn push_text(text: &mut String, value: &str) {
+    for word in value.split_whitespace() {
+        if !text.is_empty() && !text.ends_with(' ') {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+}
+
+// The readable text of an xhtml document, with all whitespace collapsed to single spaces.
+// Text nodes are always separated, so text from two paragraphs doesn't run together.
+pub fn plain_text(document: &str) -> String {
+    let mut reader = Reader::from_str(document);
+    // Lots of books have slightly broken xhtml
+    reader.check_end_names(false);
+
+    let mut text = String::new();
+    let mut hidden = 0;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                if hidden > 0 || HIDDEN_ELEMENTS.contains(&e.local_name().as_ref()) {
+                    hidden += 1;
+                }
+            }
+            Ok(Event::End(_)) => {
+                if hidden > 0 {
+                    hidden -= 1;
+                }
+            }
+            // &nbsp; is only defined in the xhtml dtd, but books use it all the time
+            Ok(Event::Text(e)) if hidden == 0 => match e.unescape_with(|entity| match entity {
+                "nbsp" => Some("\u{a0}"),
+                _ => None,
+            }) {
+                Ok(value) => push_text(&mut text, &value),
+                Err(_) => push_text(&mut text, &String::from_utf8_lossy(&e)),
+            },
+            Ok(Event::CData(e)) if hidden == 0 => {
+                push_text(&mut text, &String::from_utf8_lossy(&e))
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            // Keep what could be read before the document broke
+            Err(_) => break,
+        }
+    }
+
+    text
+}
+
+// Everything needed to find the book, one document for each spine item with text in it
+pub fn search_index(book_id: i32, epub: &mut Epub) -> InsertableSearchIndex {
+    let metadata = epub.get_metadata();
+    let title = metadata.title.clone().unwrap_or_default();
+    let authors = metadata
+        .authors
+        .iter()
+        .map(|author| author.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+
+    let documents = (0..epub.spine_len())
+        .filter_map(|spine_index| {
+            let content = plain_text(&epub.get_spine_document(spine_index)?);
+            if content.is_empty() {
+                return None;
+            }
+            Some(SearchDocument {
+                spine_index: Some(spine_index as i32),
+                content,
+            })
+        })
+        .collect();
+
+    InsertableSearchIndex {
+        book_id,
+        title,
+        authors,
+        documents,
+    }
+}
+
+// A match of a search inside a book, offset and length count characters in the plain text of the document
+#[derive(Debug, PartialEq)]
+pub struct TextMatch {
+    pub spine_index: usize,
+    pub offset: usize,
+    pub length: usize,
+    pub before: String,
+    pub text: String,
+    pub after: String,
+}
+
+// Case is ignored by comparing the first lowercase character of each character,
+// so every character keeps its position and offsets match the original text
+fn fold(text: &str) -> Vec<char> {
+    text.chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect()
+}
+
+// Every place the query shows up in the text of the spine documents, in reading order.
+// Whitespace in the query matches any whitespace in the book, like in the plain text.
+// Reads the whole book, so async callers should run it on a blocking thread.
+pub fn find_in_book(epub: &mut Epub, query: &str, limit: usize) -> Vec<TextMatch> {
+    let query = fold(&query.split_whitespace().collect::<Vec<&str>>().join(" "));
+    let mut matches = Vec::new();
+    if query.is_empty() {
+        return matches;
+    }
+
+    for spine_index in 0..epub.spine_len() {
+        let Some(document) = epub.get_spine_document(spine_index) else {
+            continue;
+        };
+        let text = plain_text(&document);
+        let chars: Vec<char> = text.chars().collect();
+        let folded = fold(&text);
+
+        let mut offset = 0;
+        while offset + query.len() <= folded.len() {
+            if folded[offset..offset + query.len()] != query[..] {
+                offset += 1;
+                continue;
+            }
+
+            let end = offset + query.len();
+            matches.push(TextMatch {
+                spine_index,
+                offset,
+                length: query.len(),
+                before: chars[offset.saturating_sub(CONTEXT_LENGTH)..offset]
+                    .iter()
+                    .collect(),
+                text: chars[offset..end].iter().collect(),
+                after: chars[end..(end + CONTEXT_LENGTH).min(chars.len())]
+                    .iter()
+                    .collect(),
+            });
+            if matches.len() >= limit {
+                return matches;
+            }
+            offset = end;
+        }
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_epub::{temp_path, write_simple_epub};
+
+    #[test]
+    fn test_plain_text() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter</title><style>p { color: red; }</style></head>
+<body>
+  <h1>One</h1><p>First&#160;line &amp; more,<br/>second&nbsp;line</p>
+  <script>alert("hidden")</script>
+  <p><![CDATA[raw text]]></p>
+</body>
+</html>"#;
+
+        assert_eq!(
+            plain_text(document),
+            "One First line & more, second line raw text"
+        );
+    }
+
+    #[test]
+    fn test_search_index() {
+        let path = temp_path("search-index.epub");
+        write_simple_epub(&path, "Indexed", "<p>Some <em>indexed</em> text</p>");
+
+        let mut epub = Epub::new(&path).unwrap();
+        let index = search_index(7, &mut epub);
+
+        assert_eq!(index.book_id, 7);
+        assert_eq!(index.title, "Indexed");
+        assert_eq!(index.authors, "Test Author");
+        assert_eq!(index.documents.len(), 1);
+        assert_eq!(index.documents[0].spine_index, Some(0));
+        assert!(index.documents[0].content.contains("Some indexed text"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_find_in_book() {
+        let path = temp_path("find-in-book.epub");
+        write_simple_epub(
+            &path,
+            "Find",
+            "<p>The spice must flow.</p><p>SPICE\n  is   everywhere, spice!</p>",
+        );
+
+        let mut epub = Epub::new(&path).unwrap();
+
+        let matches = find_in_book(&mut epub, "spice", 10);
+        assert_eq!(matches.len(), 3);
+        assert_eq!(matches[0].spine_index, 0);
+        assert_eq!(matches[1].text, "SPICE");
+        // The context is cut off at 40 characters
+        assert_eq!(
+            matches[2].before,
+            "e spice must flow. SPICE is everywhere, "
+        );
+        assert_eq!(matches[2].after, "!");
+
+        // Whitespace in the book is collapsed, so it matches a single space in the query
+        let matches = find_in_book(&mut epub, "spice  is", 10);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].offset, matches[0].before.chars().count());
+
+        assert_eq!(find_in_book(&mut epub, "spice", 1).len(), 1);
+        assert!(find_in_book(&mut epub, "melange", 10).is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
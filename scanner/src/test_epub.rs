@@ -0,0 +1,49 @@
+// Helpers for building small epub files in tests
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}", name, std::process::id()))
+}
+
+pub(crate) fn write_test_epub(path: &Path, files: &[(&str, &str)]) {
+    let file = std::fs::File::create(path).unwrap();
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::FileOptions::default();
+
+    for (name, content) in files {
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    }
+
+    zip.finish().unwrap();
+}
+
+// A single chapter epub with the package at the root
+pub(crate) fn write_simple_epub(path: &Path, title: &str, chapter: &str) {
+    let package = format!(
+        r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title>{}</dc:title>
+        <dc:creator>Test Author</dc:creator>
+    </metadata>
+    <manifest>
+        <item id="chapter1" href="chapter1.xhtml" media-type="application/xhtml+xml"/>
+    </manifest>
+    <spine>
+        <itemref idref="chapter1"/>
+    </spine>
+</package>"#,
+        title
+    );
+
+    write_test_epub(
+        path,
+        &[
+            ("mimetype", "application/epub+zip"),
+            ("content.opf", &package),
+            ("chapter1.xhtml", chapter),
+        ],
+    );
+}
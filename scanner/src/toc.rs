@@ -0,0 +1,323 @@
+use crate::epub_sandbox::{resolve_href, EpubError};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocEntry {
+    pub label: String,
+    // None when the entry points at something outside the spine
+    pub spine_index: Option<usize>,
+    pub fragment: Option<String>,
+    pub children: Vec<TocEntry>,
+}
+
+impl TocEntry {
+    fn new() -> Self {
+        TocEntry {
+            label: String::new(),
+            spine_index: None,
+            fragment: None,
+            children: Vec::new(),
+        }
+    }
+
+    // Hrefs in the toc are relative to the toc document, not the package
+    fn set_target(&mut self, href: &str, doc_dir: &Path, spine: &[Option<PathBuf>]) {
+        let (path, fragment) = match href.split_once('#') {
+            Some((path, fragment)) => (path, Some(fragment.to_string())),
+            None => (href, None),
+        };
+
+        let path = resolve_href(doc_dir, path);
+        self.spine_index = spine
+            .iter()
+            .position(|spine_path| spine_path.as_ref() == Some(&path));
+        self.fragment = fragment.filter(|fragment| !fragment.is_empty());
+    }
+
+    fn push_label(&mut self, text: &str) {
+        if !self.label.is_empty() {
+            self.label.push(' ');
+        }
+        self.label.push_str(text);
+    }
+
+    fn finish(mut self) -> Self {
+        self.label = self.label.split_whitespace().collect::<Vec<_>>().join(" ");
+        self
+    }
+}
+
+fn attribute(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .find(|a| a.key.as_ref() == name)
+        .and_then(|a| a.unescape_value().ok().map(|value| value.to_string()))
+}
+
+// Adds the finished entry to its parent, or to the root if it is a top level entry
+fn attach(stack: &mut [TocEntry], toc: &mut Vec<TocEntry>, entry: TocEntry) {
+    match stack.last_mut() {
+        Some(parent) => parent.children.push(entry),
+        None => toc.push(entry),
+    }
+}
+
+// Parses an EPUB2 toc.ncx navMap
+pub fn parse_ncx(
+    xml: &str,
+    doc_dir: &Path,
+    spine: &[Option<PathBuf>],
+) -> Result<Vec<TocEntry>, EpubError> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut toc = Vec::new();
+    let mut stack: Vec<TocEntry> = Vec::new();
+    let mut in_nav_map = false;
+    let mut in_label = false;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"navMap" => in_nav_map = true,
+                b"navPoint" if in_nav_map => stack.push(TocEntry::new()),
+                b"text" => in_label = true,
+                _ => (),
+            },
+            Ok(Event::Empty(ref e)) => {
+                if e.local_name().as_ref() != b"content" {
+                    continue;
+                }
+                if let (Some(entry), Some(src)) = (stack.last_mut(), attribute(e, b"src")) {
+                    entry.set_target(&src, doc_dir, spine);
+                }
+            }
+            Ok(Event::Text(e)) if in_label => {
+                if let Some(entry) = stack.last_mut() {
+                    let text = e.unescape()?;
+                    entry.push_label(&text);
+                }
+            }
+            Ok(Event::End(ref e)) => match e.local_name().as_ref() {
+                b"navMap" => in_nav_map = false,
+                b"navPoint" => {
+                    if let Some(entry) = stack.pop() {
+                        attach(&mut stack, &mut toc, entry.finish());
+                    }
+                }
+                b"text" => in_label = false,
+                _ => (),
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => Err(EpubError::from(e))?,
+            _ => (),
+        }
+    }
+
+    Ok(toc)
+}
+
+// Parses the toc nav element of an EPUB3 navigation document
+pub fn parse_nav(
+    xml: &str,
+    doc_dir: &Path,
+    spine: &[Option<PathBuf>],
+) -> Result<Vec<TocEntry>, EpubError> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    // Navigation documents are often hand written, so be lenient about mismatched tags
+    reader.check_end_names(false);
+
+    let mut toc = Vec::new();
+    let mut stack: Vec<TocEntry> = Vec::new();
+    // Depth of nested nav elements, only the one marked as toc is of interest
+    let mut nav_depth = 0;
+    let mut in_toc = false;
+    // Links often wrap spans, so the label only ends with the outermost element
+    let mut label_depth = 0;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"nav" => {
+                    nav_depth += 1;
+                    if !in_toc {
+                        in_toc = attribute(e, b"epub:type")
+                            .map(|types| types.split_whitespace().any(|t| t == "toc"))
+                            .unwrap_or(false);
+                    }
+                }
+                b"li" if in_toc => stack.push(TocEntry::new()),
+                b"a" | b"span" if in_toc => {
+                    label_depth += 1;
+                    if let (Some(entry), Some(href)) = (stack.last_mut(), attribute(e, b"href")) {
+                        entry.set_target(&href, doc_dir, spine);
+                    }
+                }
+                _ => (),
+            },
+            Ok(Event::Text(e)) if label_depth > 0 => {
+                if let Some(entry) = stack.last_mut() {
+                    match e.unescape() {
+                        Ok(text) => entry.push_label(&text),
+                        Err(_) => entry.push_label(&String::from_utf8_lossy(&e)),
+                    }
+                }
+            }
+            Ok(Event::End(ref e)) => match e.local_name().as_ref() {
+                b"nav" => {
+                    nav_depth -= 1;
+                    if in_toc && nav_depth == 0 {
+                        in_toc = false;
+                    }
+                }
+                b"li" if in_toc => {
+                    if let Some(entry) = stack.pop() {
+                        attach(&mut stack, &mut toc, entry.finish());
+                    }
+                }
+                b"a" | b"span" if label_depth > 0 => label_depth -= 1,
+                _ => (),
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => Err(EpubError::from(e))?,
+            _ => (),
+        }
+    }
+
+    Ok(toc)
+}
+
+fn flatten<'a>(toc: &'a [TocEntry], entries: &mut Vec<&'a TocEntry>) {
+    for entry in toc {
+        entries.push(entry);
+        flatten(&entry.children, entries);
+    }
+}
+
+// The title of the chapter a spine document belongs to. Documents without their own entry
+// belong to the closest entry before them, since chapters are often split over several files.
+pub fn chapter_title(toc: &[TocEntry], spine_index: usize) -> Option<String> {
+    let mut entries = Vec::new();
+    flatten(toc, &mut entries);
+
+    entries
+        .iter()
+        .find(|entry| entry.spine_index == Some(spine_index))
+        .or_else(|| {
+            entries
+                .iter()
+                .filter(|entry| entry.spine_index.is_some_and(|index| index < spine_index))
+                .max_by_key(|entry| entry.spine_index)
+        })
+        .map(|entry| entry.label.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spine() -> Vec<Option<PathBuf>> {
+        vec![
+            Some(PathBuf::from("OEBPS/Text/chapter1.xhtml")),
+            Some(PathBuf::from("OEBPS/Text/chapter2.xhtml")),
+        ]
+    }
+
+    #[test]
+    fn test_parse_ncx() {
+        let ncx = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <docTitle><text>Book</text></docTitle>
+    <navMap>
+        <navPoint id="p1" playOrder="1">
+            <navLabel><text>Chapter 1</text></navLabel>
+            <content src="Text/chapter1.xhtml"/>
+            <navPoint id="p2" playOrder="2">
+                <navLabel><text>Section 1.1</text></navLabel>
+                <content src="Text/chapter1.xhtml#s1"/>
+            </navPoint>
+        </navPoint>
+        <navPoint id="p3" playOrder="3">
+            <navLabel><text>Chapter 2</text></navLabel>
+            <content src="Text/chapter2.xhtml"/>
+        </navPoint>
+    </navMap>
+</ncx>"#;
+
+        let toc = parse_ncx(ncx, Path::new("OEBPS"), &spine()).unwrap();
+
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].label, "Chapter 1");
+        assert_eq!(toc[0].spine_index, Some(0));
+        assert_eq!(toc[0].children.len(), 1);
+        assert_eq!(toc[0].children[0].label, "Section 1.1");
+        assert_eq!(toc[0].children[0].fragment, Some("s1".to_string()));
+        assert_eq!(toc[1].label, "Chapter 2");
+        assert_eq!(toc[1].spine_index, Some(1));
+    }
+
+    #[test]
+    fn test_parse_nav() {
+        let nav = r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<body>
+    <nav epub:type="toc">
+        <ol>
+            <li><a href="chapter1.xhtml">Chapter
+                1</a>
+                <ol>
+                    <li><a href="chapter1.xhtml#s1">Section <em>1.1</em></a></li>
+                </ol>
+            </li>
+            <li><span>Part</span>
+                <ol><li><a href="chapter2.xhtml"><span>Chapter</span> 2</a></li></ol>
+            </li>
+        </ol>
+    </nav>
+    <nav epub:type="landmarks">
+        <ol><li><a href="chapter2.xhtml">Landmark</a></li></ol>
+    </nav>
+</body>
+</html>"#;
+
+        let toc = parse_nav(nav, Path::new("OEBPS/Text"), &spine()).unwrap();
+
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].label, "Chapter 1");
+        assert_eq!(toc[0].spine_index, Some(0));
+        assert_eq!(toc[0].children[0].label, "Section 1.1");
+        assert_eq!(toc[0].children[0].fragment, Some("s1".to_string()));
+        assert_eq!(toc[1].label, "Part");
+        assert_eq!(toc[1].spine_index, None);
+        assert_eq!(toc[1].children[0].label, "Chapter 2");
+        assert_eq!(toc[1].children[0].spine_index, Some(1));
+    }
+
+    #[test]
+    fn test_chapter_title() {
+        let entry = |label: &str, spine_index, children| TocEntry {
+            label: label.to_string(),
+            spine_index,
+            fragment: None,
+            children,
+        };
+        let toc = vec![
+            entry("Cover", None, Vec::new()),
+            entry(
+                "Part 1",
+                Some(1),
+                vec![entry("Chapter 1", Some(2), Vec::new())],
+            ),
+            entry("Chapter 2", Some(5), Vec::new()),
+        ];
+
+        assert_eq!(chapter_title(&toc, 0), None);
+        assert_eq!(chapter_title(&toc, 1).as_deref(), Some("Part 1"));
+        assert_eq!(chapter_title(&toc, 2).as_deref(), Some("Chapter 1"));
+        assert_eq!(chapter_title(&toc, 4).as_deref(), Some("Chapter 1"));
+        assert_eq!(chapter_title(&toc, 9).as_deref(), Some("Chapter 2"));
+    }
+}
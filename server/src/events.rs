@@ -1,5 +0,0 @@
-pub enum EventsTypes {
-    ScanLibrary { library_id: i32 },
-}
-
-
@@ -1,14 +1,22 @@
+use crate::overvaking::{start_overvaking, Innlevering};
 use crate::Konfig;
-use axum::routing::{delete, get, post};
-use axum::Router;
+use axum::routing::{delete, get, patch, post, put};
+use axum::{Extension, Router};
+use database::MigrationError;
+use scanner::jobs::ScanJobs;
 use std::thread;
 use tokio::select;
 use tower_http::services::{ServeDir, ServeFile};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, info, warn};
 use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
 use utoipa::Modify;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
-use web::endepunkter::{auth, books, hello, images, library};
+use web::base_url::PublicUrl;
+use web::endepunkter::{
+    auth, bookmarks, books, events, folders, hello, images, invites, library, opds, search, users,
+};
 struct Terminal {
     kanal: tokio::sync::mpsc::Receiver<String>,
 }
@@ -31,7 +39,7 @@ async fn hent_termianl_meldinger(mut terminal: Terminal) {
     loop {
         let melding = terminal.kanal.recv().await.unwrap();
 
-        println!("Fikk melding: {:?}", melding);
+        debug!("Fikk melding: {:?}", melding);
 
         if melding == "stopp\n" {
             break;
@@ -49,21 +57,73 @@ pub async fn kjerne_pakker(konfig: Konfig) {
             web::endepunkter::hello::root,
             web::endepunkter::auth::register,
             web::endepunkter::auth::login,
+            web::endepunkter::auth::logout,
+            web::endepunkter::auth::get_sessions,
+            web::endepunkter::auth::delete_session,
+            web::endepunkter::invites::add_invite,
+            web::endepunkter::invites::get_invites,
+            web::endepunkter::invites::delete_invite,
+            web::endepunkter::users::get_me,
+            web::endepunkter::users::update_me,
+            web::endepunkter::users::change_password,
+            web::endepunkter::users::get_users,
+            web::endepunkter::users::reset_password,
+            web::endepunkter::users::delete_user,
             web::endepunkter::library::add_library,
             web::endepunkter::library::get_libraries,
             web::endepunkter::library::delete_library,
             web::endepunkter::library::scan_library,
+            web::endepunkter::library::get_scan_job,
+            web::endepunkter::library::get_library_access,
+            web::endepunkter::library::grant_library_access,
+            web::endepunkter::library::revoke_library_access,
+            web::endepunkter::folders::add_folder,
+            web::endepunkter::folders::get_folders,
+            web::endepunkter::folders::get_folder,
+            web::endepunkter::folders::update_folder,
+            web::endepunkter::folders::delete_folder,
             web::endepunkter::books::get_books,
             web::endepunkter::books::get_book,
+            web::endepunkter::books::get_book_toc,
+            web::endepunkter::books::get_book_progress,
+            web::endepunkter::books::sync_book_progress,
+            web::endepunkter::books::add_highlight,
+            web::endepunkter::books::get_highlights,
+            web::endepunkter::books::update_highlight,
+            web::endepunkter::books::delete_highlight,
+            web::endepunkter::books::export_highlights,
+            web::endepunkter::books::search_book,
+            web::endepunkter::books::download_book,
+            web::endepunkter::bookmarks::add_bookmark,
+            web::endepunkter::bookmarks::get_bookmarks,
+            web::endepunkter::bookmarks::rename_bookmark,
+            web::endepunkter::bookmarks::delete_bookmark,
             web::endepunkter::books::get_book_page,
             web::endepunkter::books::get_book_resource,
             web::endepunkter::images::get_cover,
+            web::endepunkter::events::get_events,
+            web::endepunkter::search::search,
+            web::endepunkter::opds::root,
+            web::endepunkter::opds::get_libraries,
+            web::endepunkter::opds::get_library,
+            web::endepunkter::opds::get_library_books,
+            web::endepunkter::opds::get_collection,
+            web::endepunkter::opds::get_authors,
+            web::endepunkter::opds::get_author,
+            web::endepunkter::opds::get_recent,
+            web::endepunkter::opds::search,
+            web::endepunkter::opds::opensearch,
+            web::endepunkter::opds::download_book,
         ),
         components(
             schemas(
                 database::users::Register,
                 database::users::Login,
+                database::users::Role,
                 database::library::InsertableLibrary,
+                database::folders::InsertableFolder,
+                database::folders::DropType,
+                database::highlights::HighlightColor,
             )
         ),
         tags(
@@ -88,9 +148,52 @@ pub async fn kjerne_pakker(konfig: Konfig) {
     // Stupid hack to get around the fact that the openapi crate doesn't support adding security through the macro
     open_api.merge(open_api_schema);
 
-    let pool = database::create_pool(&konfig.database_path).await.unwrap();
+    let pool = match database::create_pool(&konfig.database_path).await {
+        Ok(pool) => pool,
+        Err(feil) => {
+            error!(
+                "Kunne ikke åpne databasen {}: {}",
+                konfig.database_path, feil
+            );
+            std::process::exit(1);
+        }
+    };
+
+    match database::migrate(&pool).await {
+        Ok(kjort) if kjort.is_empty() => debug!("Databasen er oppdatert"),
+        Ok(kjort) => info!("Kjørte {} migreringer på databasen", kjort.len()),
+        Err(feil @ MigrationError::NewerSchema { .. }) => {
+            error!(
+                "Databasen er laget av en nyere versjon av programmet ({}), oppdater programmet",
+                feil
+            );
+            std::process::exit(1);
+        }
+        Err(feil) => {
+            error!("Kunne ikke migrere databasen: {}", feil);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(feil) = database::scan_jobs::ScanJob::fail_unfinished(&pool).await {
+        warn!("Kunne ikke rydde opp i avbrutte skanninger: {}", feil);
+    }
 
-    println!("Bruker web-ui fra {:?}", konfig.web_ui_path);
+    let innlevering = Innlevering {
+        monster: konfig.drop_pattern.clone(),
+        karantene: konfig.quarantine_path.clone(),
+    };
+    let skanninger = ScanJobs::new(konfig.scan_concurrency);
+    start_overvaking(pool.clone(), innlevering, skanninger.clone());
+
+    info!("Lagrer omslag i {:?}", konfig.cache_path);
+    info!("Bruker web-ui fra {:?}", konfig.web_ui_path);
+    if !konfig.web_ui_path.join("index.html").exists() {
+        warn!(
+            "Fant ikke index.html i {:?}, web-grensesnittet er ikke bygget",
+            konfig.web_ui_path
+        );
+    }
     let web_ui_mappe = ServeDir::new(&konfig.web_ui_path)
         .not_found_service(ServeFile::new(&konfig.web_ui_path.join("index.html")));
 
@@ -98,37 +201,126 @@ pub async fn kjerne_pakker(konfig: Konfig) {
         .merge(SwaggerUi::new("/swagger").url("/api-docs/openapi.json", open_api))
         .route("/api/v1/auth/register", post(auth::register))
         .route("/api/v1/auth/login", post(auth::login))
+        .route("/api/v1/auth/logout", post(auth::logout))
+        .route("/api/v1/auth/sessions", get(auth::get_sessions))
+        .route("/api/v1/auth/sessions/:id", delete(auth::delete_session))
+        .route("/api/v1/invites", post(invites::add_invite))
+        .route("/api/v1/invites", get(invites::get_invites))
+        .route("/api/v1/invites/:id", delete(invites::delete_invite))
+        .route("/api/v1/me", get(users::get_me))
+        .route("/api/v1/me", patch(users::update_me))
+        .route("/api/v1/me/password", put(users::change_password))
+        .route("/api/v1/users", get(users::get_users))
+        .route("/api/v1/users/:id/password", put(users::reset_password))
+        .route("/api/v1/users/:id", delete(users::delete_user))
         .route("/hello", get(hello::root))
         .route("/api/v1/library", post(library::add_library))
         .route("/api/v1/library", get(library::get_libraries))
         .route("/api/v1/library/:id", delete(library::delete_library))
         .route("/api/v1/library/scan", post(library::scan_library))
+        .route("/api/v1/library/scan/:job_id", get(library::get_scan_job))
+        .route(
+            "/api/v1/library/:id/access",
+            get(library::get_library_access),
+        )
+        .route(
+            "/api/v1/library/:id/access/:user_id",
+            put(library::grant_library_access),
+        )
+        .route(
+            "/api/v1/library/:id/access/:user_id",
+            delete(library::revoke_library_access),
+        )
+        .route("/api/v1/folder", post(folders::add_folder))
+        .route("/api/v1/folder", get(folders::get_folders))
+        .route("/api/v1/folder/:id", get(folders::get_folder))
+        .route("/api/v1/folder/:id", put(folders::update_folder))
+        .route("/api/v1/folder/:id", delete(folders::delete_folder))
         .route("/api/v1/book", get(books::get_books))
         .route("/api/v1/book/:id", get(books::get_book))
+        .route("/api/v1/book/:id/toc", get(books::get_book_toc))
+        .route("/api/v1/book/:id/progress", get(books::get_book_progress))
+        .route("/api/v1/book/:id/progress", put(books::sync_book_progress))
+        .route("/api/v1/book/:id/search", get(books::search_book))
+        .route("/api/v1/book/:id/download", get(books::download_book))
+        .route("/api/v1/book/:id/highlights", post(books::add_highlight))
+        .route("/api/v1/book/:id/highlights", get(books::get_highlights))
+        .route(
+            "/api/v1/book/:id/highlights/export",
+            get(books::export_highlights),
+        )
+        .route(
+            "/api/v1/book/:id/highlights/:highlight_id",
+            patch(books::update_highlight),
+        )
+        .route(
+            "/api/v1/book/:id/highlights/:highlight_id",
+            delete(books::delete_highlight),
+        )
+        .route("/api/v1/book/:id/bookmarks", post(bookmarks::add_bookmark))
+        .route("/api/v1/book/:id/bookmarks", get(bookmarks::get_bookmarks))
+        .route(
+            "/api/v1/book/:id/bookmarks/:bookmark_id",
+            patch(bookmarks::rename_bookmark),
+        )
+        .route(
+            "/api/v1/book/:id/bookmarks/:bookmark_id",
+            delete(bookmarks::delete_bookmark),
+        )
         .route("/api/v1/book/:id/page/:page_num", get(books::get_book_page))
         .route(
             "/api/v1/book/:id/resource/*path",
             get(books::get_book_resource),
         )
         .route("/api/v1/images/covers/:id", get(images::get_cover))
+        .route("/api/v1/events", get(events::get_events))
+        .route("/api/v1/search", get(search::search))
+        .route("/opds", get(opds::root))
+        .route("/opds/libraries", get(opds::get_libraries))
+        .route("/opds/libraries/:id", get(opds::get_library))
+        .route("/opds/libraries/:id/books", get(opds::get_library_books))
+        .route("/opds/collections/:id", get(opds::get_collection))
+        .route("/opds/authors", get(opds::get_authors))
+        .route("/opds/authors/:id", get(opds::get_author))
+        .route("/opds/recent", get(opds::get_recent))
+        .route("/opds/search", get(opds::search))
+        .route("/opds/opensearch.xml", get(opds::opensearch))
+        .route("/opds/books/:id/download", get(opds::download_book))
         .nest_service("/", web_ui_mappe.clone())
         .fallback_service(web_ui_mappe)
         .with_state(pool.clone());
 
+    let ruter = if konfig.path_prefix.is_empty() {
+        ruter
+    } else {
+        info!("Serverer under {}", konfig.path_prefix);
+        Router::new().nest(&konfig.path_prefix, ruter)
+    };
+
+    let ruter = ruter
+        .layer(Extension(PublicUrl {
+            url: konfig.public_url.clone(),
+            trust_proxy_headers: konfig.trust_proxy_headers,
+            path_prefix: konfig.path_prefix.clone(),
+        }))
+        .layer(Extension(konfig.registration))
+        .layer(Extension(skanninger))
+        .layer(TraceLayer::new_for_http());
+
     let web_fremtid = web::serve(konfig.server_address, ruter);
 
     let terminal = terminal();
 
     let terminal_fremtid = hent_termianl_meldinger(terminal);
 
-    println!("Lytter på nettverkssocket på {}", konfig.server_address);
+    info!("Lytter på nettverkssocket på {}", konfig.server_address);
 
     select! {
         _ = web_fremtid => {},
         _ = terminal_fremtid => {
-            println!("Fikk stopp-melding fra terminal, avslutter...");
+            info!("Fikk stopp-melding fra terminal, avslutter...");
             pool.close().await;
-            println!("Stengte databasetilkobling, avsluttning ferdig.");
+            info!("Stengte databasetilkobling, avsluttning ferdig.");
         },
     }
 }
@@ -0,0 +1,413 @@
+use crate::Konfig;
+use clap::{Subcommand, ValueEnum};
+use database::folders::{DropType, InsertableFolder};
+use database::library::{Book, InsertableLibrary, Library};
+use database::users::{hash_password, BearerToken, InsertableInvite, InsertableUser, Role, User};
+use database::MigrationError;
+use scanner::jobs::ScanJobs;
+use scanner::scanner::{regenerate_cover, ScanError};
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{Pool, Sqlite};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+
+// Maintenance without the web UI. The commands work on the database directly,
+// so they can run while the server is stopped.
+#[derive(Subcommand, Debug)]
+pub enum Kommando {
+    /// Starter serveren, det samme som uten kommando
+    Serve,
+    /// Brukere
+    #[command(subcommand)]
+    User(BrukerKommando),
+    /// Biblioteker
+    #[command(subcommand)]
+    Library(BibliotekKommando),
+    /// Databasen
+    #[command(subcommand)]
+    Db(DatabaseKommando),
+    /// Omslag
+    #[command(subcommand)]
+    Covers(OmslagKommando),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BrukerKommando {
+    /// Legger til en bruker, den første brukeren blir alltid admin
+    Add {
+        username: String,
+        /// Leses fra terminalen om det mangler
+        #[arg(long)]
+        password: Option<String>,
+        #[arg(long, value_enum, default_value_t = Rolle::Member)]
+        role: Rolle,
+    },
+    /// Gir en bruker en ny rolle
+    SetRole {
+        username: String,
+        #[arg(value_enum)]
+        role: Rolle,
+    },
+    /// Lager en invitasjonskode som kan brukes én gang
+    Invite {
+        #[arg(long, value_enum, default_value_t = Rolle::Member)]
+        role: Rolle,
+    },
+    /// Setter et nytt passord og logger brukeren ut overalt
+    ResetPassword {
+        username: String,
+        /// Leses fra terminalen om det mangler
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Sletter en bruker med bokmerker, uthevinger og progresjon
+    Delete { username: String },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Rolle {
+    /// Styrer biblioteker, mapper og invitasjoner
+    Admin,
+    Member,
+    /// Kan lese og laste ned, men ikke lagre progresjon, bokmerker eller uthevinger
+    ReadOnly,
+}
+
+impl From<Rolle> for Role {
+    fn from(rolle: Rolle) -> Self {
+        match rolle {
+            Rolle::Admin => Role::Admin,
+            Rolle::Member => Role::Member,
+            Rolle::ReadOnly => Role::ReadOnly,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BibliotekKommando {
+    /// Legger til en mappe som bibliotek
+    Add {
+        path: PathBuf,
+        /// Navnet på biblioteket, standard er navnet på mappen
+        #[arg(long)]
+        name: Option<String>,
+        /// Ikke se etter endringer i mappen mens serveren kjører
+        #[arg(long)]
+        no_watch: bool,
+    },
+    /// Viser alle biblioteker
+    List,
+    /// Skanner et bibliotek og venter til skanningen er ferdig
+    Scan { id: i32 },
+    /// Fjerner et bibliotek fra databasen, bøkene blir liggende på disken
+    Remove { id: i32 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DatabaseKommando {
+    /// Kjører migreringer som ikke er kjørt
+    Migrate,
+    /// Sjekker databasefilen, fremmednøkler og migreringer
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OmslagKommando {
+    /// Henter omslagene ut av bøkene på nytt
+    Regenerate,
+}
+
+pub struct KommandoFeil(String);
+
+impl fmt::Display for KommandoFeil {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<sqlx::Error> for KommandoFeil {
+    fn from(feil: sqlx::Error) -> Self {
+        KommandoFeil(format!("Databasefeil: {}", feil))
+    }
+}
+
+impl From<MigrationError> for KommandoFeil {
+    fn from(feil: MigrationError) -> Self {
+        KommandoFeil(format!("Kunne ikke migrere databasen: {}", feil))
+    }
+}
+
+impl From<ScanError> for KommandoFeil {
+    fn from(feil: ScanError) -> Self {
+        KommandoFeil(format!("Skanningen feilet: {}", feil))
+    }
+}
+
+impl From<std::io::Error> for KommandoFeil {
+    fn from(feil: std::io::Error) -> Self {
+        KommandoFeil(feil.to_string())
+    }
+}
+
+pub async fn kjor(kommando: Kommando, konfig: &Konfig) -> Result<(), KommandoFeil> {
+    // A single connection runs the statements in order, so the last write is done before the pool closes
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(database::connect_options(&konfig.database_path)?)
+        .await?;
+
+    // Check has to see the database as it is, the other commands need the tables
+    let resultat = match kommando {
+        Kommando::Db(kommando) => db(kommando, &pool).await,
+        kommando => match database::migrate(&pool).await {
+            Ok(_) => kjor_migrert(kommando, &pool).await,
+            Err(feil) => Err(feil.into()),
+        },
+    };
+
+    pool.close().await;
+    resultat
+}
+
+async fn kjor_migrert(kommando: Kommando, pool: &Pool<Sqlite>) -> Result<(), KommandoFeil> {
+    match kommando {
+        Kommando::Serve => unreachable!("serve håndteres i main"),
+        Kommando::User(kommando) => bruker(kommando, pool).await,
+        Kommando::Library(kommando) => bibliotek(kommando, pool).await,
+        Kommando::Db(kommando) => db(kommando, pool).await,
+        Kommando::Covers(OmslagKommando::Regenerate) => omslag(pool).await,
+    }
+}
+
+fn les_passord(passord: Option<String>) -> Result<String, KommandoFeil> {
+    let passord = match passord {
+        Some(passord) => passord,
+        None => {
+            print!("Passord: ");
+            std::io::stdout().flush()?;
+            let mut passord = String::new();
+            std::io::stdin().read_line(&mut passord)?;
+            passord.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    if passord.is_empty() {
+        return Err(KommandoFeil("Passordet kan ikke være tomt".to_string()));
+    }
+    Ok(passord)
+}
+
+async fn finn_bruker(brukernavn: &str, pool: &Pool<Sqlite>) -> Result<User, KommandoFeil> {
+    User::find_by_username(brukernavn, pool)
+        .await?
+        .ok_or_else(|| KommandoFeil(format!("Fant ingen bruker som heter {}", brukernavn)))
+}
+
+async fn bruker(kommando: BrukerKommando, pool: &Pool<Sqlite>) -> Result<(), KommandoFeil> {
+    match kommando {
+        BrukerKommando::Add {
+            username,
+            password,
+            role,
+        } => {
+            if User::find_by_username(&username, pool).await?.is_some() {
+                return Err(KommandoFeil(format!(
+                    "Brukeren {} finnes allerede",
+                    username
+                )));
+            }
+            let passord = les_passord(password)?;
+            let bruker = InsertableUser {
+                username,
+                hashed_password: hash_password(&passord),
+                role: role.into(),
+            }
+            .insert(pool)
+            .await?;
+            println!(
+                "La til {} med id {} som {:?}",
+                bruker.username, bruker.id, bruker.role
+            );
+        }
+        BrukerKommando::SetRole { username, role } => {
+            let mut bruker = finn_bruker(&username, pool).await?;
+            bruker.set_role(role.into(), pool).await?;
+            println!("{} er nå {:?}", bruker.username, bruker.role);
+        }
+        BrukerKommando::Invite { role } => {
+            let invitasjon = InsertableInvite {
+                role: role.into(),
+                created_by: None,
+            }
+            .insert(pool)
+            .await?;
+            println!("{}", invitasjon.code);
+        }
+        BrukerKommando::ResetPassword { username, password } => {
+            let mut bruker = finn_bruker(&username, pool).await?;
+            let passord = les_passord(password)?;
+            bruker.set_password(&passord, pool).await?;
+            BearerToken::delete_by_user(bruker.id, pool).await?;
+            println!("Byttet passord for {}", bruker.username);
+        }
+        BrukerKommando::Delete { username } => {
+            let bruker = finn_bruker(&username, pool).await?;
+            bruker.delete_self(pool).await?;
+            println!("Slettet {}", username);
+        }
+    }
+
+    Ok(())
+}
+
+async fn bibliotek(kommando: BibliotekKommando, pool: &Pool<Sqlite>) -> Result<(), KommandoFeil> {
+    match kommando {
+        BibliotekKommando::Add {
+            path,
+            name,
+            no_watch,
+        } => {
+            let sti = path.canonicalize().map_err(|feil| {
+                KommandoFeil(format!("Kunne ikke åpne mappen {:?}: {}", path, feil))
+            })?;
+            if !sti.is_dir() {
+                return Err(KommandoFeil(format!("{:?} er ikke en mappe", sti)));
+            }
+            let sti = sti
+                .to_str()
+                .ok_or_else(|| KommandoFeil(format!("{:?} er ikke gyldig unicode", sti)))?
+                .to_string();
+            if Library::get_by_path(&sti, pool).await?.is_some() {
+                return Err(KommandoFeil(format!("{} er allerede et bibliotek", sti)));
+            }
+
+            let name = name.unwrap_or_else(|| {
+                PathBuf::from(&sti)
+                    .file_name()
+                    .map(|navn| navn.to_string_lossy().to_string())
+                    .unwrap_or_else(|| sti.clone())
+            });
+            let bibliotek = InsertableLibrary {
+                path: sti.clone(),
+                name: name.clone(),
+            }
+            .insert(pool)
+            .await?;
+            // The watcher finds libraries through their folder
+            InsertableFolder {
+                nickname: name,
+                path: sti,
+                watch: !no_watch,
+                drop_type: DropType::None,
+                destination_id: None,
+            }
+            .insert(pool)
+            .await?;
+            println!(
+                "La til {} ({}) med id {}",
+                bibliotek.name, bibliotek.path, bibliotek.id
+            );
+        }
+        BibliotekKommando::List => {
+            for bibliotek in Library::get_libraries(pool).await? {
+                let boker = Book::get_books_by_library(bibliotek.id, pool).await?.len();
+                println!(
+                    "{}\t{}\t{}\t{} bøker",
+                    bibliotek.id, bibliotek.name, bibliotek.path, boker
+                );
+            }
+        }
+        BibliotekKommando::Scan { id } => {
+            let bibliotek = finn_bibliotek(id, pool).await?;
+            println!("Skanner {}", bibliotek.path);
+            // Goes through the same jobs as the server, so it shows up there and never runs alongside another scan
+            let jobb = ScanJobs::new(1).run_scan(bibliotek.id, pool).await?;
+            if let Some(feil) = &jobb.error {
+                return Err(KommandoFeil(format!("Skanningen feilet: {}", feil)));
+            }
+            println!(
+                "{} funnet, {} importert, {} fjernet, {} uendret, {} feilet",
+                jobb.discovered, jobb.imported, jobb.removed, jobb.skipped, jobb.failed
+            );
+            for feil in jobb.get_errors(pool).await? {
+                println!("Kunne ikke lese {}: {}", feil.path, feil.message);
+            }
+        }
+        BibliotekKommando::Remove { id } => {
+            let bibliotek = finn_bibliotek(id, pool).await?;
+            bibliotek.delete_self(pool).await?;
+            println!("Fjernet bibliotek {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+async fn finn_bibliotek(id: i32, pool: &Pool<Sqlite>) -> Result<Library, KommandoFeil> {
+    match Library::get_library(id, pool).await {
+        Ok(bibliotek) => Ok(bibliotek),
+        Err(sqlx::Error::RowNotFound) => Err(KommandoFeil(format!("Fant ikke bibliotek {}", id))),
+        Err(feil) => Err(feil.into()),
+    }
+}
+
+async fn db(kommando: DatabaseKommando, pool: &Pool<Sqlite>) -> Result<(), KommandoFeil> {
+    match kommando {
+        DatabaseKommando::Migrate => {
+            let kjort = database::migrate(pool).await?;
+            for migrering in database::MIGRATOR.iter() {
+                if kjort.contains(&migrering.version) {
+                    println!("Kjørte {} {}", migrering.version, migrering.description);
+                }
+            }
+            println!("Databasen er oppdatert, {} migreringer kjørt", kjort.len());
+        }
+        DatabaseKommando::Check => {
+            let sjekk = database::maintenance::check(pool).await?;
+            for feil in &sjekk.integrity_errors {
+                println!("Feil i databasefilen: {}", feil);
+            }
+            for feil in &sjekk.foreign_key_errors {
+                println!("Peker til en rad som ikke finnes: {}", feil);
+            }
+            for migrering in &sjekk.pending_migrations {
+                println!("Ikke kjørt: {}", migrering);
+            }
+            for versjon in &sjekk.unknown_migrations {
+                println!(
+                    "Ukjent migrering {}, databasen er nyere enn programmet",
+                    versjon
+                );
+            }
+            if !sjekk.is_ok() {
+                return Err(KommandoFeil("Fant feil i databasen".to_string()));
+            }
+            println!("Fant ingen feil");
+        }
+    }
+
+    Ok(())
+}
+
+async fn omslag(pool: &Pool<Sqlite>) -> Result<(), KommandoFeil> {
+    let (mut med_omslag, mut uten_omslag, mut feilet) = (0, 0, 0);
+
+    for bok in Book::get_books(pool).await? {
+        match regenerate_cover(bok.id, pool).await {
+            Ok(true) => med_omslag += 1,
+            Ok(false) => uten_omslag += 1,
+            Err(feil) => {
+                feilet += 1;
+                println!("Kunne ikke hente omslaget til {}: {}", bok.name, feil);
+            }
+        }
+    }
+
+    println!(
+        "{} omslag hentet, {} bøker uten omslag, {} feilet",
+        med_omslag, uten_omslag, feilet
+    );
+
+    Ok(())
+}
@@ -1,39 +1,456 @@
+use crate::kommandoer::Kommando;
+use clap::{Args, Parser};
+use serde::Deserialize;
+use std::fmt;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::Level;
+use web::endepunkter::auth::Registration;
 
+const STANDARD_ADRESSE: &str = "127.0.0.1:8273";
+const STANDARD_KONFIGFIL: &str = "config.toml";
+const STANDARD_SKANNINGER: usize = 2;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "En epub-leser i nettleseren")]
+pub struct Argumenter {
+    /// Konfigurasjonsfil i TOML, leser config.toml fra mappen serveren startes i om den finnes
+    #[arg(long, short, value_name = "FIL", env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub innstillinger: Innstillinger,
+
+    #[command(subcommand)]
+    pub kommando: Option<Kommando>,
+}
+
+// Every setting can come from the config file, an environment variable or the command line.
+// The command line wins over the environment, which wins over the file.
+#[derive(Args, Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Innstillinger {
+    /// Adressen serveren lytter på [standard: 127.0.0.1:8273]
+    #[arg(long = "bind", value_name = "ADRESSE", global = true)]
+    pub bind_address: Option<String>,
+
+    /// SQLite-databasen, som en sqlite:// url eller en sti [standard: <data-dir>/database.db]
+    #[arg(long, value_name = "URL", global = true)]
+    pub database_url: Option<String>,
+
+    /// Mappen med det bygde web-grensesnittet [standard: <data-dir>/web-ui/dist]
+    #[arg(long, value_name = "MAPPE", global = true)]
+    pub web_ui_path: Option<PathBuf>,
+
+    /// Mappen data lagres i [standard: mappen serveren startes i]
+    #[arg(long, value_name = "MAPPE", global = true)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Mappen omslag og annet som kan lages på nytt lagres i [standard: <data-dir>/covers]
+    #[arg(long, value_name = "MAPPE", global = true)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Adressen serveren nås på utenfra, som https://eksempel.no/bøker. Uten den brukes Host-headeren
+    #[arg(long, value_name = "URL", global = true)]
+    pub public_url: Option<String>,
+
+    /// Stol på X-Forwarded-headerne når public-url ikke er satt, bare bak en proxy som setter dem [standard: false]
+    #[arg(long, value_name = "true|false", global = true)]
+    pub trust_proxy_headers: Option<bool>,
+
+    /// Serverer alt under denne stien, som /bøker
+    #[arg(long, value_name = "STI", global = true)]
+    pub path_prefix: Option<String>,
+
+    /// error, warn, info, debug eller trace [standard: info]
+    #[arg(long, value_name = "NIVÅ", global = true)]
+    pub log_level: Option<String>,
+
+    /// Hvor mange biblioteker som kan skannes samtidig [standard: 2]
+    #[arg(long, value_name = "ANTALL", global = true)]
+    pub scan_concurrency: Option<usize>,
+
+    /// Hvor bøker som slippes i en kildemappe legges i målmappen, se scanner::ingest
+    #[arg(long, value_name = "MØNSTER", global = true)]
+    pub drop_pattern: Option<String>,
+
+    /// Mappen bøker som ikke kan leses flyttes til [standard: <data-dir>/quarantine]
+    #[arg(long, value_name = "MAPPE", global = true)]
+    pub quarantine_path: Option<PathBuf>,
+
+    /// open, invite eller closed, den første brukeren kan alltid registrere seg [standard: open]
+    #[arg(long, value_name = "HVEM", global = true)]
+    pub registration: Option<String>,
+}
+
+impl Innstillinger {
+    fn fra_fil(sti: &Path) -> Result<Innstillinger, KonfigFeil> {
+        let innhold = std::fs::read_to_string(sti)
+            .map_err(|feil| KonfigFeil::LesFil(sti.to_path_buf(), feil))?;
+        toml::from_str(&innhold).map_err(|feil| KonfigFeil::Fil(sti.to_path_buf(), feil))
+    }
+
+    fn fra_miljo() -> Result<Innstillinger, KonfigFeil> {
+        let variabel = |navn: &str| std::env::var(navn).ok().filter(|verdi| !verdi.is_empty());
+
+        let trust_proxy_headers = match variabel("TRUST_PROXY_HEADERS") {
+            Some(verdi) => Some(verdi.parse().map_err(|_| KonfigFeil::UgyldigVerdi {
+                navn: "TRUST_PROXY_HEADERS",
+                verdi,
+                forventet: "true eller false",
+            })?),
+            None => None,
+        };
+
+        let scan_concurrency = match variabel("SCAN_CONCURRENCY") {
+            Some(verdi) => Some(verdi.parse().map_err(|_| KonfigFeil::UgyldigVerdi {
+                navn: "SCAN_CONCURRENCY",
+                verdi,
+                forventet: "et positivt heltall",
+            })?),
+            None => None,
+        };
+
+        Ok(Innstillinger {
+            bind_address: variabel("BIND_ADDRESS"),
+            database_url: variabel("DATABASE_URL"),
+            web_ui_path: variabel("WEB_UI_PATH").map(PathBuf::from),
+            // INSTALLATION_PATH is the old name
+            data_dir: variabel("DATA_DIR")
+                .or_else(|| variabel("INSTALLATION_PATH"))
+                .map(PathBuf::from),
+            cache_dir: variabel("CACHE_DIR").map(PathBuf::from),
+            public_url: variabel("PUBLIC_URL"),
+            trust_proxy_headers,
+            path_prefix: variabel("PATH_PREFIX"),
+            log_level: variabel("LOG_LEVEL"),
+            scan_concurrency,
+            drop_pattern: variabel("DROP_PATTERN"),
+            quarantine_path: variabel("QUARANTINE_PATH").map(PathBuf::from),
+            registration: variabel("REGISTRATION"),
+        })
+    }
+
+    // The values in over win
+    fn flett(self, over: Innstillinger) -> Innstillinger {
+        Innstillinger {
+            bind_address: over.bind_address.or(self.bind_address),
+            database_url: over.database_url.or(self.database_url),
+            web_ui_path: over.web_ui_path.or(self.web_ui_path),
+            data_dir: over.data_dir.or(self.data_dir),
+            cache_dir: over.cache_dir.or(self.cache_dir),
+            public_url: over.public_url.or(self.public_url),
+            trust_proxy_headers: over.trust_proxy_headers.or(self.trust_proxy_headers),
+            path_prefix: over.path_prefix.or(self.path_prefix),
+            log_level: over.log_level.or(self.log_level),
+            scan_concurrency: over.scan_concurrency.or(self.scan_concurrency),
+            drop_pattern: over.drop_pattern.or(self.drop_pattern),
+            quarantine_path: over.quarantine_path.or(self.quarantine_path),
+            registration: over.registration.or(self.registration),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KonfigFeil {
+    LesFil(PathBuf, std::io::Error),
+    Fil(PathBuf, toml::de::Error),
+    UgyldigVerdi {
+        navn: &'static str,
+        verdi: String,
+        forventet: &'static str,
+    },
+    Mappe(PathBuf, std::io::Error),
+}
+
+impl fmt::Display for KonfigFeil {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KonfigFeil::LesFil(sti, feil) => {
+                write!(f, "kunne ikke lese konfigurasjonsfilen {:?}: {}", sti, feil)
+            }
+            KonfigFeil::Fil(sti, feil) => {
+                write!(f, "feil i konfigurasjonsfilen {:?}: {}", sti, feil)
+            }
+            KonfigFeil::UgyldigVerdi {
+                navn,
+                verdi,
+                forventet,
+            } => write!(
+                f,
+                "ugyldig verdi {:?} for {}, forventet {}",
+                verdi, navn, forventet
+            ),
+            KonfigFeil::Mappe(sti, feil) => {
+                write!(f, "kunne ikke opprette mappen {:?}: {}", sti, feil)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Konfig {
     pub server_address: SocketAddr,
     pub database_path: String,
     pub web_ui_path: PathBuf,
-    pub installation_path: PathBuf,
+    pub data_path: PathBuf,
+    // Covers and anything else that can be made again from the books
+    pub cache_path: PathBuf,
+    // Where books dropped into a source folder are placed in the destination, see scanner::ingest
+    pub drop_pattern: String,
+    pub quarantine_path: PathBuf,
+    // Set when the server is behind a proxy that doesn't send X-Forwarded headers
+    pub public_url: Option<String>,
+    // Any client can send X-Forwarded headers, so they are only used when a proxy is known to set them
+    pub trust_proxy_headers: bool,
+    // Serve everything under this path, like /reader, or empty to serve from the root
+    pub path_prefix: String,
+    pub log_level: Level,
+    pub scan_concurrency: usize,
+    // Who can register when there already are users
+    pub registration: Registration,
 }
 
 impl Konfig {
-    pub fn new() -> Konfig {
-        let installation_path = std::env::var("INSTALLATION_PATH")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| std::env::current_dir().unwrap());
+    // Defaults, then the config file, then environment variables and last the command line
+    pub fn last(
+        config: Option<PathBuf>,
+        kommandolinje: Innstillinger,
+    ) -> Result<Konfig, KonfigFeil> {
+        let fil = match config {
+            Some(sti) => Innstillinger::fra_fil(&sti)?,
+            None if Path::new(STANDARD_KONFIGFIL).exists() => {
+                Innstillinger::fra_fil(Path::new(STANDARD_KONFIGFIL))?
+            }
+            None => Innstillinger::default(),
+        };
+
+        let innstillinger = fil.flett(Innstillinger::fra_miljo()?).flett(kommandolinje);
+
+        let konfig = Konfig::fra_innstillinger(innstillinger)?;
+
+        for mappe in [&konfig.data_path, &konfig.cache_path] {
+            std::fs::create_dir_all(mappe)
+                .map_err(|feil| KonfigFeil::Mappe(mappe.clone(), feil))?;
+        }
 
-        let database_path = std::env::var("DATABASE_URL")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| installation_path.join("database.db"))
-            .to_str()
-            .unwrap()
-            .to_string();
+        Ok(konfig)
+    }
+
+    fn fra_innstillinger(innstillinger: Innstillinger) -> Result<Konfig, KonfigFeil> {
+        let bind_address = innstillinger
+            .bind_address
+            .unwrap_or_else(|| STANDARD_ADRESSE.to_string());
+        let server_address = bind_address.parse().map_err(|_| KonfigFeil::UgyldigVerdi {
+            navn: "bind_address",
+            verdi: bind_address.clone(),
+            forventet: "en adresse med port, som 127.0.0.1:8273",
+        })?;
+
+        let data_path = match innstillinger.data_dir {
+            Some(data_dir) => data_dir,
+            None => std::env::current_dir()
+                .map_err(|feil| KonfigFeil::Mappe(PathBuf::from("."), feil))?,
+        };
+
+        let database_path = match innstillinger.database_url {
+            Some(url) if url.starts_with("sqlite:") => url,
+            Some(url) if url.contains("://") => {
+                return Err(KonfigFeil::UgyldigVerdi {
+                    navn: "database_url",
+                    verdi: url,
+                    forventet: "en sqlite:// url eller en sti til en fil",
+                })
+            }
+            Some(sti) => format!("sqlite://{}", sti),
+            None => format!("sqlite://{}", data_path.join("database.db").display()),
+        };
 
-        let database_path = format!("sqlite://{}", database_path);
+        let public_url = match innstillinger.public_url {
+            Some(url) if url.trim().is_empty() => None,
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                Some(url.trim_end_matches('/').to_string())
+            }
+            Some(url) => {
+                return Err(KonfigFeil::UgyldigVerdi {
+                    navn: "public_url",
+                    verdi: url,
+                    forventet: "en url som begynner med http:// eller https://",
+                })
+            }
+            None => None,
+        };
 
-        let web_ui_path = std::env::var("WEB_UI_PATH")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| installation_path.join("web-ui/dist"));
+        let log_level = match innstillinger.log_level {
+            Some(nivaa) => nivaa.parse().map_err(|_| KonfigFeil::UgyldigVerdi {
+                navn: "log_level",
+                verdi: nivaa,
+                forventet: "error, warn, info, debug eller trace",
+            })?,
+            None => Level::INFO,
+        };
 
-        let server_address = "127.0.0.1:8273".parse().unwrap();
+        let scan_concurrency = innstillinger
+            .scan_concurrency
+            .unwrap_or(STANDARD_SKANNINGER);
+        if scan_concurrency == 0 {
+            return Err(KonfigFeil::UgyldigVerdi {
+                navn: "scan_concurrency",
+                verdi: scan_concurrency.to_string(),
+                forventet: "minst 1",
+            });
+        }
+
+        let registration = match innstillinger.registration.as_deref() {
+            None | Some("open") => Registration::Open,
+            Some("invite") => Registration::Invite,
+            Some("closed") => Registration::Closed,
+            Some(verdi) => {
+                return Err(KonfigFeil::UgyldigVerdi {
+                    navn: "registration",
+                    verdi: verdi.to_string(),
+                    forventet: "open, invite eller closed",
+                })
+            }
+        };
 
-        Konfig {
+        Ok(Konfig {
             server_address,
             database_path,
-            web_ui_path,
-            installation_path,
-        }
+            web_ui_path: innstillinger
+                .web_ui_path
+                .unwrap_or_else(|| data_path.join("web-ui/dist")),
+            cache_path: innstillinger
+                .cache_dir
+                .unwrap_or_else(|| data_path.join("covers")),
+            drop_pattern: innstillinger
+                .drop_pattern
+                .unwrap_or_else(|| scanner::ingest::DEFAULT_PATTERN.to_string()),
+            quarantine_path: innstillinger
+                .quarantine_path
+                .unwrap_or_else(|| data_path.join("quarantine")),
+            public_url,
+            trust_proxy_headers: innstillinger.trust_proxy_headers.unwrap_or(false),
+            path_prefix: normalize_path_prefix(&innstillinger.path_prefix.unwrap_or_default()),
+            log_level,
+            scan_concurrency,
+            registration,
+            data_path,
+        })
+    }
+}
+
+// "reader", "/reader/" and "/reader" all become "/reader", and "/" becomes ""
+fn normalize_path_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        return String::new();
+    }
+    format!("/{}", prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standardverdier() {
+        let konfig = Konfig::fra_innstillinger(Innstillinger {
+            data_dir: Some(PathBuf::from("/data")),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(konfig.server_address.to_string(), STANDARD_ADRESSE);
+        assert_eq!(konfig.database_path, "sqlite:///data/database.db");
+        assert_eq!(konfig.web_ui_path, PathBuf::from("/data/web-ui/dist"));
+        assert_eq!(konfig.cache_path, PathBuf::from("/data/covers"));
+        assert_eq!(konfig.log_level, Level::INFO);
+        assert_eq!(konfig.path_prefix, "");
+        assert_eq!(konfig.registration, Registration::Open);
+        assert!(!konfig.trust_proxy_headers);
+    }
+
+    #[test]
+    fn test_lag() {
+        let fil: Innstillinger = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0:80"
+            log_level = "debug"
+            scan_concurrency = 4
+            path_prefix = "bøker/"
+            registration = "invite"
+            trust_proxy_headers = true
+            "#,
+        )
+        .unwrap();
+        let miljo = Innstillinger {
+            log_level: Some("warn".to_string()),
+            ..Default::default()
+        };
+        let kommandolinje = Innstillinger {
+            bind_address: Some("127.0.0.1:9000".to_string()),
+            data_dir: Some(PathBuf::from("/data")),
+            ..Default::default()
+        };
+
+        let konfig = Konfig::fra_innstillinger(fil.flett(miljo).flett(kommandolinje)).unwrap();
+
+        assert_eq!(konfig.server_address.to_string(), "127.0.0.1:9000");
+        assert_eq!(konfig.log_level, Level::WARN);
+        assert_eq!(konfig.scan_concurrency, 4);
+        assert_eq!(konfig.path_prefix, "/bøker");
+        assert_eq!(konfig.registration, Registration::Invite);
+        assert!(konfig.trust_proxy_headers);
+    }
+
+    #[test]
+    fn test_ugyldige_verdier() {
+        let ugyldig =
+            |innstillinger: Innstillinger| match Konfig::fra_innstillinger(Innstillinger {
+                data_dir: Some(PathBuf::from("/data")),
+                ..innstillinger
+            }) {
+                Err(KonfigFeil::UgyldigVerdi { navn, .. }) => navn,
+                _ => panic!("Forventet en ugyldig verdi"),
+            };
+
+        assert_eq!(
+            ugyldig(Innstillinger {
+                bind_address: Some("localhost".to_string()),
+                ..Default::default()
+            }),
+            "bind_address"
+        );
+        assert_eq!(
+            ugyldig(Innstillinger {
+                database_url: Some("postgres://localhost/bøker".to_string()),
+                ..Default::default()
+            }),
+            "database_url"
+        );
+        assert_eq!(
+            ugyldig(Innstillinger {
+                log_level: Some("mye".to_string()),
+                ..Default::default()
+            }),
+            "log_level"
+        );
+        assert_eq!(
+            ugyldig(Innstillinger {
+                scan_concurrency: Some(0),
+                ..Default::default()
+            }),
+            "scan_concurrency"
+        );
+        assert_eq!(
+            ugyldig(Innstillinger {
+                registration: Some("alle".to_string()),
+                ..Default::default()
+            }),
+            "registration"
+        );
+
+        assert!(toml::from_str::<Innstillinger>("port = 80").is_err());
     }
 }
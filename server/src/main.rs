@@ -1,15 +1,45 @@
 mod kjerne;
+mod kommandoer;
 mod konfig;
+mod overvaking;
 mod storer;
 
-use konfig::Konfig;
+use clap::Parser;
+use kommandoer::Kommando;
+use konfig::{Argumenter, Konfig};
 use scanner::set_metadata_path;
 
 #[tokio::main]
 async fn main() {
-    let konfig = Konfig::new();
-    set_metadata_path("./covers".to_owned());
-    scanner::scanner::set_metadata_path("./covers".to_owned());
+    let Argumenter {
+        config,
+        innstillinger,
+        kommando,
+    } = Argumenter::parse();
 
-    kjerne::kjerne_pakker(konfig).await;
+    let konfig = match Konfig::last(config, innstillinger) {
+        Ok(konfig) => konfig,
+        Err(feil) => {
+            eprintln!("Feil i konfigurasjonen: {}", feil);
+            std::process::exit(2);
+        }
+    };
+
+    tracing_subscriber::fmt()
+        .with_max_level(konfig.log_level)
+        .init();
+
+    let omslag = konfig.cache_path.to_string_lossy().to_string();
+    set_metadata_path(omslag.clone());
+    scanner::scanner::set_metadata_path(omslag);
+
+    match kommando {
+        None | Some(Kommando::Serve) => kjerne::kjerne_pakker(konfig).await,
+        Some(kommando) => {
+            if let Err(feil) = kommandoer::kjor(kommando, &konfig).await {
+                eprintln!("{}", feil);
+                std::process::exit(1);
+            }
+        }
+    }
 }
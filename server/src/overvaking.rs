@@ -0,0 +1,288 @@
+use database::folders::{DropType, Folder};
+use database::library::Library;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use scanner::jobs::ScanJobs;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+// Copying a book into a library fires a burst of events, so wait until the library has been quiet
+const STILLE_PERIODE: Duration = Duration::from_secs(2);
+const SJEKK_INTERVALL: Duration = Duration::from_millis(500);
+// The folders table can change while the server runs, so it is read again every so often
+const OPPDATER_INTERVALL: Duration = Duration::from_secs(60);
+
+// What should happen when something changes in a folder
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Mal {
+    // The library with this id is scanned again
+    Bibliotek(i32),
+    // The books in the source folder with this id are moved into its destination
+    Slippmappe(i32),
+}
+
+type OvervaketeMapper = Arc<RwLock<HashMap<PathBuf, Mal>>>;
+
+// Settings for source folders
+#[derive(Clone)]
+pub struct Innlevering {
+    pub monster: String,
+    pub karantene: PathBuf,
+}
+
+pub fn start_overvaking(pool: Pool<Sqlite>, innlevering: Innlevering, skanninger: ScanJobs) {
+    tokio::spawn(async move {
+        if let Err(feil) = overvak(pool, innlevering, skanninger).await {
+            error!("Kunne ikke starte overvåking av mapper: {}", feil);
+        }
+    });
+}
+
+fn relevant_hendelse(hendelse: &Event) -> bool {
+    matches!(
+        hendelse.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    )
+}
+
+async fn overvak(
+    pool: Pool<Sqlite>,
+    innlevering: Innlevering,
+    skanninger: ScanJobs,
+) -> Result<(), notify::Error> {
+    let (sender, mut mottaker) = mpsc::unbounded_channel::<Mal>();
+    let mapper: OvervaketeMapper = Arc::new(RwLock::new(HashMap::new()));
+
+    let hendelse_mapper = mapper.clone();
+    let mut watcher = notify::recommended_watcher(move |resultat: notify::Result<Event>| {
+        let hendelse = match resultat {
+            Ok(hendelse) if relevant_hendelse(&hendelse) => hendelse,
+            _ => return,
+        };
+
+        let mapper = hendelse_mapper.read().unwrap();
+        for sti in &hendelse.paths {
+            // A folder can lie inside another one, the innermost folder decides
+            let mal = mapper
+                .iter()
+                .filter(|(mappe, _)| sti.starts_with(mappe))
+                .max_by_key(|(mappe, _)| mappe.components().count())
+                .map(|(_, mal)| *mal);
+
+            if let Some(mal) = mal {
+                let _ = sender.send(mal);
+            }
+        }
+    })?;
+
+    let mut ventende: HashMap<Mal, Instant> = HashMap::new();
+    let mut sjekk = tokio::time::interval(SJEKK_INTERVALL);
+    let mut oppdater = tokio::time::interval(OPPDATER_INTERVALL);
+
+    loop {
+        select! {
+            Some(mal) = mottaker.recv() => {
+                ventende.insert(mal, Instant::now());
+            }
+            _ = sjekk.tick() => {
+                let klare: Vec<Mal> = ventende
+                    .iter()
+                    .filter(|(_, sist_endret)| sist_endret.elapsed() >= STILLE_PERIODE)
+                    .map(|(mal, _)| *mal)
+                    .collect();
+
+                for mal in klare {
+                    ventende.remove(&mal);
+                    match mal {
+                        Mal::Bibliotek(bibliotek_id) => skann_bibliotek(bibliotek_id, &skanninger, &pool).await,
+                        Mal::Slippmappe(mappe_id) => {
+                            tom_slippmappe(mappe_id, &innlevering, &skanninger, &pool).await
+                        }
+                    }
+                }
+            }
+            _ = oppdater.tick() => {
+                // Books dropped while the server was down, or before the folder was added, are handled right away
+                for mal in oppdater_mapper(&pool, &mut watcher, &mapper).await {
+                    ventende.insert(mal, Instant::now());
+                }
+            }
+        }
+    }
+}
+
+// Events come with absolute paths, while folders can be added with relative ones
+fn absolutt_sti(sti: &str) -> PathBuf {
+    std::fs::canonicalize(sti).unwrap_or_else(|_| PathBuf::from(sti))
+}
+
+// Watches every folder marked with watch that belongs to a library and every source folder,
+// and stops watching the rest. Returns the source folders that were not watched before.
+async fn oppdater_mapper(
+    pool: &Pool<Sqlite>,
+    watcher: &mut RecommendedWatcher,
+    mapper: &OvervaketeMapper,
+) -> Vec<Mal> {
+    let mapper_i_databasen = match Folder::get_folders(pool).await {
+        Ok(mapper) => mapper,
+        Err(feil) => {
+            warn!("Kunne ikke hente mapper som skal overvåkes: {}", feil);
+            return Vec::new();
+        }
+    };
+
+    let mut nye_mapper = HashMap::new();
+    for mappe in mapper_i_databasen {
+        if mappe.drop_type == DropType::Source {
+            nye_mapper.insert(absolutt_sti(&mappe.path), Mal::Slippmappe(mappe.id));
+            continue;
+        }
+
+        if !mappe.watch {
+            continue;
+        }
+
+        match Library::get_by_path(&mappe.path, pool).await {
+            Ok(Some(bibliotek)) => {
+                nye_mapper.insert(absolutt_sti(&mappe.path), Mal::Bibliotek(bibliotek.id));
+            }
+            Ok(None) => (),
+            Err(feil) => warn!("Kunne ikke hente bibliotek for {}: {}", mappe.path, feil),
+        }
+    }
+
+    // The lock can't be held while calling the watcher, since its event thread takes the same lock
+    let gamle_mapper: Vec<PathBuf> = mapper.read().unwrap().keys().cloned().collect();
+
+    for sti in &gamle_mapper {
+        if !nye_mapper.contains_key(sti) {
+            info!("Slutter å overvåke {:?}", sti);
+            let _ = watcher.unwatch(sti);
+        }
+    }
+
+    nye_mapper.retain(|sti, _| {
+        if gamle_mapper.contains(sti) {
+            return true;
+        }
+        match watcher.watch(sti, RecursiveMode::Recursive) {
+            Ok(_) => {
+                info!("Overvåker {:?}", sti);
+                true
+            }
+            Err(feil) => {
+                warn!("Kunne ikke overvåke {:?}: {}", sti, feil);
+                false
+            }
+        }
+    });
+
+    let nye_slippmapper = nye_mapper
+        .iter()
+        .filter(|(sti, mal)| matches!(mal, Mal::Slippmappe(_)) && !gamle_mapper.contains(sti))
+        .map(|(_, mal)| *mal)
+        .collect();
+
+    *mapper.write().unwrap() = nye_mapper;
+
+    nye_slippmapper
+}
+
+// Moves the books in a source folder into its destination library, and scans the library
+async fn tom_slippmappe(
+    mappe_id: i32,
+    innlevering: &Innlevering,
+    skanninger: &ScanJobs,
+    pool: &Pool<Sqlite>,
+) {
+    let kilde = match Folder::get_folder(mappe_id, pool).await {
+        Ok(kilde) => kilde,
+        Err(_) => return,
+    };
+
+    let destinasjon = match kilde.get_destination(pool).await {
+        Ok(Some(destinasjon)) => destinasjon,
+        Ok(None) => {
+            warn!("Fant ingen målmappe for {}", kilde.path);
+            return;
+        }
+        Err(feil) => {
+            warn!("Kunne ikke hente målmappe for {}: {}", kilde.path, feil);
+            return;
+        }
+    };
+
+    let bibliotek = match Library::get_by_path(&destinasjon.path, pool).await {
+        Ok(Some(bibliotek)) => bibliotek,
+        Ok(None) => {
+            warn!("Målmappen {} er ikke et bibliotek", destinasjon.path);
+            return;
+        }
+        Err(feil) => {
+            warn!(
+                "Kunne ikke hente bibliotek for {}: {}",
+                destinasjon.path, feil
+            );
+            return;
+        }
+    };
+
+    let innlevering = innlevering.clone();
+    let kilde_sti = PathBuf::from(&kilde.path);
+    let destinasjon_sti = PathBuf::from(&destinasjon.path);
+    let resultat = tokio::task::spawn_blocking(move || {
+        scanner::ingest::ingest_folder(
+            &kilde_sti,
+            &destinasjon_sti,
+            &innlevering.karantene,
+            &innlevering.monster,
+        )
+    })
+    .await;
+
+    let rapport = match resultat {
+        Ok(Ok(rapport)) => rapport,
+        Ok(Err(feil)) => {
+            warn!("Kunne ikke hente bøker fra {}: {}", kilde.path, feil);
+            return;
+        }
+        Err(feil) => {
+            error!("Hentingen av bøker fra {} stoppet: {}", kilde.path, feil);
+            return;
+        }
+    };
+
+    for (sti, feil) in &rapport.quarantined {
+        warn!("Flyttet {:?} til karantene: {}", sti, feil);
+    }
+    for (sti, feil) in &rapport.failed {
+        warn!("Kunne ikke flytte {:?}: {}", sti, feil);
+    }
+
+    if rapport.imported.is_empty() {
+        return;
+    }
+
+    info!(
+        "Flyttet {} bøker fra {} til {}",
+        rapport.imported.len(),
+        kilde.path,
+        destinasjon.path
+    );
+    skann_bibliotek(bibliotek.id, skanninger, pool).await;
+}
+
+async fn skann_bibliotek(bibliotek_id: i32, skanninger: &ScanJobs, pool: &Pool<Sqlite>) {
+    // Changes that come in while the library is being scanned are picked up by a queued scan
+    if let Err(feil) = skanninger.start_scan(bibliotek_id, pool).await {
+        error!(
+            "Kunne ikke starte skanning av bibliotek {}: {}",
+            bibliotek_id, feil
+        );
+    }
+}
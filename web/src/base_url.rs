@@ -0,0 +1,175 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use hyper::{header, HeaderMap};
+use std::convert::Infallible;
+
+// How the server is reached from the outside, added to the router as an extension.
+// Without it the url is worked out from the headers of each request.
+#[derive(Clone, Default)]
+pub struct PublicUrl {
+    // Like https://books.example.com/reader, the forwarded headers are ignored when it is set
+    pub url: Option<String>,
+    // Only a proxy in front of the server should decide the url, so the X-Forwarded headers are
+    // ignored unless this is set. Any client could send them, and the url ends up in links,
+    // the <base> of book pages and the session cookie.
+    pub trust_proxy_headers: bool,
+    // The path the routes are nested under, like /reader, or empty
+    pub path_prefix: String,
+}
+
+// The public url of the server for the current request, without a trailing slash.
+// Links that leave the api, like the <base> of book pages, have to be built from it.
+pub struct BaseUrl(pub String);
+
+impl BaseUrl {
+    // The path has to start with a slash
+    pub fn join(&self, path: &str) -> String {
+        format!("{}{}", self.0, path)
+    }
+
+    // Only the path, like /reader, or empty when the server is at the root
+    pub fn path(&self) -> &str {
+        let without_scheme = self.0.split_once("://").map_or(&*self.0, |(_, rest)| rest);
+        without_scheme
+            .find('/')
+            .map_or("", |start| &without_scheme[start..])
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.0.starts_with("https://")
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for BaseUrl
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let public_url = parts
+            .extensions
+            .get::<PublicUrl>()
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(BaseUrl(base_url(&public_url, &parts.headers)))
+    }
+}
+
+// A trusted reverse proxy tells where the request came from with the X-Forwarded headers,
+// otherwise the url is the Host header the client connected with
+fn base_url(public_url: &PublicUrl, headers: &HeaderMap) -> String {
+    if let Some(url) = &public_url.url {
+        return url.trim_end_matches('/').to_string();
+    }
+
+    // Proxies in a chain append to the headers, the first value is the one the client used
+    let first_value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    let forwarded = |name: &str| {
+        if public_url.trust_proxy_headers {
+            first_value(name)
+        } else {
+            None
+        }
+    };
+
+    let proto = forwarded("x-forwarded-proto").unwrap_or("http");
+    let host = forwarded("x-forwarded-host")
+        .or_else(|| first_value(header::HOST.as_str()))
+        .unwrap_or("localhost");
+    let forwarded_prefix = forwarded("x-forwarded-prefix")
+        .unwrap_or_default()
+        .trim_end_matches('/');
+
+    format!(
+        "{}://{}{}{}",
+        proto, host, forwarded_prefix, public_url.path_prefix
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_base_url() {
+        let direct = PublicUrl::default();
+        assert_eq!(
+            base_url(&direct, &headers(&[("host", "localhost:8273")])),
+            "http://localhost:8273"
+        );
+
+        let proxied = headers(&[
+            ("host", "127.0.0.1:8273"),
+            ("x-forwarded-proto", "https, http"),
+            ("x-forwarded-host", "books.example.com"),
+            ("x-forwarded-prefix", "/reader/"),
+        ]);
+        let behind_proxy = PublicUrl {
+            trust_proxy_headers: true,
+            ..PublicUrl::default()
+        };
+        assert_eq!(
+            base_url(&behind_proxy, &proxied),
+            "https://books.example.com/reader"
+        );
+
+        let prefixed = PublicUrl {
+            url: None,
+            trust_proxy_headers: false,
+            path_prefix: "/epub".to_string(),
+        };
+        assert_eq!(
+            base_url(&prefixed, &headers(&[("host", "example.com")])),
+            "http://example.com/epub"
+        );
+
+        let configured = PublicUrl {
+            url: Some("https://example.com/books/".to_string()),
+            trust_proxy_headers: true,
+            path_prefix: "/books".to_string(),
+        };
+        assert_eq!(base_url(&configured, &proxied), "https://example.com/books");
+
+        assert_eq!(BaseUrl("https://example.com/books".into()).path(), "/books");
+        assert_eq!(BaseUrl("http://localhost:8273".into()).path(), "");
+    }
+
+    #[test]
+    fn test_untrusted_forwarded_headers() {
+        // A client talking to the server directly can't move the links somewhere else
+        let spoofed = headers(&[
+            ("host", "books.example.com"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "evil.example.com"),
+            ("x-forwarded-prefix", "/phish"),
+        ]);
+        let prefixed = PublicUrl {
+            url: None,
+            trust_proxy_headers: false,
+            path_prefix: "/epub".to_string(),
+        };
+        assert_eq!(
+            base_url(&prefixed, &spoofed),
+            "http://books.example.com/epub"
+        );
+    }
+}
@@ -1,13 +1,21 @@
+use crate::base_url::BaseUrl;
+use crate::{GenericSuccess, ValidatedUser, SESSION_COOKIE};
 use axum::debug_handler;
-use axum::extract::State;
+use axum::extract::{Path, State, TypedHeader};
+use axum::headers::UserAgent;
 use axum::response::IntoResponse;
 use axum::response::Response;
+use axum::Extension;
 use axum::Json;
+use database::users::BearerToken;
 use database::users::Login;
 use database::users::LoginError;
+use database::users::NewBearerToken;
 use database::users::Register;
 use database::users::RegisterError;
-use hyper::StatusCode;
+use database::users::User;
+use database::users::TOKEN_LIFETIME;
+use hyper::{header, StatusCode};
 use serde::Serialize;
 use serde_json::json;
 use sqlx::sqlite::SqlitePool;
@@ -23,23 +31,59 @@ use sqlx::sqlite::SqlitePool;
 #[debug_handler]
 pub async fn register(
     State(pool): State<SqlitePool>,
+    base_url: BaseUrl,
+    registration: Option<Extension<Registration>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(register): Json<Register>,
-) -> Result<Json<AuthBody>, AuthError> {
-    let user_result = register.register(&pool).await;
+) -> Result<impl IntoResponse, AuthError> {
+    let registration = registration
+        .map(|Extension(registration)| registration)
+        .unwrap_or_default();
+
+    // The first user can always register, it becomes the admin
+    let first_user = !User::any_exist(&pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?;
+
+    if !first_user {
+        match registration {
+            Registration::Open => (),
+            Registration::Invite if register.invite_code.is_some() => (),
+            Registration::Invite => return Err(AuthError::InvalidInvite),
+            Registration::Closed => return Err(AuthError::RegistrationClosed),
+        }
+    }
+
+    let user_agent = user_agent.map(|TypedHeader(user_agent)| user_agent.to_string());
+    let user_result = register.register(user_agent, &pool).await;
 
     match user_result {
-        Ok(token) => Ok(Json(AuthBody {
-            bearer_token: token.token,
-        })),
+        Ok(token) => Ok(auth_response(token, &base_url)),
         Err(RegisterError::UsernameTaken) => Err(AuthError::UsernameTaken),
+        Err(RegisterError::InvalidInvite) => Err(AuthError::InvalidInvite),
         Err(RegisterError::DatabaseError(_)) => Err(AuthError::WrongCredentials),
     }
 }
 
+// Who can register when there already are users, set in the server config
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub enum Registration {
+    #[default]
+    Open,
+    // An admin has to make an invite code for every new user
+    Invite,
+    Closed,
+}
+
 pub enum AuthError {
     WrongCredentials,
     UsernameTaken,
     InvalidToken,
+    SessionNotFound,
+    // Logged in, but the role doesn't allow it
+    Forbidden,
+    RegistrationClosed,
+    InvalidInvite,
     InternalError,
 }
 
@@ -49,6 +93,10 @@ impl IntoResponse for AuthError {
             AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
             AuthError::UsernameTaken => (StatusCode::CONFLICT, "Username taken"),
             AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
+            AuthError::SessionNotFound => (StatusCode::NOT_FOUND, "Session not found"),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, "Not allowed"),
+            AuthError::RegistrationClosed => (StatusCode::FORBIDDEN, "Registration is closed"),
+            AuthError::InvalidInvite => (StatusCode::FORBIDDEN, "Invalid invite code"),
             AuthError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
         };
 
@@ -63,6 +111,37 @@ impl IntoResponse for AuthError {
 #[derive(Serialize)]
 pub struct AuthBody {
     bearer_token: String,
+    // Seconds since the unix epoch, moved forward every time the token is used
+    expires_at: i64,
+}
+
+// The token goes in the body for the api calls, and in a cookie for what the browser loads
+// by itself, see SessionUser
+fn auth_response(token: NewBearerToken, base_url: &BaseUrl) -> Response {
+    let cookie = session_cookie(&token.token, TOKEN_LIFETIME, base_url);
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(AuthBody {
+            bearer_token: token.token,
+            expires_at: token.bearer_token.expires_at,
+        }),
+    )
+        .into_response()
+}
+
+// A max age of 0 removes the cookie
+fn session_cookie(token: &str, max_age: i64, base_url: &BaseUrl) -> String {
+    let mut cookie = format!(
+        "{}={}; Path={}/api; Max-Age={}; HttpOnly; SameSite=Strict",
+        SESSION_COOKIE,
+        token,
+        base_url.path(),
+        max_age
+    );
+    if base_url.is_https() {
+        cookie.push_str("; Secure");
+    }
+    cookie
 }
 
 #[utoipa::path(
@@ -76,16 +155,126 @@ pub struct AuthBody {
 #[debug_handler]
 pub async fn login(
     State(pool): State<SqlitePool>,
+    base_url: BaseUrl,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(login): Json<Login>,
-) -> Result<Json<AuthBody>, AuthError> {
-    let login_result = login.login(&pool).await;
+) -> Result<impl IntoResponse, AuthError> {
+    let user_agent = user_agent.map(|TypedHeader(user_agent)| user_agent.to_string());
+    let login_result = login.login(user_agent, &pool).await;
 
     match login_result {
-        Ok(token) => Ok(Json(AuthBody {
-            bearer_token: token.token,
-        })),
+        Ok(token) => Ok(auth_response(token, &base_url)),
         Err(LoginError::UserNotFound) => Err(AuthError::WrongCredentials),
         Err(LoginError::PasswordIncorrect) => Err(AuthError::WrongCredentials),
         Err(LoginError::DatabaseError(_)) => Err(AuthError::InternalError),
     }
 }
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/logout",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+#[debug_handler]
+pub async fn logout(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    base_url: BaseUrl,
+) -> Result<impl IntoResponse, AuthError> {
+    let token = BearerToken::get_token(user.token_id, &pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?
+        .ok_or(AuthError::InvalidToken)?;
+
+    token
+        .delete_self(&pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?;
+
+    Ok((
+        [(header::SET_COOKIE, session_cookie("", 0, &base_url))],
+        Json(GenericSuccess {
+            success: "Logged out".to_string(),
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct SessionBody {
+    id: i32,
+    device: Option<String>,
+    user_agent: Option<String>,
+    // Seconds since the unix epoch
+    created_at: i64,
+    last_used_at: i64,
+    expires_at: i64,
+    // The session making the request
+    current: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/sessions",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+#[debug_handler]
+pub async fn get_sessions(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+) -> Result<Json<Vec<SessionBody>>, AuthError> {
+    let tokens = BearerToken::get_by_user(user.user_id, &pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?;
+
+    let sessions = tokens
+        .into_iter()
+        .map(|token| SessionBody {
+            current: token.id == user.token_id,
+            id: token.id,
+            device: token.device,
+            user_agent: token.user_agent,
+            created_at: token.created_at,
+            last_used_at: token.last_used_at,
+            expires_at: token.expires_at,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/auth/sessions/{id}",
+    params(
+        ("id" = i32, Path, description = "The id of the session to revoke"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+#[debug_handler]
+pub async fn delete_session(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(id): Path<i32>,
+) -> Result<Json<GenericSuccess>, AuthError> {
+    // Other users' sessions are treated as missing, so their ids can't be probed
+    let token = BearerToken::get_token(id, &pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?
+        .filter(|token| token.user_id == user.user_id)
+        .ok_or(AuthError::SessionNotFound)?;
+
+    token
+        .delete_self(&pool)
+        .await
+        .map_err(|_| AuthError::InternalError)?;
+
+    Ok(Json(GenericSuccess {
+        success: "Session revoked".to_string(),
+    }))
+}
@@ -0,0 +1,234 @@
+use crate::{GenericSuccess, ValidatedUser, WritingUser};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use database::assets::Asset;
+use database::bookmarks::{Bookmark, InsertableBookmark};
+use database::library::Book;
+use hyper::StatusCode;
+use scanner::cfi::Cfi;
+use scanner::epub_sandbox::{Epub, EpubError};
+use scanner::toc::chapter_title;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/book/{book_id}/bookmarks",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to bookmark"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn add_bookmark(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path(book_id): Path<i32>,
+    Json(bookmark): Json<NewBookmark>,
+) -> Result<Json<BookmarkBody>, BookmarkError> {
+    let cfi = bookmark
+        .cfi
+        .parse::<Cfi>()
+        .map_err(|_| BookmarkError::InvalidLocation)?;
+
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let book = Book::get_book(book_id, &pool)
+        .await
+        .map_err(|error| match error {
+            sqlx::Error::RowNotFound => BookmarkError::BookNotFound,
+            _ => BookmarkError::InternalError,
+        })?;
+
+    let book_asset = Asset::get_asset(&book.asset_id, &pool)
+        .await?
+        .ok_or(BookmarkError::BookNotFound)?;
+
+    // The location has to exist in the book, and the chapter is looked up from where it actually points
+    let book_path = std::path::PathBuf::from(book_asset.local_path);
+    let mut epub = Epub::new(&book_path)?;
+    let resolved = epub
+        .resolve_cfi(&cfi)
+        .map_err(|_| BookmarkError::InvalidLocation)?;
+    let chapter = chapter_title(&epub.get_toc()?, resolved.spine_index);
+
+    let bookmark = InsertableBookmark {
+        book_id,
+        user_id: user.user_id,
+        cfi: cfi.to_string(),
+        spine_index: resolved.spine_index as i32,
+        label: bookmark.label,
+        chapter,
+    }
+    .insert(&pool)
+    .await?;
+
+    Ok(Json(BookmarkBody::from(bookmark)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/bookmarks",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to get the bookmarks for"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_bookmarks(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+) -> Result<Json<Vec<BookmarkBody>>, BookmarkError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let bookmarks = Bookmark::get_by_book(book_id, user.user_id, &pool).await?;
+
+    Ok(Json(
+        bookmarks.into_iter().map(BookmarkBody::from).collect(),
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/book/{book_id}/bookmarks/{bookmark_id}",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book the bookmark is in"),
+        ("bookmark_id" = i32, Path, description = "The id of the bookmark to rename"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn rename_bookmark(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path((book_id, bookmark_id)): Path<(i32, i32)>,
+    Json(rename): Json<RenameBookmark>,
+) -> Result<Json<BookmarkBody>, BookmarkError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let mut bookmark = Bookmark::get_bookmark(bookmark_id, book_id, user.user_id, &pool)
+        .await?
+        .ok_or(BookmarkError::NotFound)?;
+
+    bookmark.rename(rename.label, &pool).await?;
+
+    Ok(Json(BookmarkBody::from(bookmark)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/book/{book_id}/bookmarks/{bookmark_id}",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book the bookmark is in"),
+        ("bookmark_id" = i32, Path, description = "The id of the bookmark to delete"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn delete_bookmark(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path((book_id, bookmark_id)): Path<(i32, i32)>,
+) -> Result<Json<GenericSuccess>, BookmarkError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let bookmark = Bookmark::get_bookmark(bookmark_id, book_id, user.user_id, &pool)
+        .await?
+        .ok_or(BookmarkError::NotFound)?;
+
+    bookmark.delete_self(&pool).await?;
+
+    Ok(Json(GenericSuccess {
+        success: "Bookmark deleted".to_string(),
+    }))
+}
+
+// Books in libraries the user can't see are treated as if they don't exist
+async fn check_access(book_id: i32, user_id: i32, pool: &SqlitePool) -> Result<(), BookmarkError> {
+    match Book::can_access(book_id, user_id, pool).await? {
+        true => Ok(()),
+        false => Err(BookmarkError::BookNotFound),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewBookmark {
+    cfi: String,
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RenameBookmark {
+    // Leaving it out removes the label
+    label: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BookmarkBody {
+    id: i32,
+    book_id: i32,
+    cfi: String,
+    spine_index: i32,
+    label: Option<String>,
+    chapter: Option<String>,
+    created_at: i64,
+}
+
+impl From<Bookmark> for BookmarkBody {
+    fn from(bookmark: Bookmark) -> Self {
+        BookmarkBody {
+            id: bookmark.id,
+            book_id: bookmark.book_id,
+            cfi: bookmark.cfi,
+            spine_index: bookmark.spine_index,
+            label: bookmark.label,
+            chapter: bookmark.chapter,
+            created_at: bookmark.created_at,
+        }
+    }
+}
+
+pub enum BookmarkError {
+    NotFound,
+    BookNotFound,
+    BadFile,
+    InvalidLocation,
+    InternalError,
+}
+
+impl From<sqlx::Error> for BookmarkError {
+    fn from(_: sqlx::Error) -> Self {
+        BookmarkError::InternalError
+    }
+}
+
+impl From<EpubError> for BookmarkError {
+    fn from(_: EpubError) -> Self {
+        BookmarkError::BadFile
+    }
+}
+
+impl IntoResponse for BookmarkError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            BookmarkError::NotFound => (StatusCode::NOT_FOUND, "Bookmark not found"),
+            BookmarkError::BookNotFound => (StatusCode::NOT_FOUND, "Book not found"),
+            BookmarkError::BadFile => (StatusCode::BAD_REQUEST, "Bad file"),
+            BookmarkError::InvalidLocation => (StatusCode::BAD_REQUEST, "Invalid location"),
+            BookmarkError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        (status, body).into_response()
+    }
+}
@@ -1,21 +1,32 @@
 use std::os::fd::FromRawFd;
 
-use crate::ValidatedUser;
+use crate::base_url::BaseUrl;
+use crate::{GenericSuccess, SessionUser, ValidatedUser, WritingUser};
 use axum::body::{Full, StreamBody};
 use axum::debug_handler;
 use axum::extract::{Path, Query, State};
 use axum::response::{IntoResponse, Json, Response};
 use database::assets::Asset;
-use database::library::{Book, InsertableBookProgress};
+use database::highlights::{Highlight, HighlightColor, InsertableHighlight};
+use database::library::{Book, BookMetadata, BookProgress, InsertableBookProgress};
 use epub::doc::EpubDoc;
 use hyper::header;
+use hyper::HeaderMap;
 use hyper::StatusCode;
+use scanner::cfi::Cfi;
 use scanner::epub_sandbox::{Epub, EpubError};
+use scanner::events::{publish, Event};
+use scanner::search::{find_in_book, TextMatch};
+use scanner::toc::{chapter_title, TocEntry};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::sqlite::SqlitePool;
 
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
 #[utoipa::path(
     get,
@@ -26,9 +37,9 @@ use tokio::fs::File;
 )]
 pub async fn get_books(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    user: ValidatedUser,
 ) -> Result<Json<Vec<BookBody>>, BookError> {
-    let books: Vec<Book> = match Book::get_books(&pool).await {
+    let books: Vec<Book> = match Book::get_books_for_user(user.user_id, &pool).await {
         Ok(books) => books,
         Err(_) => return Err(BookError::InternalError),
     };
@@ -58,51 +69,661 @@ pub async fn get_books(
 )]
 pub async fn get_book(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    user: ValidatedUser,
     Path(book_id): Path<i32>,
-) -> Result<Json<BookBody>, BookError> {
+) -> Result<Json<BookDetailsBody>, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
     let book = match Book::get_book(book_id, &pool).await {
         Ok(book) => book,
         Err(_) => return Err(BookError::InternalError),
     };
 
-    Ok(Json(BookBody {
+    let metadata = BookMetadata::get_by_book(book.id, &pool).await?;
+
+    Ok(Json(BookDetailsBody {
         id: book.id,
         title: book.name,
         book_asset: book.asset_id,
         primary_cover: book.primary_cover,
+        isbn: metadata.isbn().map(|isbn| isbn.to_string()),
+        language: metadata.language,
+        publisher: metadata.publisher,
+        published: metadata.published,
+        description: metadata.description,
+        authors: metadata
+            .authors
+            .into_iter()
+            .map(|author| AuthorBody {
+                id: author.id,
+                name: author.name,
+                file_as: author.file_as,
+                role: author.role,
+            })
+            .collect(),
+        subjects: metadata.subjects,
+        identifiers: metadata
+            .identifiers
+            .into_iter()
+            .map(|identifier| IdentifierBody {
+                scheme: identifier.scheme,
+                value: identifier.value,
+            })
+            .collect(),
+        series: metadata
+            .series
+            .into_iter()
+            .map(|series| SeriesBody {
+                id: series.id,
+                name: series.name,
+                position: series.position,
+            })
+            .collect(),
     }))
 }
 
 #[utoipa::path(
-    post,
-    path = "/api/v1/books/{book_id}/sync",
+    get,
+    path = "/api/v1/book/{book_id}/toc",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to get the table of contents for"),
+    ),
     responses(
         (status = 200, content_type = "application/json")
     )
 )]
-pub async fn sync_book(
+pub async fn get_book_toc(
     State(pool): State<SqlitePool>,
     user: ValidatedUser,
     Path(book_id): Path<i32>,
-    Json(book_progress): Json<InsertableBookProgress>,
-) -> Result<Json<BookSyncResult>, BookError> {
+) -> Result<Json<Vec<TocBody>>, BookError> {
+    let (_, mut epub) = open_book(book_id, user.user_id, &pool).await?;
+    let toc = epub.get_toc()?;
 
-    match Book::get_book(book_id, &pool).await {
-        Ok(book) => book,
-        Err(_) => return Err(BookError::InternalError),
+    Ok(Json(toc.into_iter().map(TocBody::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/progress",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to get the reading progress for"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_book_progress(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+) -> Result<Json<ProgressBody>, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let progress = BookProgress::get_progress(book_id, user.user_id, &pool)
+        .await?
+        .ok_or(BookError::ProgressNotFound)?;
+
+    Ok(Json(ProgressBody::from(progress)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/book/{book_id}/progress",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to store the reading progress for"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn sync_book_progress(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path(book_id): Path<i32>,
+    Json(update): Json<ProgressUpdate>,
+) -> Result<Json<ProgressBody>, BookError> {
+    let fractions = [update.page_progress, update.total_progress];
+    if update.spine_index < 0
+        || fractions
+            .iter()
+            .any(|fraction| !(0.0..=1.0).contains(fraction))
+    {
+        return Err(BookError::InvalidProgress);
+    }
+
+    let cfi = match &update.cfi {
+        Some(cfi) => match cfi.parse::<Cfi>() {
+            Ok(cfi) if cfi.spine_index == update.spine_index as usize => Some(cfi.to_string()),
+            _ => return Err(BookError::InvalidProgress),
+        },
+        None => None,
     };
 
-    match book_progress.insert(&pool).await {
-        Ok(book_progess) => book_progess,
-        Err(_) => return Err(BookError::InternalError),
+    check_access(book_id, user.user_id, &pool).await?;
+
+    // A device with its clock set in the future would otherwise win every conflict until then
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default();
+    let updated_at = update
+        .updated_at
+        .map_or(now, |updated_at| updated_at.min(now));
+
+    let progress = InsertableBookProgress {
+        book_id,
+        user_id: user.user_id,
+        spine_index: update.spine_index,
+        page_progress: update.page_progress,
+        total_progress: update.total_progress,
+        cfi,
+        updated_at,
+    }
+    .upsert(&pool)
+    .await?;
+
+    // The stored progress is newer when another device synced after this one
+    if progress.updated_at == updated_at {
+        publish(Event::ProgressSynced {
+            user_id: user.user_id,
+            book_id,
+        });
+    }
+
+    Ok(Json(ProgressBody::from(progress)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/book/{book_id}/highlights",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to highlight in"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn add_highlight(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path(book_id): Path<i32>,
+    Json(highlight): Json<NewHighlight>,
+) -> Result<Json<HighlightBody>, BookError> {
+    let cfi = match highlight.cfi.parse::<Cfi>() {
+        Ok(cfi) if cfi.is_range() => cfi,
+        _ => return Err(BookError::InvalidHighlight),
     };
+    if highlight.text.trim().is_empty() {
+        return Err(BookError::InvalidHighlight);
+    }
+
+    // The range has to exist in the book, the document it resolves to decides the spine index
+    let (_, mut epub) = open_book(book_id, user.user_id, &pool).await?;
+    let resolved = epub
+        .resolve_cfi(&cfi)
+        .map_err(|_| BookError::InvalidHighlight)?;
+
+    let highlight = InsertableHighlight {
+        book_id,
+        user_id: user.user_id,
+        cfi: cfi.to_string(),
+        spine_index: resolved.spine_index as i32,
+        text: highlight.text,
+        color: highlight.color.unwrap_or_default(),
+        note: highlight.note.filter(|note| !note.is_empty()),
+    }
+    .insert(&pool)
+    .await?;
+
+    Ok(Json(HighlightBody::from(highlight)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/highlights",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to get the highlights for"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_highlights(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+) -> Result<Json<Vec<HighlightBody>>, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let highlights = sorted_highlights(book_id, user.user_id, &pool).await?;
+
+    Ok(Json(
+        highlights.into_iter().map(HighlightBody::from).collect(),
+    ))
+}
 
-    Ok(Json(BookSyncResult {
-        status: "Book synced".to_string(),
+#[utoipa::path(
+    patch,
+    path = "/api/v1/book/{book_id}/highlights/{highlight_id}",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book the highlight is in"),
+        ("highlight_id" = i32, Path, description = "The id of the highlight to change"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn update_highlight(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path((book_id, highlight_id)): Path<(i32, i32)>,
+    Json(changes): Json<HighlightChanges>,
+) -> Result<Json<HighlightBody>, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let mut highlight = Highlight::get_highlight(highlight_id, book_id, user.user_id, &pool)
+        .await?
+        .ok_or(BookError::HighlightNotFound)?;
+
+    if let Some(color) = changes.color {
+        highlight.color = color;
+    }
+    if let Some(note) = changes.note {
+        highlight.note = Some(note).filter(|note| !note.is_empty());
+    }
+    highlight.update(&pool).await?;
+
+    Ok(Json(HighlightBody::from(highlight)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/book/{book_id}/highlights/{highlight_id}",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book the highlight is in"),
+        ("highlight_id" = i32, Path, description = "The id of the highlight to delete"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn delete_highlight(
+    State(pool): State<SqlitePool>,
+    user: WritingUser,
+    Path((book_id, highlight_id)): Path<(i32, i32)>,
+) -> Result<Json<GenericSuccess>, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let highlight = Highlight::get_highlight(highlight_id, book_id, user.user_id, &pool)
+        .await?
+        .ok_or(BookError::HighlightNotFound)?;
+
+    highlight.delete_self(&pool).await?;
+
+    Ok(Json(GenericSuccess {
+        success: "Highlight deleted".to_string(),
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/highlights/export",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to export the highlights of"),
+        ("format" = Option<String>, Query, description = "markdown (default) or json"),
+    ),
+    responses(
+        (status = 200, content_type = "text/markdown"),
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn export_highlights(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, BookError> {
+    let (book, mut epub) = open_book(book_id, user.user_id, &pool).await?;
+    let toc = epub.get_toc()?;
+    let highlights = sorted_highlights(book_id, user.user_id, &pool).await?;
+
+    // Highlights are in reading order, so every chapter is one run of them
+    let mut chapters: Vec<ChapterHighlightsBody> = Vec::new();
+    for highlight in highlights {
+        let chapter = chapter_title(&toc, highlight.spine_index as usize);
+        match chapters.last_mut() {
+            Some(last) if last.chapter == chapter => {
+                last.highlights.push(HighlightBody::from(highlight))
+            }
+            _ => chapters.push(ChapterHighlightsBody {
+                chapter,
+                highlights: vec![HighlightBody::from(highlight)],
+            }),
+        }
+    }
+
+    let export = HighlightExportBody {
+        book_id: book.id,
+        title: book.name,
+        chapters,
+    };
+
+    match query.format.unwrap_or(ExportFormat::Markdown) {
+        ExportFormat::Json => Ok(Json(export).into_response()),
+        ExportFormat::Markdown => {
+            let headers = [
+                (
+                    header::CONTENT_TYPE,
+                    "text/markdown; charset=utf-8".to_string(),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"highlights-{}.md\"", book.id),
+                ),
+            ];
+            Ok((headers, export.to_markdown()).into_response())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/search",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to search in"),
+        ("q" = String, Query, description = "The text to find, case is ignored"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of matches to return, 100 by default"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn search_book(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+    Query(query): Query<BookSearchQuery>,
+) -> Result<Json<Vec<TextMatchBody>>, BookError> {
+    if query.q.trim().is_empty() {
+        return Err(BookError::InvalidQuery);
+    }
+
+    let (_, book_path) = find_book_file(book_id, user.user_id, &pool).await?;
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    // Reads every chapter of the book, which would hold up the other requests on the runtime
+    let matches = tokio::task::spawn_blocking(move || -> Result<_, BookError> {
+        let mut epub = Epub::new(&book_path)?;
+        let toc = epub.get_toc()?;
+
+        Ok(find_in_book(&mut epub, &query.q, limit)
+            .into_iter()
+            .map(|text_match| {
+                let chapter = chapter_title(&toc, text_match.spine_index);
+                TextMatchBody::new(text_match, chapter)
+            })
+            .collect())
+    })
+    .await
+    .map_err(|_| BookError::InternalError)??;
+
+    Ok(Json(matches))
+}
+
+// The database only knows the spine document, inside one the highlights are sorted by where they start
+async fn sorted_highlights(
+    book_id: i32,
+    user_id: i32,
+    pool: &SqlitePool,
+) -> Result<Vec<Highlight>, BookError> {
+    let mut highlights = Highlight::get_by_book(book_id, user_id, pool).await?;
+
+    highlights.sort_by_cached_key(|highlight| {
+        let start = highlight
+            .cfi
+            .parse::<Cfi>()
+            .map(|cfi| cfi.start())
+            .unwrap_or_default();
+        let steps: Vec<usize> = start.steps.iter().map(|step| step.index).collect();
+        (highlight.spine_index, steps, start.offset, highlight.id)
+    });
+
+    Ok(highlights)
+}
+
+// Books in libraries the user can't see are treated as if they don't exist
+async fn check_access(book_id: i32, user_id: i32, pool: &SqlitePool) -> Result<(), BookError> {
+    match Book::can_access(book_id, user_id, pool).await? {
+        true => Ok(()),
+        false => Err(BookError::NotFound),
+    }
+}
+
+async fn open_book(
+    book_id: i32,
+    user_id: i32,
+    pool: &SqlitePool,
+) -> Result<(Book, Epub), BookError> {
+    let (book, book_path) = find_book_file(book_id, user_id, pool).await?;
+    let epub = Epub::new(&book_path)?;
+
+    Ok((book, epub))
+}
+
+async fn find_book_file(
+    book_id: i32,
+    user_id: i32,
+    pool: &SqlitePool,
+) -> Result<(Book, std::path::PathBuf), BookError> {
+    check_access(book_id, user_id, pool).await?;
+
+    let book = Book::get_book(book_id, pool)
+        .await
+        .map_err(|error| match error {
+            sqlx::Error::RowNotFound => BookError::NotFound,
+            _ => BookError::InternalError,
+        })?;
+
+    let book_asset = Asset::get_asset(&book.asset_id, pool)
+        .await?
+        .ok_or(BookError::NotFound)?;
+
+    Ok((book, std::path::PathBuf::from(book_asset.local_path)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/book/{book_id}/download",
+    params(
+        ("book_id" = i32, Path, description = "The id of the book to download"),
+    ),
+    responses(
+        (status = 200, content_type = "application/epub+zip"),
+        (status = 206, content_type = "application/epub+zip"),
+        (status = 304),
+        (status = 416)
+    )
+)]
+pub async fn download_book(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(book_id): Path<i32>,
+    headers: HeaderMap,
+) -> Result<Response, BookError> {
+    check_access(book_id, user.user_id, &pool).await?;
+
+    let book = Book::get_book(book_id, &pool)
+        .await
+        .map_err(|error| match error {
+            sqlx::Error::RowNotFound => BookError::NotFound,
+            _ => BookError::InternalError,
+        })?;
+    let book_asset = Asset::get_asset(&book.asset_id, &pool)
+        .await?
+        .ok_or(BookError::NotFound)?;
+    let filename = book_filename(&book, &pool).await?;
+
+    file_response(&book_asset.local_path, &filename, &headers)
+        .await
+        .map_err(|_| BookError::NotFound)
+}
+
+// "Author - Title.epub", or just the title when the book has no author
+pub(crate) async fn book_filename(book: &Book, pool: &SqlitePool) -> Result<String, sqlx::Error> {
+    let metadata = BookMetadata::get_by_book(book.id, pool).await?;
+    let name = match metadata.authors.first() {
+        Some(author) => format!("{} - {}", author.name, book.name),
+        None => book.name.clone(),
+    };
+
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    Ok(format!("{}.epub", name.trim()))
+}
+
+// Header values have to be ascii, so the real name is percent encoded in filename* (RFC 6266)
+// and filename gets an ascii version for old clients
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && c != '"' { c } else { '_' })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    // First and last byte, both included
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Only single ranges are supported, anything else gets the whole file, which is allowed by RFC 9110
+fn byte_range(range: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (start, end) = (start.trim(), end.trim());
+    let (first, last) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last n bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (Ok(first), Err(_)) if end.is_empty() => (first, size.saturating_sub(1)),
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+        _ => return ByteRange::Full,
+    };
+
+    if first >= size {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial(first, last)
+}
+
+// Streams a file with an ETag, answering conditional and range requests
+pub(crate) async fn file_response(
+    path: &str,
+    filename: &str,
+    request_headers: &HeaderMap,
+) -> Result<Response, std::io::Error> {
+    let mut file = File::open(path).await?;
+    let file_metadata = file.metadata().await?;
+    let size = file_metadata.len();
+
+    // Size and modification time change whenever the file does, and are cheaper than hashing it
+    let modified = file_metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", size, modified);
+
+    let header_value = |name: header::HeaderName| {
+        request_headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let not_modified = header_value(header::IF_NONE_MATCH).is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == "*" || tag.trim().trim_start_matches("W/") == etag)
+    });
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    // A range only applies to the version of the file the client already has part of
+    let range = match header_value(header::IF_RANGE) {
+        Some(if_range) if if_range.trim() != etag => ByteRange::Full,
+        _ => byte_range(header_value(header::RANGE), size),
+    };
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/epub+zip".to_string()),
+        (header::CONTENT_DISPOSITION, content_disposition(filename)),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::ETAG, etag),
+    ];
+
+    match range {
+        ByteRange::Full => {
+            let body = StreamBody::new(ReaderStream::new(file));
+            Ok((headers, [(header::CONTENT_LENGTH, size.to_string())], body).into_response())
+        }
+        ByteRange::Partial(first, last) => {
+            file.seek(SeekFrom::Start(first)).await?;
+            let length = last - first + 1;
+            let body = StreamBody::new(ReaderStream::new(file.take(length)));
+            let range_headers = [
+                (header::CONTENT_LENGTH, length.to_string()),
+                (
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", first, last, size),
+                ),
+            ];
+            Ok((StatusCode::PARTIAL_CONTENT, headers, range_headers, body).into_response())
+        }
+        ByteRange::Unsatisfiable => Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response()),
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/book/{asset_id}/page/{page_num}",
@@ -115,17 +736,24 @@ pub async fn sync_book(
     )
 )]
 pub async fn get_book_page(
+    user: SessionUser,
     State(pool): State<SqlitePool>,
+    base_url: BaseUrl,
     Path((asset_id, page_num)): Path<(String, usize)>,
 ) -> Result<impl IntoResponse, BookError> {
+    if !Asset::can_access(&asset_id, user.user_id, &pool).await? {
+        return Err(BookError::InvalidPath);
+    }
+
     let book_asset = Asset::get_asset(&asset_id, &pool)
         .await?
         .ok_or(BookError::InvalidPath)?;
 
     let book_path = std::path::PathBuf::from(book_asset.local_path);
     let mut epub = Epub::new(&book_path)?;
+    let resource_url = base_url.join(&format!("/api/v1/book/{}/resource", asset_id));
     let page = epub
-        .get_page(page_num, &asset_id)
+        .get_page(page_num, &resource_url)
         .ok_or(BookError::InvalidPath)?;
     let html = page.0;
 
@@ -147,12 +775,14 @@ pub async fn get_book_page(
 )]
 #[debug_handler]
 pub async fn get_book_resource(
+    // Loaded by the stylesheets and images of the page, so it has to work with the session cookie
+    user: SessionUser,
     Path((asset_id, path)): Path<(String, String)>,
-
     State(pool): State<SqlitePool>,
 ) -> Result<impl IntoResponse, BookError> {
-    // Need to use a query for the bearer token
-    // Because it will be used in background-image css, and that doesn't support headers
+    if !Asset::can_access(&asset_id, user.user_id, &pool).await? {
+        return Err(BookError::InvalidPath);
+    }
 
     let book_asset = Asset::get_asset(&asset_id, &pool)
         .await?
@@ -196,21 +826,234 @@ pub struct BookBody {
     primary_cover: Option<String>,
 }
 
+#[derive(Serialize)]
+pub struct BookDetailsBody {
+    id: i32,
+    title: String,
+    book_asset: String,
+    primary_cover: Option<String>,
+    authors: Vec<AuthorBody>,
+    language: Option<String>,
+    publisher: Option<String>,
+    published: Option<String>,
+    description: Option<String>,
+    subjects: Vec<String>,
+    isbn: Option<String>,
+    identifiers: Vec<IdentifierBody>,
+    series: Vec<SeriesBody>,
+}
+
+#[derive(Serialize)]
+pub struct AuthorBody {
+    id: i32,
+    name: String,
+    file_as: Option<String>,
+    role: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IdentifierBody {
+    scheme: Option<String>,
+    value: String,
+}
+
+#[derive(Serialize)]
+pub struct SeriesBody {
+    id: i32,
+    name: String,
+    position: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct TocBody {
+    label: String,
+    spine_index: Option<usize>,
+    fragment: Option<String>,
+    children: Vec<TocBody>,
+}
+
+impl From<TocEntry> for TocBody {
+    fn from(entry: TocEntry) -> Self {
+        TocBody {
+            label: entry.label,
+            spine_index: entry.spine_index,
+            fragment: entry.fragment,
+            children: entry.children.into_iter().map(TocBody::from).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProgressUpdate {
+    spine_index: i32,
+    page_progress: f64,
+    total_progress: f64,
+    // Exact reading location, has to point into the document at spine_index
+    cfi: Option<String>,
+    // Milliseconds since the unix epoch when the reader got here, defaults to now
+    updated_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ProgressBody {
+    book_id: i32,
+    spine_index: i32,
+    page_progress: f64,
+    total_progress: f64,
+    cfi: Option<String>,
+    updated_at: i64,
+}
+
+impl From<BookProgress> for ProgressBody {
+    fn from(progress: BookProgress) -> Self {
+        ProgressBody {
+            book_id: progress.book_id,
+            spine_index: progress.spine_index,
+            page_progress: progress.page_progress,
+            total_progress: progress.total_progress,
+            cfi: progress.cfi,
+            updated_at: progress.updated_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookSearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TextMatchBody {
+    spine_index: usize,
+    // Characters into the text of the spine document, with whitespace collapsed
+    offset: usize,
+    length: usize,
+    before: String,
+    text: String,
+    after: String,
+    chapter: Option<String>,
+}
+
+impl TextMatchBody {
+    fn new(text_match: TextMatch, chapter: Option<String>) -> Self {
+        TextMatchBody {
+            spine_index: text_match.spine_index,
+            offset: text_match.offset,
+            length: text_match.length,
+            before: text_match.before,
+            text: text_match.text,
+            after: text_match.after,
+            chapter,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewHighlight {
+    // A range, a single location can't be highlighted
+    cfi: String,
+    text: String,
+    color: Option<HighlightColor>,
+    note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HighlightChanges {
+    color: Option<HighlightColor>,
+    // An empty note removes it
+    note: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct HighlightBody {
+    id: i32,
+    book_id: i32,
+    cfi: String,
+    spine_index: i32,
+    text: String,
+    color: HighlightColor,
+    note: Option<String>,
+    created_at: i64,
+    updated_at: i64,
+}
+
+impl From<Highlight> for HighlightBody {
+    fn from(highlight: Highlight) -> Self {
+        HighlightBody {
+            id: highlight.id,
+            book_id: highlight.book_id,
+            cfi: highlight.cfi,
+            spine_index: highlight.spine_index,
+            text: highlight.text,
+            color: highlight.color,
+            note: highlight.note,
+            created_at: highlight.created_at,
+            updated_at: highlight.updated_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
 #[derive(Deserialize)]
-pub struct BookSync {
-    pub page_num: usize,
-    pub page_percent: f32, 
+pub struct ExportQuery {
+    format: Option<ExportFormat>,
 }
 
 #[derive(Serialize)]
-pub struct BookSyncResult {
-    pub status: String,
+pub struct HighlightExportBody {
+    book_id: i32,
+    title: String,
+    chapters: Vec<ChapterHighlightsBody>,
+}
+
+#[derive(Serialize)]
+pub struct ChapterHighlightsBody {
+    // None for highlights before the first chapter in the table of contents
+    chapter: Option<String>,
+    highlights: Vec<HighlightBody>,
+}
+
+impl HighlightExportBody {
+    fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n", self.title);
+
+        for chapter in &self.chapters {
+            if let Some(title) = &chapter.chapter {
+                markdown.push_str(&format!("\n## {}\n", title));
+            }
+
+            for highlight in &chapter.highlights {
+                markdown.push('\n');
+                for line in highlight.text.lines() {
+                    markdown.push_str(&format!("> {}\n", line));
+                }
+                if let Some(note) = &highlight.note {
+                    markdown.push_str(&format!("\n{}\n", note));
+                }
+            }
+        }
+
+        markdown
+    }
 }
 
 pub enum BookError {
     InternalError,
     InvalidPath,
     BadFile,
+    NotFound,
+    ProgressNotFound,
+    InvalidProgress,
+    HighlightNotFound,
+    InvalidHighlight,
+    InvalidQuery,
 }
 
 impl From<sqlx::Error> for BookError {
@@ -231,6 +1074,12 @@ impl IntoResponse for BookError {
             BookError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
             BookError::InvalidPath => (StatusCode::BAD_REQUEST, "Invalid path"),
             BookError::BadFile => (StatusCode::BAD_REQUEST, "Bad file"),
+            BookError::NotFound => (StatusCode::NOT_FOUND, "Book not found"),
+            BookError::ProgressNotFound => (StatusCode::NOT_FOUND, "No progress stored for book"),
+            BookError::InvalidProgress => (StatusCode::BAD_REQUEST, "Invalid progress"),
+            BookError::HighlightNotFound => (StatusCode::NOT_FOUND, "Highlight not found"),
+            BookError::InvalidHighlight => (StatusCode::BAD_REQUEST, "Invalid highlight"),
+            BookError::InvalidQuery => (StatusCode::BAD_REQUEST, "Empty query"),
         };
 
         let body = Json(json!({
@@ -240,3 +1089,45 @@ impl IntoResponse for BookError {
         (status, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range(None, 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-9"), 100), ByteRange::Partial(0, 9));
+        assert_eq!(
+            byte_range(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            byte_range(Some("bytes=-10"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            byte_range(Some("bytes=-500"), 100),
+            ByteRange::Partial(0, 99)
+        );
+        assert_eq!(
+            byte_range(Some("bytes=50-500"), 100),
+            ByteRange::Partial(50, 99)
+        );
+        assert_eq!(
+            byte_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(byte_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=9-0"), 100), ByteRange::Full);
+        assert_eq!(byte_range(Some("lines=0-9"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("Håkon - \"Bok\".epub"),
+            "attachment; filename=\"H_kon - _Bok_.epub\"; filename*=UTF-8''H%C3%A5kon%20-%20%22Bok%22.epub"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use crate::SessionUser;
+use axum::extract::State;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use database::library::Library;
+use database::users::{BearerToken, User};
+use futures::stream::{self, Stream};
+use scanner::events::{subscribe, Event};
+use sqlx::sqlite::SqlitePool;
+use std::convert::Infallible;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::interval;
+
+// How often a quiet stream checks that the session it was opened with still exists
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// EventSource in the browser can't send an authorization header, but it sends the session cookie
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    responses(
+        (status = 200, content_type = "text/event-stream")
+    )
+)]
+pub async fn get_events(
+    State(pool): State<SqlitePool>,
+    user: SessionUser,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    Sse::new(event_stream(user, pool)).keep_alive(KeepAlive::default())
+}
+
+// Ends once the user logs out, is logged out by someone else or is deleted
+fn event_stream(
+    user: SessionUser,
+    pool: SqlitePool,
+) -> impl Stream<Item = Result<SseEvent, Infallible>> {
+    let SessionUser { user_id, token_id } = user;
+    let check = interval(SESSION_CHECK_INTERVAL);
+
+    stream::unfold((subscribe(), check), move |(mut receiver, mut check)| {
+        let pool = pool.clone();
+        async move {
+            loop {
+                let received = tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = check.tick() => {
+                        if !session_active(user_id, token_id, &pool).await {
+                            return None;
+                        }
+                        continue;
+                    }
+                };
+
+                if !session_active(user_id, token_id, &pool).await {
+                    return None;
+                }
+
+                let sse_event = match received {
+                    Ok(event) if can_see(&event, user_id, &pool).await => to_sse_event(&event),
+                    Ok(_) => continue,
+                    // The client has to fetch what it shows again, since some events were dropped
+                    Err(RecvError::Lagged(missed)) => SseEvent::default()
+                        .data(format!(r#"{{"type":"lagged","missed":{}}}"#, missed)),
+                    Err(RecvError::Closed) => return None,
+                };
+
+                return Some((Ok(sse_event), (receiver, check)));
+            }
+        }
+    })
+}
+
+// Basic auth has no session to end, the user can still be deleted
+async fn session_active(user_id: i32, token_id: Option<i32>, pool: &SqlitePool) -> bool {
+    let active = match token_id {
+        Some(token_id) => BearerToken::get_token(token_id, pool).await.map(|token| {
+            token.is_some_and(|token| token.expires_at > OffsetDateTime::now_utc().unix_timestamp())
+        }),
+        None => User::find_by_id(user_id, pool)
+            .await
+            .map(|user| user.is_some()),
+    };
+
+    active.unwrap_or(false)
+}
+
+// Access is looked up for every event, so a revoked user stops getting them right away
+async fn can_see(event: &Event, user_id: i32, pool: &SqlitePool) -> bool {
+    if !event.visible_to(user_id) {
+        return false;
+    }
+
+    match event.library_id() {
+        Some(library_id) => Library::can_access(library_id, user_id, pool)
+            .await
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+fn to_sse_event(event: &Event) -> SseEvent {
+    SseEvent::default()
+        .json_data(event)
+        .unwrap_or_else(|_| SseEvent::default().comment("Could not serialize event"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::users::{InsertableBearerToken, InsertableUser, Role};
+    use futures::StreamExt;
+    use scanner::events::publish;
+
+    #[tokio::test]
+    async fn test_stream_ends_when_logged_out() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        database::migrate(&pool).await.unwrap();
+
+        let user = InsertableUser {
+            username: "reader".to_string(),
+            hashed_password: String::new(),
+            role: Role::Member,
+        }
+        .insert(&pool)
+        .await
+        .unwrap();
+        let session = InsertableBearerToken {
+            user_id: user.id,
+            device: None,
+            user_agent: None,
+        }
+        .insert(&pool)
+        .await
+        .unwrap()
+        .bearer_token;
+
+        let events = event_stream(
+            SessionUser {
+                user_id: user.id,
+                token_id: Some(session.id),
+            },
+            pool.clone(),
+        );
+        tokio::pin!(events);
+        let timeout = Duration::from_secs(5);
+
+        publish(Event::ProgressSynced {
+            user_id: user.id,
+            book_id: 1,
+        });
+        let event = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert!(event.is_some());
+
+        // Logging out ends the stream instead of sending the next event
+        session.delete_self(&pool).await.unwrap();
+        publish(Event::ProgressSynced {
+            user_id: user.id,
+            book_id: 1,
+        });
+        let event = tokio::time::timeout(timeout, events.next()).await.unwrap();
+        assert!(event.is_none());
+    }
+}
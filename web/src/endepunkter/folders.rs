@@ -0,0 +1,240 @@
+use crate::{AdminUser, GenericSuccess};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use database::folders::{DropType, Folder, InsertableFolder};
+use database::library::Library;
+use hyper::StatusCode;
+use scanner::ingest::paths_overlap;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/folder",
+    request_body = Folder,
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn add_folder(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Json(folder): Json<InsertableFolder>,
+) -> Result<Json<FolderBody>, FolderError> {
+    validate_folder(&folder, None, &pool).await?;
+
+    let folder = folder.insert(&pool).await?;
+
+    Ok(Json(FolderBody::from(folder)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/folder",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_folders(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+) -> Result<Json<Vec<FolderBody>>, FolderError> {
+    let folders = Folder::get_folders(&pool).await?;
+
+    Ok(Json(folders.into_iter().map(FolderBody::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/folder/{id}",
+    params(("id" = i32, Path, description = "Folder id")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_folder(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+) -> Result<Json<FolderBody>, FolderError> {
+    let folder = Folder::get_folder(id, &pool).await?;
+
+    Ok(Json(FolderBody::from(folder)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/folder/{id}",
+    params(("id" = i32, Path, description = "Folder id")),
+    request_body = Folder,
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn update_folder(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+    Json(changes): Json<InsertableFolder>,
+) -> Result<Json<FolderBody>, FolderError> {
+    let mut folder = Folder::get_folder(id, &pool).await?;
+
+    validate_folder(&changes, Some(id), &pool).await?;
+
+    folder.nickname = changes.nickname;
+    folder.path = changes.path;
+    folder.watch = changes.watch;
+    folder.drop_type = changes.drop_type;
+    folder.destination_id = changes.destination_id;
+    folder.update(&pool).await?;
+
+    Ok(Json(FolderBody::from(folder)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/folder/{id}",
+    params(("id" = i32, Path, description = "Folder id")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn delete_folder(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+) -> Result<Json<GenericSuccess>, FolderError> {
+    Folder::get_folder(id, &pool).await?;
+    Folder::delete_folder(id, &pool).await?;
+
+    Ok(Json(GenericSuccess {
+        success: "Folder deleted".to_string(),
+    }))
+}
+
+// The folder has to exist on disk, and a source can only point at a destination folder
+async fn validate_folder(
+    folder: &InsertableFolder,
+    id: Option<i32>,
+    pool: &SqlitePool,
+) -> Result<(), FolderError> {
+    if !std::path::Path::new(&folder.path).is_dir() {
+        return Err(FolderError::InvalidPath);
+    }
+
+    validate_overlap(folder, id, pool).await?;
+
+    let Some(destination_id) = folder.destination_id else {
+        return Ok(());
+    };
+
+    if folder.drop_type != DropType::Source || Some(destination_id) == id {
+        return Err(FolderError::InvalidDestination);
+    }
+
+    match Folder::get_folder(destination_id, pool).await {
+        Ok(destination) if destination.drop_type == DropType::Destination => Ok(()),
+        Ok(_) | Err(sqlx::Error::RowNotFound) => Err(FolderError::InvalidDestination),
+        Err(_) => Err(FolderError::InternalError),
+    }
+}
+
+// Books dropped into a source are moved into a destination library, if one folder is inside the
+// other the moved books would be dropped again on every change
+async fn validate_overlap(
+    folder: &InsertableFolder,
+    id: Option<i32>,
+    pool: &SqlitePool,
+) -> Result<(), FolderError> {
+    let others: Vec<String> = match folder.drop_type {
+        DropType::Source => {
+            let destinations = Folder::get_by_drop_type(DropType::Destination, pool).await?;
+            let libraries = Library::get_libraries(pool).await?;
+            destinations
+                .into_iter()
+                .map(|destination| destination.path)
+                .chain(libraries.into_iter().map(|library| library.path))
+                .collect()
+        }
+        DropType::Destination => Folder::get_by_drop_type(DropType::Source, pool)
+            .await?
+            .into_iter()
+            .filter(|source| Some(source.id) != id)
+            .map(|source| source.path)
+            .collect(),
+        DropType::None => Vec::new(),
+    };
+
+    let path = std::path::Path::new(&folder.path);
+    if others
+        .iter()
+        .any(|other| paths_overlap(path, std::path::Path::new(other)))
+    {
+        return Err(FolderError::OverlappingPaths);
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct FolderBody {
+    id: i32,
+    nickname: String,
+    path: String,
+    watch: bool,
+    drop_type: DropType,
+    destination_id: Option<i32>,
+}
+
+impl From<Folder> for FolderBody {
+    fn from(folder: Folder) -> Self {
+        FolderBody {
+            id: folder.id,
+            nickname: folder.nickname,
+            path: folder.path,
+            watch: folder.watch,
+            drop_type: folder.drop_type,
+            destination_id: folder.destination_id,
+        }
+    }
+}
+
+pub enum FolderError {
+    InvalidPath,
+    InvalidDestination,
+    OverlappingPaths,
+    NotFound,
+    InternalError,
+}
+
+impl From<sqlx::Error> for FolderError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => FolderError::NotFound,
+            _ => FolderError::InternalError,
+        }
+    }
+}
+
+impl IntoResponse for FolderError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            FolderError::InvalidPath => (StatusCode::BAD_REQUEST, "Invalid path"),
+            FolderError::InvalidDestination => (StatusCode::BAD_REQUEST, "Invalid destination"),
+            FolderError::OverlappingPaths => (
+                StatusCode::BAD_REQUEST,
+                "A source folder can't be inside a destination or library, or contain one",
+            ),
+            FolderError::NotFound => (StatusCode::NOT_FOUND, "Folder not found"),
+            FolderError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        (status, body).into_response()
+    }
+}
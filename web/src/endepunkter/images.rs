@@ -1,4 +1,4 @@
-use crate::ValidatedUser;
+use crate::SessionUser;
 use axum::body::StreamBody;
 use axum::extract::{Path, State};
 use axum::http::StatusCode;
@@ -25,9 +25,16 @@ const TEMPORARY_COVER_DIR: &str = "./covers";
 )]
 #[debug_handler]
 pub async fn get_cover(
+    user: SessionUser,
     State(pool): State<SqlitePool>,
     Path(asset_id): Path<String>,
 ) -> impl IntoResponse {
+    match Asset::can_access(&asset_id, user.user_id, &pool).await {
+        Ok(true) => {}
+        Ok(false) => return Err(ImageError::ImageNotFound),
+        Err(_) => return Err(ImageError::InternalError),
+    }
+
     let cover_asset_option = match Asset::get_asset(&asset_id, &pool).await {
         Ok(asset) => asset,
         Err(_) => return Err(ImageError::InternalError),
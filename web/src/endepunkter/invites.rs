@@ -0,0 +1,125 @@
+use crate::{AdminUser, GenericSuccess};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use database::users::{InsertableInvite, Invite, Role};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/invites",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn add_invite(
+    State(pool): State<SqlitePool>,
+    user: AdminUser,
+    Json(invite): Json<NewInvite>,
+) -> Result<Json<InviteBody>, InviteError> {
+    let invite = InsertableInvite {
+        role: invite.role,
+        created_by: Some(user.user_id),
+    }
+    .insert(&pool)
+    .await?;
+
+    Ok(Json(InviteBody::from(invite)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/invites",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_invites(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+) -> Result<Json<Vec<InviteBody>>, InviteError> {
+    let invites = Invite::get_invites(&pool).await?;
+
+    Ok(Json(invites.into_iter().map(InviteBody::from).collect()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/invites/{id}",
+    params(("id" = i32, Path, description = "Invite id")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn delete_invite(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+) -> Result<Json<GenericSuccess>, InviteError> {
+    if !Invite::delete_invite(id, &pool).await? {
+        return Err(InviteError::NotFound);
+    }
+
+    Ok(Json(GenericSuccess {
+        success: "Invite deleted".to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct NewInvite {
+    role: Role,
+}
+
+#[derive(Serialize)]
+pub struct InviteBody {
+    id: i32,
+    code: String,
+    role: Role,
+    created_by: Option<i32>,
+    created_at: i64,
+    used_by: Option<i32>,
+    used_at: Option<i64>,
+}
+
+impl From<Invite> for InviteBody {
+    fn from(invite: Invite) -> Self {
+        InviteBody {
+            id: invite.id,
+            code: invite.code,
+            role: invite.role,
+            created_by: invite.created_by,
+            created_at: invite.created_at,
+            used_by: invite.used_by,
+            used_at: invite.used_at,
+        }
+    }
+}
+
+pub enum InviteError {
+    NotFound,
+    InternalError,
+}
+
+impl From<sqlx::Error> for InviteError {
+    fn from(_: sqlx::Error) -> Self {
+        InviteError::InternalError
+    }
+}
+
+impl IntoResponse for InviteError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            InviteError::NotFound => (StatusCode::NOT_FOUND, "Invite not found"),
+            InviteError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        (status, body).into_response()
+    }
+}
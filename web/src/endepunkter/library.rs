@@ -1,24 +1,24 @@
 use axum::debug_handler;
-use axum::extract::{FromRef, State};
+use axum::extract::State;
 use axum::Json;
 use axum::{
-    async_trait,
-    extract::{FromRequestParts, Path, TypedHeader},
-    headers::{authorization::Bearer, Authorization},
-    http::{request::Parts, StatusCode},
+    extract::Path,
+    http::StatusCode,
     response::{IntoResponse, Response},
     routing::{get, post},
-    RequestPartsExt,
+    Extension,
 };
 use database::library::{InsertableLibrary, Library};
-use database::users::BearerToken;
+use database::scan_jobs::{ScanJob, ScanJobError, ScanStatus};
+use database::users::{Role, User};
+use scanner::jobs::ScanJobs;
 use scanner::LibraryScanner;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::sqlite::SqlitePool;
 use utoipa::ToSchema;
 
-use crate::endepunkter::auth::AuthError;
+use crate::{AdminUser, ValidatedUser};
 
 #[utoipa::path(
     post,
@@ -30,7 +30,7 @@ use crate::endepunkter::auth::AuthError;
 )]
 pub async fn add_library(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    _: AdminUser,
     Json(library): Json<InsertableLibrary>,
 ) -> Result<Json<LibraryBody>, LibraryError> {
     let library_result = library.insert(&pool).await;
@@ -38,7 +38,7 @@ pub async fn add_library(
     match library_result {
         Ok(library) => Ok(Json(LibraryBody {
             id: library.id,
-            path: library.path,
+            path: Some(library.path),
             name: library.name,
         })),
         Err(_) => Err(LibraryError::InternalError),
@@ -54,9 +54,11 @@ pub async fn add_library(
 )]
 pub async fn get_libraries(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    user: ValidatedUser,
 ) -> Result<Json<Vec<LibraryBody>>, LibraryError> {
-    let libraries = Library::get_libraries(&pool).await;
+    let libraries = Library::get_libraries_for_user(user.user_id, &pool).await;
+    // Where the books are stored on the server is only of use to the admin
+    let show_path = user.role == Role::Admin;
 
     match libraries {
         Ok(libraries) => {
@@ -64,7 +66,7 @@ pub async fn get_libraries(
                 .into_iter()
                 .map(|library| LibraryBody {
                     id: library.id,
-                    path: library.path,
+                    path: show_path.then_some(library.path),
                     name: library.name,
                 })
                 .collect();
@@ -85,7 +87,7 @@ pub async fn get_libraries(
 )]
 pub async fn delete_library(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    _: AdminUser,
     Path(id): Path<i32>,
 ) -> Result<Json<GenericSuccess>, LibraryError> {
     let library = Library::delete_library(id, &pool).await;
@@ -108,51 +110,159 @@ pub async fn delete_library(
 )]
 pub async fn scan_library(
     State(pool): State<SqlitePool>,
-    _: ValidatedUser,
+    Extension(jobs): Extension<ScanJobs>,
+    _: AdminUser,
     Json(options): Json<LibraryScanOptions>,
-) -> Result<Json<GenericSuccess>, LibraryError> {
-    let result = Library::get_library(options.library_id, &pool).await;
-
-    let library = match result {
+) -> Result<Json<ScanJobBody>, LibraryError> {
+    let library = match Library::get_library(options.library_id, &pool).await {
         Ok(library) => library,
+        Err(sqlx::Error::RowNotFound) => return Err(LibraryError::NotFound),
         Err(_) => return Err(LibraryError::InternalError),
     };
 
-    tokio::spawn(async move {
-        println!("Scanning library {}", &library.path);
-        println!("Using new scanner");
-        match scanner::scanner::clean_up(&library.path, &pool).await {
-            Ok(_) => println!("Done cleaning up library {}", &library.path),
-            Err(_) => println!("Error cleaning up library {}", &library.path),
-        }
-        match scanner::scanner::force_scan(&library.path, options.library_id, &pool).await {
-            Ok(_) => println!("Done scanning library {}", &library.path),
-            Err(_) => println!("Error scanning library can't continue"),
-        }
-    });
-
-    // tokio::spawn(async move {
-    //     println!("Scanning library {}", library.path);
-    //     let mut scanner = LibraryScanner::new(library.path.clone());
-    //     match scanner.scan().await {
-    //         Ok(_) => println!("Done scanning library {}", library.path),
-    //         Err(_) => {
-    //             println!("Error scanning library can't continue");
-    //             return;
-    //         }
-    //     };
-
-    //     match scanner.store_to_db(library.id, &pool).await {
-    //         Ok(_) => println!("Done storing library {}", library.path),
-    //         Err(_) => println!("Error storing library {}", library.path),
-    //     };
-    // });
+    // Starts right away, or after the scan that is already running for the library
+    let job = jobs
+        .start_scan(library.id, &pool)
+        .await
+        .map_err(|_| LibraryError::InternalError)?;
+
+    Ok(Json(ScanJobBody::new(job, Vec::new())))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library/scan/{job_id}",
+    params(("job_id" = i32, Path, description = "Scan job id")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_scan_job(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Path(job_id): Path<i32>,
+) -> Result<Json<ScanJobBody>, LibraryError> {
+    let job = match ScanJob::get_job(job_id, &pool).await {
+        Ok(job) => job,
+        Err(sqlx::Error::RowNotFound) => return Err(LibraryError::ScanJobNotFound),
+        Err(_) => return Err(LibraryError::InternalError),
+    };
+
+    match Library::can_access(job.library_id, user.user_id, &pool).await {
+        Ok(true) => {}
+        Ok(false) => return Err(LibraryError::ScanJobNotFound),
+        Err(_) => return Err(LibraryError::InternalError),
+    }
+
+    let errors = job
+        .get_errors(&pool)
+        .await
+        .map_err(|_| LibraryError::InternalError)?;
+
+    Ok(Json(ScanJobBody::new(job, errors)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library/{id}/access",
+    params(("id" = i32, Path, description = "Library id")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_library_access(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<LibraryAccessBody>>, LibraryError> {
+    existing_library(id, &pool).await?;
+
+    let users = Library::get_users_with_access(id, &pool)
+        .await
+        .map_err(|_| LibraryError::InternalError)?;
+
+    Ok(Json(
+        users
+            .into_iter()
+            .map(|user| LibraryAccessBody {
+                user_id: user.id,
+                username: user.username,
+                role: user.role,
+            })
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/library/{id}/access/{user_id}",
+    params(
+        ("id" = i32, Path, description = "Library id"),
+        ("user_id" = i32, Path, description = "The id of the user to give access to the library"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn grant_library_access(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path((id, user_id)): Path<(i32, i32)>,
+) -> Result<Json<GenericSuccess>, LibraryError> {
+    existing_library(id, &pool).await?;
+
+    match User::find_by_id(user_id, &pool).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(LibraryError::UserNotFound),
+        Err(_) => return Err(LibraryError::InternalError),
+    }
+
+    Library::grant_access(id, user_id, &pool)
+        .await
+        .map_err(|_| LibraryError::InternalError)?;
+
+    Ok(Json(GenericSuccess {
+        success: "Access granted".to_string(),
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/library/{id}/access/{user_id}",
+    params(
+        ("id" = i32, Path, description = "Library id"),
+        ("user_id" = i32, Path, description = "The id of the user to take access to the library from"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn revoke_library_access(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path((id, user_id)): Path<(i32, i32)>,
+) -> Result<Json<GenericSuccess>, LibraryError> {
+    let revoked = Library::revoke_access(id, user_id, &pool)
+        .await
+        .map_err(|_| LibraryError::InternalError)?;
+
+    if !revoked {
+        return Err(LibraryError::AccessNotFound);
+    }
 
     Ok(Json(GenericSuccess {
-        success: "Library scan started".to_string(),
+        success: "Access revoked".to_string(),
     }))
 }
 
+async fn existing_library(id: i32, pool: &SqlitePool) -> Result<Library, LibraryError> {
+    match Library::get_library(id, pool).await {
+        Ok(library) => Ok(library),
+        Err(sqlx::Error::RowNotFound) => Err(LibraryError::NotFound),
+        Err(_) => Err(LibraryError::InternalError),
+    }
+}
+
 #[derive(Deserialize, ToSchema)]
 pub struct LibraryScanOptions {
     library_id: i32,
@@ -160,6 +270,10 @@ pub struct LibraryScanOptions {
 
 pub enum LibraryError {
     InvalidPath,
+    NotFound,
+    ScanJobNotFound,
+    UserNotFound,
+    AccessNotFound,
     InternalError,
 }
 
@@ -167,6 +281,12 @@ impl IntoResponse for LibraryError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
             LibraryError::InvalidPath => (StatusCode::BAD_REQUEST, "Invalid path"),
+            LibraryError::NotFound => (StatusCode::NOT_FOUND, "Library not found"),
+            LibraryError::ScanJobNotFound => (StatusCode::NOT_FOUND, "Scan job not found"),
+            LibraryError::UserNotFound => (StatusCode::NOT_FOUND, "User not found"),
+            LibraryError::AccessNotFound => {
+                (StatusCode::NOT_FOUND, "User has no access to the library")
+            }
             LibraryError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
         };
 
@@ -182,47 +302,69 @@ impl IntoResponse for LibraryError {
 
 pub struct LibraryBody {
     id: i32,
-    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
     name: String,
 }
 
+// Admins can see every library without being in this list
 #[derive(Serialize)]
-pub struct GenericSuccess {
-    success: String,
-}
-
-pub struct ValidatedUser {
+pub struct LibraryAccessBody {
     user_id: i32,
     username: String,
+    role: Role,
 }
 
-#[async_trait]
-impl<S> FromRequestParts<S> for ValidatedUser
-where
-    SqlitePool: FromRef<S>,
-    S: Send + Sync,
-{
-    type Rejection = AuthError;
-
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let pool = SqlitePool::from_ref(state);
-
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| AuthError::InvalidToken)?;
-
-        let token = bearer.token().to_string();
-        let token_object = BearerToken::find_by_token(&token, &pool)
-            .await
-            .map_err(|_| AuthError::InvalidToken)?;
-
-        match token_object {
-            Some(token_object) => Ok(ValidatedUser {
-                user_id: token_object.user_id,
-                username: "".to_string(),
-            }),
-            None => Err(AuthError::InvalidToken),
+#[derive(Serialize)]
+pub struct ScanJobBody {
+    id: i32,
+    library_id: i32,
+    status: ScanStatus,
+    discovered: i64,
+    imported: i64,
+    skipped: i64,
+    failed: i64,
+    removed: i64,
+    error: Option<String>,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    errors: Vec<ScanJobErrorBody>,
+}
+
+impl ScanJobBody {
+    fn new(job: ScanJob, errors: Vec<ScanJobError>) -> Self {
+        ScanJobBody {
+            id: job.id,
+            library_id: job.library_id,
+            status: job.status,
+            discovered: job.discovered,
+            imported: job.imported,
+            skipped: job.skipped,
+            failed: job.failed,
+            removed: job.removed,
+            error: job.error,
+            created_at: job.created_at,
+            started_at: job.started_at,
+            finished_at: job.finished_at,
+            errors: errors
+                .into_iter()
+                .map(|error| ScanJobErrorBody {
+                    path: error.path,
+                    message: error.message,
+                })
+                .collect(),
         }
     }
 }
+
+#[derive(Serialize)]
+pub struct ScanJobErrorBody {
+    path: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct GenericSuccess {
+    success: String,
+}
@@ -1,8 +1,15 @@
 pub mod auth;
+pub mod bookmarks;
 pub mod books;
+pub mod events;
+pub mod folders;
 pub mod hello;
 pub mod images;
+pub mod invites;
 pub mod library;
+pub mod opds;
+pub mod search;
+pub mod users;
 use tokio::sync::OnceCell;
 
 static METADATA_PATH: OnceCell<String> = OnceCell::const_new();
@@ -0,0 +1,817 @@
+// OPDS 1.2 catalog for e-readers and reading apps, see https://specs.opds.io/opds-1.2
+//
+// The feeds are Atom documents, and every link is built from the public url of the server, see
+// BaseUrl, so the catalog works behind a reverse proxy and under a path prefix. Readers can't
+// log in, so HTTP Basic auth is used instead of bearer tokens.
+use crate::base_url::BaseUrl;
+use crate::endepunkter::auth::AuthError;
+use crate::endepunkter::books::{book_filename, file_response};
+use crate::SessionUser;
+use axum::extract::{FromRef, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, Json};
+use database::assets::Asset;
+use database::library::{Author, Book, BookMetadata, Collection, Library};
+use database::search::SearchResult;
+use hyper::{header, HeaderMap, StatusCode};
+use quick_xml::events::{BytesDecl, BytesText, Event};
+use quick_xml::Writer;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+const EPUB_TYPE: &str = "application/epub+zip";
+
+const PAGE_SIZE: i64 = 50;
+
+// SessionUser does the checking, this only turns its errors into the ones readers understand
+pub struct OpdsUser {
+    pub user_id: i32,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for OpdsUser
+where
+    SqlitePool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = OpdsError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match SessionUser::from_request_parts(parts, state).await {
+            Ok(user) => Ok(OpdsUser {
+                user_id: user.user_id,
+            }),
+            Err(AuthError::InternalError) => Err(OpdsError::InternalError),
+            Err(_) => Err(OpdsError::Unauthorized),
+        }
+    }
+}
+
+struct Link {
+    rel: &'static str,
+    href: String,
+    kind: Option<&'static str>,
+}
+
+impl Link {
+    fn new(rel: &'static str, href: impl Into<String>, kind: &'static str) -> Self {
+        Link {
+            rel,
+            href: href.into(),
+            kind: Some(kind),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    id: String,
+    title: String,
+    updated: i64,
+    authors: Vec<String>,
+    language: Option<String>,
+    publisher: Option<String>,
+    issued: Option<String>,
+    categories: Vec<String>,
+    summary: Option<String>,
+    content: Option<String>,
+    links: Vec<Link>,
+}
+
+struct Feed {
+    id: String,
+    title: String,
+    kind: &'static str,
+    links: Vec<Link>,
+    entries: Vec<Entry>,
+}
+
+// Atom wants RFC 3339 dates
+fn rfc3339(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|date| date.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+fn write_text(writer: &mut Writer<Vec<u8>>, name: &str, text: &str) -> quick_xml::Result<()> {
+    writer
+        .create_element(name)
+        .write_text_content(BytesText::new(text))?;
+    Ok(())
+}
+
+// Hrefs are paths on the server, base is put in front of them
+fn write_links(writer: &mut Writer<Vec<u8>>, links: &[Link], base: &str) -> quick_xml::Result<()> {
+    for link in links {
+        let href = format!("{}{}", base, link.href);
+        let mut element = writer
+            .create_element("link")
+            .with_attribute(("rel", link.rel))
+            .with_attribute(("href", href.as_str()));
+        if let Some(kind) = link.kind {
+            element = element.with_attribute(("type", kind));
+        }
+        element.write_empty()?;
+    }
+    Ok(())
+}
+
+fn write_entry(writer: &mut Writer<Vec<u8>>, entry: &Entry, base: &str) -> quick_xml::Result<()> {
+    writer
+        .create_element("entry")
+        .write_inner_content(|writer| {
+            write_text(writer, "title", &entry.title)?;
+            write_text(writer, "id", &entry.id)?;
+            write_text(writer, "updated", &rfc3339(entry.updated))?;
+            for author in &entry.authors {
+                writer
+                    .create_element("author")
+                    .write_inner_content(|writer| write_text(writer, "name", author))?;
+            }
+            if let Some(language) = &entry.language {
+                write_text(writer, "dc:language", language)?;
+            }
+            if let Some(publisher) = &entry.publisher {
+                write_text(writer, "dc:publisher", publisher)?;
+            }
+            if let Some(issued) = &entry.issued {
+                write_text(writer, "dc:issued", issued)?;
+            }
+            for category in &entry.categories {
+                writer
+                    .create_element("category")
+                    .with_attribute(("term", category.as_str()))
+                    .with_attribute(("label", category.as_str()))
+                    .write_empty()?;
+            }
+            if let Some(summary) = &entry.summary {
+                writer
+                    .create_element("summary")
+                    .with_attribute(("type", "text"))
+                    .write_text_content(BytesText::new(summary))?;
+            }
+            if let Some(content) = &entry.content {
+                writer
+                    .create_element("content")
+                    .with_attribute(("type", "text"))
+                    .write_text_content(BytesText::new(content))?;
+            }
+            write_links(writer, &entry.links, base)
+        })?;
+    Ok(())
+}
+
+impl Feed {
+    fn new(id: &str, title: impl Into<String>, kind: &'static str, href: String) -> Self {
+        Feed {
+            id: format!("urn:epubreader:opds:{}", id),
+            title: title.into(),
+            kind,
+            links: vec![
+                Link::new("self", href, kind),
+                Link::new("start", "/opds", NAVIGATION_TYPE),
+                Link::new("search", "/opds/opensearch.xml", OPENSEARCH_TYPE),
+            ],
+            entries: Vec::new(),
+        }
+    }
+
+    fn link(mut self, rel: &'static str, href: impl Into<String>, kind: &'static str) -> Self {
+        self.links.push(Link::new(rel, href, kind));
+        self
+    }
+
+    // A link to a feed below this one
+    fn subsection(
+        &mut self,
+        id: String,
+        title: impl Into<String>,
+        content: Option<String>,
+        href: String,
+        kind: &'static str,
+    ) {
+        self.entries.push(Entry {
+            id: format!("urn:epubreader:opds:{}", id),
+            title: title.into(),
+            updated: OffsetDateTime::now_utc().unix_timestamp(),
+            content,
+            links: vec![Link::new("subsection", href, kind)],
+            ..Default::default()
+        });
+    }
+
+    fn to_xml(&self, base: &str) -> quick_xml::Result<Vec<u8>> {
+        let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+        writer
+            .create_element("feed")
+            .with_attribute(("xmlns", "http://www.w3.org/2005/Atom"))
+            .with_attribute(("xmlns:dc", "http://purl.org/dc/terms/"))
+            .with_attribute(("xmlns:opds", "http://opds-spec.org/2010/catalog"))
+            .write_inner_content(|writer| {
+                write_text(writer, "id", &self.id)?;
+                write_text(writer, "title", &self.title)?;
+                write_text(
+                    writer,
+                    "updated",
+                    &rfc3339(OffsetDateTime::now_utc().unix_timestamp()),
+                )?;
+                write_links(writer, &self.links, base)?;
+                for entry in &self.entries {
+                    write_entry(writer, entry, base)?;
+                }
+                Ok(())
+            })?;
+
+        Ok(writer.into_inner())
+    }
+
+    fn into_response(self, base_url: &BaseUrl) -> Result<Response, OpdsError> {
+        let xml = self.to_xml(&base_url.0)?;
+        Ok(([(header::CONTENT_TYPE, self.kind)], xml).into_response())
+    }
+}
+
+fn book_entry(book: Book, metadata: BookMetadata) -> Entry {
+    let mut links = vec![Link::new(
+        "http://opds-spec.org/acquisition",
+        format!("/opds/books/{}/download", book.id),
+        EPUB_TYPE,
+    )];
+    if let Some(cover) = &book.primary_cover {
+        for rel in [
+            "http://opds-spec.org/image",
+            "http://opds-spec.org/image/thumbnail",
+        ] {
+            links.push(Link {
+                rel,
+                href: format!("/api/v1/images/covers/{}", cover),
+                kind: None,
+            });
+        }
+    }
+
+    Entry {
+        id: format!("urn:epubreader:book:{}", book.id),
+        title: book.name,
+        updated: book.added_at,
+        authors: metadata
+            .authors
+            .into_iter()
+            .map(|author| author.name)
+            .collect(),
+        language: metadata.language,
+        publisher: metadata.publisher,
+        issued: metadata.published,
+        categories: metadata.subjects,
+        summary: metadata.description,
+        content: None,
+        links,
+    }
+}
+
+// Books are fetched one past the page size, to know if there is a next page
+async fn acquisition_feed(
+    mut feed: Feed,
+    mut books: Vec<Book>,
+    page: i64,
+    href: &str,
+    base_url: &BaseUrl,
+    pool: &SqlitePool,
+) -> Result<Response, OpdsError> {
+    if books.len() as i64 > PAGE_SIZE {
+        books.truncate(PAGE_SIZE as usize);
+        feed = feed.link(
+            "next",
+            format!("{}?page={}", href, page + 1),
+            ACQUISITION_TYPE,
+        );
+    }
+    if page > 1 {
+        feed = feed.link(
+            "previous",
+            format!("{}?page={}", href, page - 1),
+            ACQUISITION_TYPE,
+        );
+    }
+
+    let book_ids: Vec<i32> = books.iter().map(|book| book.id).collect();
+    let mut metadata: HashMap<i32, BookMetadata> = BookMetadata::get_by_books(&book_ids, pool)
+        .await?
+        .into_iter()
+        .map(|metadata| (metadata.book_id, metadata))
+        .collect();
+
+    // A book removed since the page was fetched is left out
+    for book in books {
+        if let Some(metadata) = metadata.remove(&book.id) {
+            feed.entries.push(book_entry(book, metadata));
+        }
+    }
+
+    feed.into_response(base_url)
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds",
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn root(_: OpdsUser, base_url: BaseUrl) -> Result<Response, OpdsError> {
+    let mut feed = Feed::new("root", "Library", NAVIGATION_TYPE, "/opds".to_string());
+
+    feed.subsection(
+        "libraries".to_string(),
+        "Libraries",
+        Some("Browse the books by library and collection".to_string()),
+        "/opds/libraries".to_string(),
+        NAVIGATION_TYPE,
+    );
+    feed.subsection(
+        "authors".to_string(),
+        "Authors",
+        Some("Browse the books by author".to_string()),
+        "/opds/authors".to_string(),
+        NAVIGATION_TYPE,
+    );
+    feed.subsection(
+        "recent".to_string(),
+        "Recently added",
+        Some("The newest books".to_string()),
+        "/opds/recent".to_string(),
+        ACQUISITION_TYPE,
+    );
+
+    feed.into_response(&base_url)
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/libraries",
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_libraries(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+) -> Result<Response, OpdsError> {
+    let mut feed = Feed::new(
+        "libraries",
+        "Libraries",
+        NAVIGATION_TYPE,
+        "/opds/libraries".to_string(),
+    )
+    .link("up", "/opds", NAVIGATION_TYPE);
+
+    for library in Library::get_libraries_for_user(user.user_id, &pool).await? {
+        feed.subsection(
+            format!("library:{}", library.id),
+            library.name,
+            None,
+            format!("/opds/libraries/{}", library.id),
+            NAVIGATION_TYPE,
+        );
+    }
+
+    feed.into_response(&base_url)
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/libraries/{id}",
+    params(("id" = i32, Path, description = "Library id")),
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_library(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+    Path(id): Path<i32>,
+) -> Result<Response, OpdsError> {
+    check_library(id, user.user_id, &pool).await?;
+    let library = Library::get_library(id, &pool).await?;
+    let mut feed = Feed::new(
+        &format!("library:{}", id),
+        &library.name,
+        NAVIGATION_TYPE,
+        format!("/opds/libraries/{}", id),
+    )
+    .link("up", "/opds/libraries", NAVIGATION_TYPE);
+
+    feed.subsection(
+        format!("library:{}:books", id),
+        "All books",
+        None,
+        format!("/opds/libraries/{}/books", id),
+        ACQUISITION_TYPE,
+    );
+
+    // The root collection holds the books directly in the library folder, they are all under "All books"
+    let mut collections = Collection::get_by_libary(id, &pool).await?;
+    collections.retain(|collection| collection.path != library.path);
+    collections.sort_by_key(|collection| collection.name.to_lowercase());
+
+    for collection in collections {
+        feed.subsection(
+            format!("collection:{}", collection.id),
+            collection.name,
+            None,
+            format!("/opds/collections/{}", collection.id),
+            ACQUISITION_TYPE,
+        );
+    }
+
+    feed.into_response(&base_url)
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/libraries/{id}/books",
+    params(
+        ("id" = i32, Path, description = "Library id"),
+        ("page" = Option<i64>, Query, description = "Page number, starting at 1"),
+    ),
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_library_books(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+    Path(id): Path<i32>,
+    Query(query): Query<PageQuery>,
+) -> Result<Response, OpdsError> {
+    check_library(id, user.user_id, &pool).await?;
+    let library = Library::get_library(id, &pool).await?;
+    let page = query.page();
+    let href = format!("/opds/libraries/{}/books", id);
+
+    let books = Book::get_page_by_library(id, PAGE_SIZE + 1, (page - 1) * PAGE_SIZE, &pool).await?;
+    let feed = Feed::new(
+        &format!("library:{}:books", id),
+        &library.name,
+        ACQUISITION_TYPE,
+        format!("{}?page={}", href, page),
+    )
+    .link("up", format!("/opds/libraries/{}", id), NAVIGATION_TYPE);
+
+    acquisition_feed(feed, books, page, &href, &base_url, &pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/collections/{id}",
+    params(
+        ("id" = i32, Path, description = "Collection id"),
+        ("page" = Option<i64>, Query, description = "Page number, starting at 1"),
+    ),
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_collection(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+    Path(id): Path<i32>,
+    Query(query): Query<PageQuery>,
+) -> Result<Response, OpdsError> {
+    let collection = Collection::get_collection(id, &pool).await?;
+    check_library(collection.library_id, user.user_id, &pool).await?;
+    let page = query.page();
+    let href = format!("/opds/collections/{}", id);
+
+    let books =
+        Book::get_page_by_collection(id, PAGE_SIZE + 1, (page - 1) * PAGE_SIZE, &pool).await?;
+    let feed = Feed::new(
+        &format!("collection:{}", id),
+        &collection.name,
+        ACQUISITION_TYPE,
+        format!("{}?page={}", href, page),
+    )
+    .link(
+        "up",
+        format!("/opds/libraries/{}", collection.library_id),
+        NAVIGATION_TYPE,
+    );
+
+    acquisition_feed(feed, books, page, &href, &base_url, &pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/authors",
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_authors(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+) -> Result<Response, OpdsError> {
+    let mut feed = Feed::new(
+        "authors",
+        "Authors",
+        NAVIGATION_TYPE,
+        "/opds/authors".to_string(),
+    )
+    .link("up", "/opds", NAVIGATION_TYPE);
+
+    for author in Author::get_authors(user.user_id, &pool).await? {
+        let books = match author.book_count {
+            1 => "1 book".to_string(),
+            count => format!("{} books", count),
+        };
+        feed.subsection(
+            format!("author:{}", author.id),
+            author.name,
+            Some(books),
+            format!("/opds/authors/{}", author.id),
+            ACQUISITION_TYPE,
+        );
+    }
+
+    feed.into_response(&base_url)
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/authors/{id}",
+    params(
+        ("id" = i32, Path, description = "Author id"),
+        ("page" = Option<i64>, Query, description = "Page number, starting at 1"),
+    ),
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_author(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+    Path(id): Path<i32>,
+    Query(query): Query<PageQuery>,
+) -> Result<Response, OpdsError> {
+    let author = Author::get_author(id, user.user_id, &pool).await?;
+    let page = query.page();
+    let href = format!("/opds/authors/{}", id);
+
+    let books = Book::get_page_by_author(
+        id,
+        user.user_id,
+        PAGE_SIZE + 1,
+        (page - 1) * PAGE_SIZE,
+        &pool,
+    )
+    .await?;
+    let feed = Feed::new(
+        &format!("author:{}", id),
+        &author.name,
+        ACQUISITION_TYPE,
+        format!("{}?page={}", href, page),
+    )
+    .link("up", "/opds/authors", NAVIGATION_TYPE);
+
+    acquisition_feed(feed, books, page, &href, &base_url, &pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/recent",
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn get_recent(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+) -> Result<Response, OpdsError> {
+    let books = Book::get_recent(PAGE_SIZE, user.user_id, &pool).await?;
+    let feed = Feed::new(
+        "recent",
+        "Recently added",
+        ACQUISITION_TYPE,
+        "/opds/recent".to_string(),
+    )
+    .link("up", "/opds", NAVIGATION_TYPE);
+
+    acquisition_feed(feed, books, 1, "/opds/recent", &base_url, &pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/search",
+    params(
+        ("q" = String, Query, description = "Words to look for in the title, authors and text of the books"),
+    ),
+    responses(
+        (status = 200, content_type = "application/atom+xml")
+    )
+)]
+pub async fn search(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    base_url: BaseUrl,
+    Query(query): Query<OpdsSearchQuery>,
+) -> Result<Response, OpdsError> {
+    let book_ids: Vec<i32> = SearchResult::search(&query.q, PAGE_SIZE, user.user_id, &pool)
+        .await?
+        .iter()
+        .map(|result| result.book_id)
+        .collect();
+    let books = Book::get_books_by_ids(&book_ids, &pool).await?;
+
+    let feed = Feed::new(
+        "search",
+        format!("Search for {}", query.q),
+        ACQUISITION_TYPE,
+        "/opds/search".to_string(),
+    )
+    .link("up", "/opds", NAVIGATION_TYPE);
+
+    acquisition_feed(feed, books, 1, "/opds/search", &base_url, &pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/opensearch.xml",
+    responses(
+        (status = 200, content_type = "application/opensearchdescription+xml")
+    )
+)]
+pub async fn opensearch(_: OpdsUser, base_url: BaseUrl) -> Result<Response, OpdsError> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("OpenSearchDescription")
+        .with_attribute(("xmlns", "http://a9.com/-/spec/opensearch/1.1/"))
+        .write_inner_content(|writer| {
+            write_text(writer, "ShortName", "Library")?;
+            write_text(writer, "Description", "Search the books in the library")?;
+            write_text(writer, "InputEncoding", "UTF-8")?;
+            write_text(writer, "OutputEncoding", "UTF-8")?;
+            writer
+                .create_element("Url")
+                .with_attribute(("type", ACQUISITION_TYPE))
+                .with_attribute((
+                    "template",
+                    base_url.join("/opds/search?q={searchTerms}").as_str(),
+                ))
+                .write_empty()?;
+            Ok(())
+        })?;
+
+    Ok((
+        [(header::CONTENT_TYPE, OPENSEARCH_TYPE)],
+        writer.into_inner(),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/opds/books/{id}/download",
+    params(("id" = i32, Path, description = "Book id")),
+    responses(
+        (status = 200, content_type = "application/epub+zip")
+    )
+)]
+pub async fn download_book(
+    State(pool): State<SqlitePool>,
+    user: OpdsUser,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+) -> Result<Response, OpdsError> {
+    if !Book::can_access(id, user.user_id, &pool).await? {
+        return Err(OpdsError::NotFound);
+    }
+
+    let book = Book::get_book(id, &pool).await?;
+    let asset = Asset::get_asset(&book.asset_id, &pool)
+        .await?
+        .ok_or(OpdsError::NotFound)?;
+    let filename = book_filename(&book, &pool).await?;
+
+    file_response(&asset.local_path, &filename, &headers)
+        .await
+        .map_err(|_| OpdsError::NotFound)
+}
+
+// Libraries the user can't see are treated as if they don't exist
+async fn check_library(id: i32, user_id: i32, pool: &SqlitePool) -> Result<(), OpdsError> {
+    match Library::can_access(id, user_id, pool).await? {
+        true => Ok(()),
+        false => Err(OpdsError::NotFound),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    page: Option<i64>,
+}
+
+impl PageQuery {
+    fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OpdsSearchQuery {
+    q: String,
+}
+
+pub enum OpdsError {
+    Unauthorized,
+    NotFound,
+    InternalError,
+}
+
+impl From<sqlx::Error> for OpdsError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::RowNotFound => OpdsError::NotFound,
+            _ => OpdsError::InternalError,
+        }
+    }
+}
+
+impl From<quick_xml::Error> for OpdsError {
+    fn from(_: quick_xml::Error) -> Self {
+        OpdsError::InternalError
+    }
+}
+
+impl IntoResponse for OpdsError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            OpdsError::Unauthorized => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
+            OpdsError::NotFound => (StatusCode::NOT_FOUND, "Not found"),
+            OpdsError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        // Readers only ask for a username and password when the server tells them to use Basic auth
+        if status == StatusCode::UNAUTHORIZED {
+            let challenge = [(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"Library\", charset=\"UTF-8\"",
+            )];
+            return (status, challenge, body).into_response();
+        }
+
+        (status, body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1700000000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_feed_xml() {
+        let mut feed = Feed::new("root", "Books & more", NAVIGATION_TYPE, "/opds".to_string());
+        feed.subsection(
+            "recent".to_string(),
+            "Recently <added>",
+            None,
+            "/opds/recent".to_string(),
+            ACQUISITION_TYPE,
+        );
+
+        let xml = String::from_utf8(feed.to_xml("").unwrap()).unwrap();
+        assert!(xml.contains("<title>Books &amp; more</title>"));
+        assert!(xml.contains("<title>Recently &lt;added&gt;</title>"));
+        assert!(xml.contains(
+            "<link rel=\"subsection\" href=\"/opds/recent\" type=\"application/atom+xml;profile=opds-catalog;kind=acquisition\"/>"
+        ));
+    }
+}
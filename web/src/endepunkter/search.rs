@@ -0,0 +1,112 @@
+use crate::ValidatedUser;
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use database::search::{SearchResult, MATCH_END, MATCH_START};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/search",
+    params(
+        ("q" = String, Query, description = "Words to look for in the title, authors and text of the books"),
+        ("limit" = Option<i64>, Query, description = "Maximum number of books to return, 20 by default"),
+    ),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn search(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResultBody>>, SearchError> {
+    if query.q.trim().is_empty() {
+        return Err(SearchError::EmptyQuery);
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let results = SearchResult::search(&query.q, limit, user.user_id, &pool).await?;
+
+    Ok(Json(
+        results.into_iter().map(SearchResultBody::from).collect(),
+    ))
+}
+
+// The snippet is text from the book, so it's escaped before the matches are marked with <mark>
+fn snippet_html(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResultBody {
+    book_id: i32,
+    title: String,
+    // The spine document with the best match, None when only the title or authors matched
+    spine_index: Option<i32>,
+    snippet: String,
+    // Lower is better
+    rank: f64,
+}
+
+impl From<SearchResult> for SearchResultBody {
+    fn from(result: SearchResult) -> Self {
+        SearchResultBody {
+            book_id: result.book_id,
+            title: result.title,
+            spine_index: result.spine_index,
+            snippet: snippet_html(&result.snippet),
+            rank: result.rank,
+        }
+    }
+}
+
+pub enum SearchError {
+    EmptyQuery,
+    InternalError,
+}
+
+impl From<sqlx::Error> for SearchError {
+    fn from(_: sqlx::Error) -> Self {
+        SearchError::InternalError
+    }
+}
+
+impl IntoResponse for SearchError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            SearchError::EmptyQuery => (StatusCode::BAD_REQUEST, "Empty query"),
+            SearchError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        (status, body).into_response()
+    }
+}
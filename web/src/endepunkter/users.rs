@@ -0,0 +1,231 @@
+use crate::{AdminUser, GenericSuccess, ValidatedUser};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use database::users::{verify_password, BearerToken, Role, User};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::sqlite::SqlitePool;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_me(user: ValidatedUser) -> Json<UserBody> {
+    Json(UserBody {
+        id: user.user_id,
+        username: user.username,
+        role: user.role,
+    })
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/me",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn update_me(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Json(changes): Json<UserChanges>,
+) -> Result<Json<UserBody>, UserError> {
+    let mut me = find_user(user.user_id, &pool).await?;
+
+    if let Some(username) = changes.username {
+        let username = username.trim();
+        if username.is_empty() {
+            return Err(UserError::InvalidUsername);
+        }
+
+        // The unique constraint decides, a check before the update could race with another rename
+        if username != me.username {
+            match me.set_username(username, &pool).await {
+                Err(sqlx::Error::Database(error)) if error.is_unique_violation() => {
+                    return Err(UserError::UsernameTaken)
+                }
+                result => result?,
+            }
+        }
+    }
+
+    Ok(Json(UserBody::from(me)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/me/password",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn change_password(
+    State(pool): State<SqlitePool>,
+    user: ValidatedUser,
+    Json(change): Json<PasswordChange>,
+) -> Result<Json<GenericSuccess>, UserError> {
+    let mut me = find_user(user.user_id, &pool).await?;
+
+    // A stolen session shouldn't be enough to take over the account
+    if !verify_password(&me.hashed_password, &change.current_password) {
+        return Err(UserError::WrongPassword);
+    }
+    if change.new_password.is_empty() {
+        return Err(UserError::InvalidPassword);
+    }
+
+    me.set_password(&change.new_password, &pool).await?;
+    BearerToken::delete_other_sessions(me.id, user.token_id, &pool).await?;
+
+    Ok(Json(GenericSuccess {
+        success: "Password changed".to_string(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn get_users(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+) -> Result<Json<Vec<UserBody>>, UserError> {
+    let users = User::get_users(&pool).await?;
+
+    Ok(Json(users.into_iter().map(UserBody::from).collect()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/users/{id}/password",
+    params(("id" = i32, Path, description = "The id of the user to set a new password for")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn reset_password(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+    Json(reset): Json<PasswordReset>,
+) -> Result<Json<GenericSuccess>, UserError> {
+    let mut user = find_user(id, &pool).await?;
+
+    if reset.password.is_empty() {
+        return Err(UserError::InvalidPassword);
+    }
+
+    // The user has to log in again everywhere with the new password
+    user.set_password(&reset.password, &pool).await?;
+    BearerToken::delete_by_user(user.id, &pool).await?;
+
+    Ok(Json(GenericSuccess {
+        success: "Password reset".to_string(),
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/users/{id}",
+    params(("id" = i32, Path, description = "The id of the user to delete")),
+    responses(
+        (status = 200, content_type = "application/json")
+    )
+)]
+pub async fn delete_user(
+    State(pool): State<SqlitePool>,
+    _: AdminUser,
+    Path(id): Path<i32>,
+) -> Result<Json<GenericSuccess>, UserError> {
+    let user = find_user(id, &pool).await?;
+
+    // Someone has to be left to manage the libraries
+    if !user.delete_unless_last_admin(&pool).await? {
+        return Err(UserError::LastAdmin);
+    }
+
+    Ok(Json(GenericSuccess {
+        success: "User deleted".to_string(),
+    }))
+}
+
+async fn find_user(id: i32, pool: &SqlitePool) -> Result<User, UserError> {
+    User::find_by_id(id, pool).await?.ok_or(UserError::NotFound)
+}
+
+#[derive(Deserialize)]
+pub struct UserChanges {
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordReset {
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct UserBody {
+    id: i32,
+    username: String,
+    role: Role,
+}
+
+impl From<User> for UserBody {
+    fn from(user: User) -> Self {
+        UserBody {
+            id: user.id,
+            username: user.username,
+            role: user.role,
+        }
+    }
+}
+
+pub enum UserError {
+    NotFound,
+    UsernameTaken,
+    InvalidUsername,
+    WrongPassword,
+    InvalidPassword,
+    LastAdmin,
+    InternalError,
+}
+
+impl From<sqlx::Error> for UserError {
+    fn from(_: sqlx::Error) -> Self {
+        UserError::InternalError
+    }
+}
+
+impl IntoResponse for UserError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = match self {
+            UserError::NotFound => (StatusCode::NOT_FOUND, "User not found"),
+            UserError::UsernameTaken => (StatusCode::CONFLICT, "Username taken"),
+            UserError::InvalidUsername => (StatusCode::BAD_REQUEST, "Invalid username"),
+            UserError::WrongPassword => (StatusCode::FORBIDDEN, "Wrong password"),
+            UserError::InvalidPassword => (StatusCode::BAD_REQUEST, "Invalid password"),
+            UserError::LastAdmin => (StatusCode::CONFLICT, "Can't delete the last admin"),
+            UserError::InternalError => (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+        };
+
+        let body = Json(json!({
+            "error": error_message,
+        }));
+
+        (status, body).into_response()
+    }
+}
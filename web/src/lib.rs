@@ -2,15 +2,19 @@ use axum::extract::FromRef;
 use axum::{
     async_trait,
     extract::{FromRequestParts, TypedHeader},
-    headers::{authorization::Bearer, Authorization},
+    headers::{
+        authorization::{Basic, Bearer},
+        Authorization, Cookie,
+    },
     http::request::Parts,
     RequestPartsExt,
 };
-use database::users::BearerToken;
+use database::users::{authenticate_password, BearerToken, Role, User};
 use endepunkter::auth::AuthError;
 use serde::Serialize;
 use sqlx::sqlite::SqlitePool;
 
+pub mod base_url;
 pub mod endepunkter;
 
 use axum::{Router, Server};
@@ -29,6 +33,9 @@ pub struct GenericSuccess {
 pub struct ValidatedUser {
     user_id: i32,
     username: String,
+    // The session the request was made with
+    token_id: i32,
+    role: Role,
 }
 
 #[async_trait]
@@ -48,20 +55,138 @@ where
             .map_err(|_| AuthError::InvalidToken)?;
 
         let token = bearer.token().to_string();
-        let token_object = BearerToken::find_by_token(&token, &pool)
+        let token_object = BearerToken::authenticate(&token, &pool)
             .await
             .map_err(|_| AuthError::InvalidToken)?;
 
-        match token_object {
-            Some(token_object) => Ok(ValidatedUser {
-                user_id: token_object.user_id,
-                username: "".to_string(),
+        let token_object = token_object.ok_or(AuthError::InvalidToken)?;
+
+        let user = User::find_by_id(token_object.user_id, &pool)
+            .await
+            .map_err(|_| AuthError::InternalError)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        Ok(ValidatedUser {
+            user_id: user.id,
+            username: user.username,
+            token_id: token_object.id,
+            role: user.role,
+        })
+    }
+}
+
+// For managing libraries, folders and invites
+pub struct AdminUser {
+    user_id: i32,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AdminUser
+where
+    SqlitePool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = ValidatedUser::from_request_parts(parts, state).await?;
+
+        match user.role {
+            Role::Admin => Ok(AdminUser {
+                user_id: user.user_id,
             }),
-            None => Err(AuthError::InvalidToken),
+            _ => Err(AuthError::Forbidden),
         }
     }
 }
 
+// For saving progress, bookmarks and highlights, which read only users can't
+pub struct WritingUser {
+    user_id: i32,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WritingUser
+where
+    SqlitePool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = ValidatedUser::from_request_parts(parts, state).await?;
+
+        if !user.role.can_write() {
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(WritingUser {
+            user_id: user.user_id,
+        })
+    }
+}
+
+// The bearer token is also stored in this cookie when logging in, see endepunkter::auth
+pub const SESSION_COOKIE: &str = "session";
+
+// For the endpoints the browser loads by itself, like images, the book pages in the reader
+// iframe and the stylesheets and backgrounds inside them. Those requests can't carry the
+// Authorization header, so the session cookie is accepted as well. OPDS readers send Basic auth
+// when they fetch covers. Only used for reading, so a forged request from another site can't
+// change anything with the cookie.
+pub struct SessionUser {
+    pub user_id: i32,
+    // None with Basic auth, which has no session
+    pub token_id: Option<i32>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionUser
+where
+    SqlitePool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let pool = SqlitePool::from_ref(state);
+
+        if let Ok(TypedHeader(Authorization(basic))) =
+            parts.extract::<TypedHeader<Authorization<Basic>>>().await
+        {
+            let user = authenticate_password(basic.username(), basic.password(), &pool)
+                .await
+                .map_err(|_| AuthError::InternalError)?
+                .ok_or(AuthError::WrongCredentials)?;
+
+            return Ok(SessionUser {
+                user_id: user.id,
+                token_id: None,
+            });
+        }
+
+        let token = match parts.extract::<TypedHeader<Authorization<Bearer>>>().await {
+            Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
+            Err(_) => parts
+                .extract::<TypedHeader<Cookie>>()
+                .await
+                .ok()
+                .and_then(|TypedHeader(cookie)| cookie.get(SESSION_COOKIE).map(str::to_string))
+                .ok_or(AuthError::InvalidToken)?,
+        };
+
+        let token_object = BearerToken::authenticate(&token, &pool)
+            .await
+            .map_err(|_| AuthError::InvalidToken)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        Ok(SessionUser {
+            user_id: token_object.user_id,
+            token_id: Some(token_object.id),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;